@@ -0,0 +1,70 @@
+// Adds and removes boids while the simulation is running, without the boids having to leave the GPU.
+// spawn copies the new boids behind the alive boids, finish_spawn then increases the alive count.
+// compact copies the boids that stay alive to the other boid buffer, finish_compact then sets the alive count to the amount that was copied.
+// Boids are only ever read up to the alive count, so the boids behind it don't have to be cleared.
+
+#import "shaders/boid_types.wgsl"::{Boid, minimum_image}
+
+struct Lifecycle {
+    // Boids within the radius around the center are removed by compact
+    despawn_center: vec4<f32>,
+    despawn_radius: f32,
+    spawn_count: u32,
+    capacity: u32,
+    // 1 when the world wraps around, then the radius reaches across the edges like the neighbour search
+    wrap: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> lifecycle: Lifecycle;
+@group(0) @binding(1)
+var<storage> boids_src: array<Boid>;
+@group(0) @binding(2)
+var<storage, read_write> boids_dst: array<Boid>;
+@group(0) @binding(3)
+var<storage> spawned: array<Boid>;
+@group(0) @binding(4)
+var<storage, read_write> alive_count: atomic<u32>;
+@group(0) @binding(5)
+var<storage, read_write> compacted_count: atomic<u32>;
+
+@compute @workgroup_size(32)
+fn spawn(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = invocation_id.x;
+    if (index >= lifecycle.spawn_count) {
+        return;
+    }
+
+    let slot = atomicLoad(&alive_count) + index;
+    if (slot < lifecycle.capacity) {
+        boids_dst[slot] = spawned[index];
+    }
+}
+
+@compute @workgroup_size(1)
+fn finish_spawn() {
+    atomicStore(&alive_count, min(atomicLoad(&alive_count) + lifecycle.spawn_count, lifecycle.capacity));
+}
+
+@compute @workgroup_size(32)
+fn compact(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = invocation_id.x;
+    if (index >= atomicLoad(&alive_count)) {
+        return;
+    }
+
+    let boid = boids_src[index];
+    var offset = vec4<f32>(boid.pos.xyz - lifecycle.despawn_center.xyz, 0.0);
+    if (lifecycle.wrap == 1u) {
+        offset = minimum_image(offset);
+    }
+    if (length(offset) > lifecycle.despawn_radius) {
+        boids_dst[atomicAdd(&compacted_count, 1u)] = boid;
+    }
+}
+
+@compute @workgroup_size(1)
+fn finish_compact() {
+    atomicStore(&alive_count, atomicLoad(&compacted_count));
+    atomicStore(&compacted_count, 0u);
+}
@@ -0,0 +1,66 @@
+// The structs, constants and functions that are shared by the shaders of the boids.
+// Imported by the compute shaders and the instancing shader, so they all read the same layout.
+// Has to match Params, SpeciesParams and Boid in shared.rs field for field.
+
+struct SpeciesParams {
+    speed: f32,
+    seperationDistance : f32,
+    alignmentDistance : f32,
+    cohesionDistance : f32,
+    seperationScale : f32,
+    alignmentScale : f32,
+    cohesionScale : f32,
+    viewCos : f32,
+    blindSpotCos : f32,
+    mass : f32,
+    maxForce : f32,
+    minSpeed : f32,
+    maxTurnRate : f32,
+    flapRate : f32,
+    paddingA : f32,
+    paddingB : f32,
+}
+
+struct Params {
+    species : array<SpeciesParams, 4>,
+    // interactions[a][b] is how a boid of species a reacts to a boid of species b
+    interactions : array<vec4<u32>, 4>,
+    grid_size : f32,
+    cell_size : f32,
+    boundary_mode : u32,
+    boundary_margin : f32,
+    boundary_strength : f32,
+    species_count : u32,
+    neighbour_mode : u32,
+    nearest_count : u32,
+    steering_model : u32,
+    flight_time : f32,
+    perch_time : f32,
+    landing_distance : f32,
+}
+
+struct Boid {
+    pos: vec4<f32>,
+    vel: vec4<f32>,
+    // The x is the state, the y the seconds the boid has been in it, the z the phase of its wings
+    // and the w a value between 0 and 1 that stays with the boid
+    state: vec4<f32>
+}
+
+// Has to match BoundaryMode::shader_value
+const BOUNDARY_WRAP: u32 = 0u;
+const BOUNDARY_BOUNCE: u32 = 1u;
+const BOUNDARY_STEER_BACK: u32 = 2u;
+const BOUNDARY_OPEN: u32 = 3u;
+
+// Has to match the state of Boid
+const STATE_FLYING: u32 = 0u;
+const STATE_LANDING: u32 = 1u;
+const STATE_PERCHED: u32 = 2u;
+const STATE_TAKING_OFF: u32 = 3u;
+
+// The shortest offset from one boid to another, which might be across the edge of the world.
+// The world goes from -1 to 1, so it is 2 wide on every axis.
+fn minimum_image(offset: vec4<f32>) -> vec4<f32> {
+    return vec4<f32>(offset.xyz - 2.0 * round(offset.xyz * 0.5), 0.0);
+}
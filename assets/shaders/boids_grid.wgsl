@@ -1,22 +1,104 @@
 // This file is inspired by the boids shader from wgpu:
 // https://github.com/gfx-rs/wgpu/blob/trunk/examples/src/boids/compute.wgsl
 // Modified to work with the grid structure.
-
-struct Params {
-    speed: f32,
-    seperationDistance : f32,
-    alignmentDistance : f32,
-    cohesionDistance : f32,
-    seperationScale : f32,
-    alignmentScale : f32,
-    cohesionScale : f32,
-    grid_size : f32,
-    cell_size : f32,
+// Every boid uses the params of its species, which is stored in the w of its position.
+// Boids within the panic radius of a predator flee from it and fly faster.
+// Boids within the margin of an obstacle steer away from it, meshes are baked into a signed distance field.
+// Attractors pull the boids towards them, paths also pull the boids along them.
+// Boids only see the neighbours within their view cone and outside of the blind spot behind them.
+// The topological rule aligns with and flies towards the k nearest neighbours instead of all neighbours within a distance.
+// The Reynolds steering model limits the rules to a max force, the speed to a range and the turn rate.
+// The wind carries the boids along, its turbulence is curl noise.
+// Boids that have flown long enough land on the perch obstacles below them and take off again after a while or when disturbed.
+
+#import "shaders/boid_types.wgsl"::{
+    SpeciesParams, Params, Boid,
+    BOUNDARY_WRAP, BOUNDARY_BOUNCE, BOUNDARY_STEER_BACK, BOUNDARY_OPEN,
+    STATE_FLYING, STATE_LANDING, STATE_PERCHED, STATE_TAKING_OFF,
+    minimum_image,
 }
 
-struct Boid {
+// Has to match ObstacleShape::shader_value
+const SHAPE_SPHERE: u32 = 0u;
+const SHAPE_CUBOID: u32 = 1u;
+const SHAPE_CAPSULE: u32 = 2u;
+
+// Has to match AttractorShape::shader_value
+const ATTRACTOR_POINT: u32 = 0u;
+const ATTRACTOR_PATH: u32 = 2u;
+
+// Has to match NeighbourMode::shader_value
+const NEIGHBOUR_TOPOLOGICAL: u32 = 1u;
+
+// Has to match SteeringModel::shader_value
+const STEERING_REYNOLDS: u32 = 1u;
+
+// Only the sides of a perch that face up can be landed on, the y of their normal has to be at least this.
+const PERCH_SLOPE: f32 = 0.7;
+// Height above the perch at which a perched boid sits.
+const PERCH_HEIGHT: f32 = 0.005;
+// Seconds a boid flies up after taking off, before it flies with the flock again.
+const TAKE_OFF_TIME: f32 = 0.5;
+
+// Has to match MAX_NEAREST
+const MAX_NEAREST: u32 = 16u;
+
+// Has to match SDF_BAND
+const SDF_BAND: f32 = 0.2;
+
+// Has to match SpeciesInteraction::shader_value
+const INTERACTION_IGNORE: u32 = 0u;
+const INTERACTION_AVOID: u32 = 1u;
+const INTERACTION_FLOCK: u32 = 2u;
+
+struct Predator {
+    // The w is the panic radius
     pos: vec4<f32>,
-    vel: vec4<f32>
+    flee_strength: f32,
+    speed_boost: f32,
+}
+
+struct Predators {
+    count: u32,
+    predators: array<Predator>,
+}
+
+struct Obstacle {
+    // The w of a is the shape, see ObstacleData
+    a: vec4<f32>,
+    b: vec4<f32>,
+    margin: f32,
+    strength: f32,
+    perch: u32,
+}
+
+struct Attractor {
+    // The w of a is the shape and the w of b is the weight, see AttractorData
+    a: vec4<f32>,
+    b: vec4<f32>,
+    falloff: f32,
+    path_segments: u32,
+}
+
+struct Attractors {
+    count: u32,
+    attractors: array<Attractor>,
+}
+
+struct Wind {
+    // The w is the speed of the turbulence
+    velocity: vec4<f32>,
+    time: f32,
+    turbulence_scale: f32,
+}
+
+struct Obstacles {
+    count: u32,
+    // 0 when there are no mesh obstacles
+    sdf_resolution: u32,
+    sdf_margin: f32,
+    sdf_strength: f32,
+    obstacles: array<Obstacle>,
 }
 
 @group(0) @binding(0)
@@ -33,28 +115,271 @@ var<storage, read_write> boids_dst: array<Boid>;
 var<storage> amount_of_crows_vec: array<u32>;
 @group(0) @binding(5)
 var<storage> crow_idxs: array<u32>;
-
+// Center of the grid, written by the grid_build shader when the world is open
+@group(0) @binding(6)
+var<storage> grid_origin: vec4<f32>;
+// The boids after the alive count have been despawned or not spawned yet
+@group(0) @binding(7)
+var<uniform> alive_count: u32;
+@group(0) @binding(8)
+var<storage> predators: Predators;
+@group(0) @binding(9)
+var<storage> obstacles: Obstacles;
+// Signed distance to the closest mesh obstacle over the -1 to 1 simulation space
+@group(0) @binding(10)
+var sdf: texture_3d<f32>;
+@group(0) @binding(11)
+var<storage> attractors: Attractors;
+@group(0) @binding(12)
+var<uniform> wind: Wind;
+
+// Wraps a cell coordinate to the other side of the grid, the world is toroidal.
 fn wrap_around(coord: i32, max_value: i32) -> i32 {
     if (coord < 0) {
-        return max_value - 1;
+        return coord + max_value;
     } else if (coord >= max_value) {
-        return 0;
+        return coord - max_value;
     } else {
         return coord;
     }
 }
 
-@compute @workgroup_size(32)
-fn main(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+// Same as the grid_build shader, positions at exactly 1.0 end up in the last cell instead of outside of the grid.
+fn grid_coordinate(coord: f32) -> i32 {
+    let cell = i32(max((coord / params.cell_size) + (params.grid_size * 0.5), 0.0));
+    return min(cell, i32(params.grid_size) - 1);
+}
+
+// Same as the grid_build shader, in an open world the boids outside of the grid are not sorted into it.
+fn outside_grid(grid_pos: vec3<f32>) -> bool {
+    let half_size = params.grid_size * params.cell_size * 0.5;
+    return params.boundary_mode == BOUNDARY_OPEN && (any(grid_pos < vec3<f32>(-half_size)) || any(grid_pos >= vec3<f32>(half_size)));
+}
+
+// Pushes a boid that is within the margin of a wall back, stronger the closer it gets to the wall.
+fn steer_back(pos: vec4<f32>) -> vec4<f32> {
+    let inner = 1.0 - params.boundary_margin;
+    let over = max(abs(pos.xyz) - vec3<f32>(inner), vec3<f32>(0.0)) / max(params.boundary_margin, 0.0001);
+    return vec4<f32>(-sign(pos.xyz) * over * params.boundary_strength, 0.0);
+}
+
+// The direction away from the obstacle in xyz and the signed distance to it in w.
+fn obstacle_distance(obstacle: Obstacle, pos: vec3<f32>, wrap: bool) -> vec4<f32> {
+    var offset = vec4<f32>(pos - obstacle.a.xyz, 0.0);
+    if (wrap) {
+        offset = minimum_image(offset);
+    }
+    let p = offset.xyz;
+    let shape = u32(obstacle.a.w);
+
+    if (shape == SHAPE_CUBOID) {
+        let q = abs(p) - obstacle.b.xyz;
+        let outside = max(q, vec3<f32>(0.0));
+        if (length(outside) > 0.0) {
+            return vec4<f32>(normalize(sign(p) * outside), length(outside));
+        }
+        // Inside of the box it is pushed out through the closest side.
+        let inside = max(q.x, max(q.y, q.z));
+        var normal = vec3<f32>(0.0, 0.0, sign(p.z));
+        if (q.x == inside) {
+            normal = vec3<f32>(sign(p.x), 0.0, 0.0);
+        } else if (q.y == inside) {
+            normal = vec3<f32>(0.0, sign(p.y), 0.0);
+        }
+        return vec4<f32>(normal, inside);
+    }
+
+    // A sphere is a capsule of which both ends are the center.
+    var closest = vec3<f32>(0.0);
+    if (shape == SHAPE_CAPSULE) {
+        let axis = obstacle.b.xyz - obstacle.a.xyz;
+        closest = axis * clamp(dot(p, axis) / max(dot(axis, axis), 0.000001), 0.0, 1.0);
+    }
+    let away = p - closest;
+    if (length(away) == 0.0) {
+        return vec4<f32>(0.0, 1.0, 0.0, -obstacle.b.w);
+    }
+    return vec4<f32>(normalize(away), length(away) - obstacle.b.w);
+}
+
+fn sdf_load(base: vec3<i32>, offset: vec3<i32>) -> f32 {
+    let res = i32(obstacles.sdf_resolution);
+    return textureLoad(sdf, min(base + offset, vec3<i32>(res - 1)), 0).x;
+}
 
-    // let grid_size_x: i32 = 10; 
-    // let grid_size_y: i32 = 10; 
-    // let grid_size_z: i32 = 10; 
+// Trilinear interpolation of the distances, the texture can't be filtered as it holds 32 bit floats.
+fn sdf_distance(pos: vec3<f32>) -> f32 {
+    let res = i32(obstacles.sdf_resolution);
+    if (res == 0 || any(abs(pos) > vec3<f32>(1.0))) {
+        return SDF_BAND;
+    }
+    let voxel = clamp((pos + 1.0) * 0.5 * f32(res) - 0.5, vec3<f32>(0.0), vec3<f32>(f32(res - 1)));
+    let base = min(vec3<i32>(floor(voxel)), vec3<i32>(max(res - 2, 0)));
+    let t = voxel - vec3<f32>(base);
+    let x00 = mix(sdf_load(base, vec3<i32>(0, 0, 0)), sdf_load(base, vec3<i32>(1, 0, 0)), t.x);
+    let x10 = mix(sdf_load(base, vec3<i32>(0, 1, 0)), sdf_load(base, vec3<i32>(1, 1, 0)), t.x);
+    let x01 = mix(sdf_load(base, vec3<i32>(0, 0, 1)), sdf_load(base, vec3<i32>(1, 0, 1)), t.x);
+    let x11 = mix(sdf_load(base, vec3<i32>(0, 1, 1)), sdf_load(base, vec3<i32>(1, 1, 1)), t.x);
+    return mix(mix(x00, x10, t.y), mix(x01, x11, t.y), t.z);
+}
+
+// The direction away from the closest mesh, from the difference of the distances around the position.
+fn sdf_normal(pos: vec3<f32>) -> vec3<f32> {
+    let h = 1.0 / f32(obstacles.sdf_resolution);
+    let gradient = vec3<f32>(
+        sdf_distance(pos + vec3<f32>(h, 0.0, 0.0)) - sdf_distance(pos - vec3<f32>(h, 0.0, 0.0)),
+        sdf_distance(pos + vec3<f32>(0.0, h, 0.0)) - sdf_distance(pos - vec3<f32>(0.0, h, 0.0)),
+        sdf_distance(pos + vec3<f32>(0.0, 0.0, h)) - sdf_distance(pos - vec3<f32>(0.0, 0.0, h)));
+    if (length(gradient) == 0.0) {
+        return vec3<f32>(0.0);
+    }
+    return normalize(gradient);
+}
+
+// Whether a neighbour at the offset is within the view cone around the heading and outside of the blind spot.
+fn can_see(sp: SpeciesParams, heading: vec3<f32>, offset: vec3<f32>) -> bool {
+    let dst = length(offset);
+    let speed = length(heading);
+    if (dst == 0.0 || speed == 0.0) {
+        return true;
+    }
+    let cos_angle = clamp(dot(heading, offset) / (dst * speed), -1.0, 1.0);
+    return cos_angle >= sp.viewCos && -cos_angle <= sp.blindSpotCos;
+}
+
+// The closest side of a perch that faces up, the normal in xyz and the distance to it in w.
+// The w is further away than any landing distance when there is no perch.
+fn closest_perch(pos: vec3<f32>, wrap: bool) -> vec4<f32> {
+    var closest = vec4<f32>(0.0, 1.0, 0.0, 1000.0);
+    for (var o = 0u; o < obstacles.count; o++) {
+        let obstacle = obstacles.obstacles[o];
+        if (obstacle.perch == 0u) {
+            continue;
+        }
+        let surface = obstacle_distance(obstacle, pos, wrap);
+        if (surface.y >= PERCH_SLOPE && surface.w > -PERCH_HEIGHT && surface.w < closest.w) {
+            closest = surface;
+        }
+    }
+    return closest;
+}
+
+// Moves the boid to its next state, the seconds in the state start at 0 again when it changes.
+// The wing phase in the z is kept, so the wings don't jump.
+// The wait decides how much longer or shorter than the flight and perch time this boid waits.
+fn next_state(state: vec4<f32>, perch: vec4<f32>, disturbed: bool, wait: f32, landing_step: f32) -> vec4<f32> {
+    let current = u32(state.x);
+    var next = current;
+    if (current == STATE_FLYING) {
+        if (params.flight_time > 0.0 && state.y > params.flight_time * wait && perch.w < params.landing_distance) {
+            next = STATE_LANDING;
+        }
+    } else if (current == STATE_LANDING) {
+        if (disturbed) {
+            next = STATE_TAKING_OFF;
+        } else if (perch.w > params.landing_distance * 2.0) {
+            // The perch has moved away.
+            next = STATE_FLYING;
+        } else if (perch.w <= landing_step) {
+            next = STATE_PERCHED;
+        }
+    } else if (current == STATE_PERCHED) {
+        if (disturbed || state.y > params.perch_time * wait || perch.w > PERCH_HEIGHT * 2.0) {
+            next = STATE_TAKING_OFF;
+        }
+    } else if (current == STATE_TAKING_OFF && state.y > TAKE_OFF_TIME) {
+        next = STATE_FLYING;
+    }
+    if (next != current) {
+        return vec4<f32>(f32(next), 0.0, state.z, state.w);
+    }
+    return state;
+}
+
+// Random value between -1 and 1 for a corner of the noise lattice.
+fn lattice_hash(corner: vec3<i32>) -> f32 {
+    var h = (u32(corner.x) * 73856093u) ^ (u32(corner.y) * 19349663u) ^ (u32(corner.z) * 83492791u);
+    h = (h ^ (h >> 16u)) * 2246822507u;
+    h = (h ^ (h >> 13u)) * 3266489909u;
+    h = h ^ (h >> 16u);
+    return f32(h) / 4294967295.0 * 2.0 - 1.0;
+}
+
+fn value_noise(p: vec3<f32>) -> f32 {
+    let cell = vec3<i32>(floor(p));
+    let f = p - floor(p);
+    let u = f * f * (3.0 - 2.0 * f);
+    let x00 = mix(lattice_hash(cell), lattice_hash(cell + vec3<i32>(1, 0, 0)), u.x);
+    let x10 = mix(lattice_hash(cell + vec3<i32>(0, 1, 0)), lattice_hash(cell + vec3<i32>(1, 1, 0)), u.x);
+    let x01 = mix(lattice_hash(cell + vec3<i32>(0, 0, 1)), lattice_hash(cell + vec3<i32>(1, 0, 1)), u.x);
+    let x11 = mix(lattice_hash(cell + vec3<i32>(0, 1, 1)), lattice_hash(cell + vec3<i32>(1, 1, 1)), u.x);
+    return mix(mix(x00, x10, u.y), mix(x01, x11, u.y), u.z);
+}
+
+// Three unrelated noise values, the potential the curl noise is taken of.
+fn noise_potential(p: vec3<f32>) -> vec3<f32> {
+    return vec3<f32>(value_noise(p), value_noise(p + vec3<f32>(31.4, 0.0, 0.0)), value_noise(p + vec3<f32>(0.0, 0.0, 47.2)));
+}
+
+// The curl of the potential flows in swirls, without pushing the boids together or apart.
+fn curl_noise(p: vec3<f32>) -> vec3<f32> {
+    let e = 0.01;
+    let dx = (noise_potential(p + vec3<f32>(e, 0.0, 0.0)) - noise_potential(p - vec3<f32>(e, 0.0, 0.0))) / (2.0 * e);
+    let dy = (noise_potential(p + vec3<f32>(0.0, e, 0.0)) - noise_potential(p - vec3<f32>(0.0, e, 0.0))) / (2.0 * e);
+    let dz = (noise_potential(p + vec3<f32>(0.0, 0.0, e)) - noise_potential(p - vec3<f32>(0.0, 0.0, e))) / (2.0 * e);
+    return vec3<f32>(dy.z - dz.y, dz.x - dx.z, dx.y - dy.x);
+}
+
+// The velocity of the air at the position, the wind with its gust and the turbulence that slowly changes over time.
+fn wind_at(pos: vec3<f32>) -> vec3<f32> {
+    var velocity = wind.velocity.xyz;
+    if (wind.velocity.w > 0.0) {
+        velocity += curl_noise(pos / wind.turbulence_scale + vec3<f32>(0.0, wind.time * 0.1, 0.0)) * wind.velocity.w;
+    }
+    return velocity;
+}
+
+// The offset from the position to the closest point of the attractor in xyz and the distance to it in w.
+fn attractor_offset(attractor: Attractor, pos: vec3<f32>, wrap: bool) -> vec4<f32> {
+    var offset = vec4<f32>(pos - attractor.a.xyz, 0.0);
+    if (wrap) {
+        offset = minimum_image(offset);
+    }
+    let p = offset.xyz;
+    var closest = vec3<f32>(0.0);
+    if (u32(attractor.a.w) != ATTRACTOR_POINT) {
+        let axis = attractor.b.xyz - attractor.a.xyz;
+        closest = axis * clamp(dot(p, axis) / max(dot(axis, axis), 0.000001), 0.0, 1.0);
+    }
+    let to = closest - p;
+    return vec4<f32>(to, length(to));
+}
+
+// Pulls towards the closest point, a path segment also pulls along itself so the boids follow the path.
+fn attractor_force(attractor: Attractor, to: vec4<f32>) -> vec4<f32> {
+    var strength = attractor.b.w;
+    if (attractor.falloff > 0.0) {
+        strength *= attractor.falloff / (attractor.falloff + to.w);
+    }
+    var pull = vec3<f32>(0.0);
+    if (to.w > 0.0) {
+        pull = to.xyz / to.w;
+    }
+    let axis = attractor.b.xyz - attractor.a.xyz;
+    if (u32(attractor.a.w) == ATTRACTOR_PATH && length(axis) > 0.0) {
+        // Boids on the path are only pulled along it.
+        if (attractor.falloff > 0.0) {
+            pull *= min(to.w / attractor.falloff, 1.0);
+        }
+        pull += normalize(axis);
+    }
+    return vec4<f32>(pull * strength, 0.0);
+}
 
-    //let total_grids = arrayLength(&amount_of_crows_vec);
-    //let total_indices = arrayLength(&crow_idxs);
+@compute @workgroup_size(32)
+fn main(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
 
-    let total_boids = arrayLength(&boids_src);
+    let total_boids = alive_count;
     let index = invocation_id.x;
 
     if (index >= total_boids) {
@@ -63,55 +388,58 @@ fn main(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
 
     var vPos = boids_src[index].pos; // Boid Position
     var vVel = boids_src[index].vel; // Boid Velocity
-
-    var grid_x = u32((vPos.x / params.cell_size) + (params.grid_size * 0.5)); //% u32(params.grid_size);
-    var grid_y = u32((vPos.y / params.cell_size) + (params.grid_size * 0.5)); //% u32(params.grid_size);
-    var grid_z = u32((vPos.z / params.cell_size) + (params.grid_size * 0.5)); //% u32(params.grid_size);
-
-    var grid_idx: u32 = grid_x * u32(params.grid_size) * u32(params.grid_size) + grid_y * u32(params.grid_size) + grid_z;
-    var start_idx: u32 = 0u;
-    var end_idx: u32 = amount_of_crows_vec[grid_idx]; 
-    if (grid_idx > 0u) {
-        start_idx = amount_of_crows_vec[grid_idx - 1u];
-    }
-
-    // var delta_x: i32 = -1;
-    // while (delta_x <= 1) {
-    //     var delta_y: i32 = -1;
-    //     while (delta_y <= 1) {
-    //         var delta_z: i32 = -1;
-    //         while (delta_z <= 1) {
-    //             if (delta_x != 0 || delta_y != 0 || delta_z != 0) {
-    //                 let neighbor_grid_x = wrap_around(i32(grid_x) + delta_x, grid_size_x);
-    //                 let neighbor_grid_y = wrap_around(i32(grid_y) + delta_y, grid_size_y);
-    //                 let neighbor_grid_z = wrap_around(i32(grid_z) + delta_z, grid_size_z);
-
-    //                 let neighbor_grid_index = neighbor_grid_x +
-    //                                           neighbor_grid_y * grid_size_x +
-    //                                           neighbor_grid_z * grid_size_x * grid_size_y;
-    //             }
-    //             delta_z = delta_z + 1;
-    //         }
-    //         delta_y = delta_y + 1;
-    //     }
-    //     delta_x = delta_x + 1;
-    // }
-
-
+    var state = boids_src[index].state;
+    let species = min(u32(vPos.w), params.species_count - 1u);
+    let sp = params.species[species];
+
+    let grid_size = i32(params.grid_size);
+    let grid_pos = vPos.xyz - grid_origin.xyz;
+    let grid_x = grid_coordinate(grid_pos.x);
+    let grid_y = grid_coordinate(grid_pos.y);
+    let grid_z = grid_coordinate(grid_pos.z);
+
+    // Only a toroidal world searches the cells on the other side of the grid.
+    let wrap = params.boundary_mode == BOUNDARY_WRAP;
+    var max_delta = 1;
+    if (wrap) {
+        // With less than 3 cells the neighbour on both sides is the same cell, so it should only be visited once.
+        max_delta = min(1, grid_size - 2);
+    }
+    // A boid outside of the grid has no neighbours, so no cells are searched.
+    if (outside_grid(grid_pos)) {
+        max_delta = -2;
+    }
 
     var total_seperation : vec4<f32> = vec4<f32>(0.0, 0.0, 0.0, 0.0);
     var total_alignment: vec4<f32> = vec4<f32>(0.0, 0.0, 0.0, 0.0);
     var total_cohesion : vec4<f32> = vec4<f32>(0.0, 0.0, 0.0, 0.0);
     var alignmentCount : i32 = 0;
     var cohesionCount: i32 = 0;
+    var startled = false;
+
+    // The nearest neighbours found so far sorted by distance, the offset in xyz and the distance in w.
+    let topological = params.neighbour_mode == NEIGHBOUR_TOPOLOGICAL;
+    let k = clamp(params.nearest_count, 1u, MAX_NEAREST);
+    var nearest: array<vec4<f32>, MAX_NEAREST>;
+    var nearest_idxs: array<u32, MAX_NEAREST>;
+    var nearest_found: u32 = 0u;
 
     var pos: vec4<f32>;
     var vel: vec4<f32>;
 
-    for (var cgx = max(grid_x - 1u, 0u); cgx < min(u32(params.grid_size), grid_x + 2u); cgx++){ 
-        for (var cgy = max(grid_y - 1u, 0u); cgy < min(u32(params.grid_size), grid_y + 2u); cgy++) {
-            for (var cgz = max(grid_z - 1u, 0u); cgz < min(u32(params.grid_size), grid_z + 2u); cgz++) {
-                var grid_idx: u32 = cgx * u32(params.grid_size) * u32(params.grid_size) + cgy * u32(params.grid_size) + cgz;
+    for (var dx = -1; dx <= max_delta; dx++) {
+        for (var dy = -1; dy <= max_delta; dy++) {
+            for (var dz = -1; dz <= max_delta; dz++) {
+                var cell = vec3<i32>(grid_x + dx, grid_y + dy, grid_z + dz);
+                if (wrap) {
+                    cell = vec3<i32>(wrap_around(cell.x, grid_size), wrap_around(cell.y, grid_size), wrap_around(cell.z, grid_size));
+                } else if (any(cell < vec3<i32>(0)) || any(cell >= vec3<i32>(grid_size))) {
+                    continue;
+                }
+                let cgx = u32(cell.x);
+                let cgy = u32(cell.y);
+                let cgz = u32(cell.z);
+                var grid_idx: u32 = cgx * u32(grid_size) * u32(grid_size) + cgy * u32(grid_size) + cgz;
                 var start_idx: u32 = 0u;
                 var end_idx: u32 = amount_of_crows_vec[grid_idx];
                 if (grid_idx > 0u) {
@@ -123,19 +451,72 @@ fn main(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
                         continue;
                     }
 
-                    pos = boids_src[crow_idxs[i]].pos;
-                    vel = boids_src[crow_idxs[i]].vel;
+                    let other = boids_src[crow_idxs[i]];
+                    let other_species = min(u32(other.pos.w), params.species_count - 1u);
+                    let interaction = params.interactions[species][other_species];
+                    if (interaction == INTERACTION_IGNORE) {
+                        continue;
+                    }
+
+                    // The position of the other boid as seen from this boid, so flocks stay together across the edges.
+                    // The w is the species, so it is left out of the offset.
+                    var offset = vec4<f32>(other.pos.xyz - vPos.xyz, 0.0);
+                    if (wrap) {
+                        offset = minimum_image(offset);
+                    }
+                    pos = vPos + offset;
+                    vel = other.vel;
+
+                    let dst = length(offset);
+
+                    if (!can_see(sp, vVel.xyz, offset.xyz)) {
+                        continue;
+                    }
+
+                    // A neighbour taking off startles the boids around it, so the whole flock scatters.
+                    let other_state = u32(other.state.x);
+                    if (other_state == STATE_TAKING_OFF && dst < sp.cohesionDistance) {
+                        startled = true;
+                    }
+
+                    // Boids of a species that is avoided are kept away from as far as the boid can see.
+                    if (interaction == INTERACTION_AVOID) {
+                        if (0.0 < dst && dst < sp.cohesionDistance) {
+                            total_seperation += normalize(offset) * f32(-1) / dst;
+                        }
+                        continue;
+                    }
+
+                    if (0.0 < dst && dst < sp.seperationDistance) {
+                        total_seperation += normalize(offset) * f32(-1) / dst;
+                    }
 
-                    let dst = distance(pos, vPos);
+                    // Landing and perched boids are on the ground, the flying boids only keep away from them.
+                    if (other_state == STATE_LANDING || other_state == STATE_PERCHED) {
+                        continue;
+                    }
 
-                    if (0.0 < dst && dst < params.seperationDistance) {
-                        total_seperation += normalize(pos - vPos) * f32(-1) / dst;
+                    if (topological) {
+                        // Insert into the sorted nearest neighbours, the farthest one drops off when there are k already.
+                        if (nearest_found < k || dst < nearest[k - 1u].w) {
+                            var j = min(nearest_found, k - 1u);
+                            while (j > 0u && nearest[j - 1u].w > dst) {
+                                nearest[j] = nearest[j - 1u];
+                                nearest_idxs[j] = nearest_idxs[j - 1u];
+                                j--;
+                            }
+                            nearest[j] = vec4<f32>(offset.xyz, dst);
+                            nearest_idxs[j] = crow_idxs[i];
+                            nearest_found = min(nearest_found + 1u, k);
+                        }
+                        continue;
                     }
-                    if (dst < params.alignmentDistance) {
+
+                    if (dst < sp.alignmentDistance) {
                         total_alignment += vel;
                         alignmentCount += 1;
                     }
-                    if (dst < params.cohesionDistance) {
+                    if (dst < sp.cohesionDistance) {
                         total_cohesion += pos;
                         cohesionCount += 1;
                     }
@@ -176,6 +557,13 @@ fn main(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     //     }
     // }
 
+    for (var n = 0u; n < nearest_found; n++) {
+        total_alignment += boids_src[nearest_idxs[n]].vel;
+        total_cohesion += vPos + vec4<f32>(nearest[n].xyz, 0.0);
+    }
+    alignmentCount += i32(nearest_found);
+    cohesionCount += i32(nearest_found);
+
     if (alignmentCount > 0) {
         total_alignment /= f32(alignmentCount);
     }
@@ -190,18 +578,166 @@ fn main(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
         total_seperation = normalize(total_seperation);
     }
 
-    vVel = vVel + (total_seperation * params.seperationScale) +
-        (total_alignment * params.alignmentScale) +
-        (total_cohesion * params.cohesionScale);
+    vVel = vVel + (total_seperation * sp.seperationScale) +
+        (total_alignment * sp.alignmentScale) +
+        (total_cohesion * sp.cohesionScale);
+
+    if (params.boundary_mode == BOUNDARY_STEER_BACK) {
+        vVel += steer_back(vPos);
+    }
 
-    // clamp velocity for a more pleasing simulation
-    vVel = normalize(vVel) * params.speed;
+    // Flee from the predators that are close, the closer the predator the harder and faster the boid flees.
+    var panic = 0.0;
+    for (var p = 0u; p < predators.count; p++) {
+        let predator = predators.predators[p];
+        var away = vec4<f32>(vPos.xyz - predator.pos.xyz, 0.0);
+        if (wrap) {
+            away = minimum_image(away);
+        }
+        let dst = length(away);
+        if (0.0 < dst && dst < predator.pos.w) {
+            let fear = 1.0 - dst / predator.pos.w;
+            vVel += normalize(away) * fear * predator.flee_strength;
+            panic = max(panic, fear * predator.speed_boost);
+        }
+    }
 
-    // kinematic update
-    vPos = vPos + (vVel * delta_time);
+    // Steer away from the obstacles that are close, the closer the obstacle the harder the boid steers.
+    for (var o = 0u; o < obstacles.count; o++) {
+        let obstacle = obstacles.obstacles[o];
+        let away = obstacle_distance(obstacle, vPos.xyz, wrap);
+        if (away.w < obstacle.margin) {
+            vVel += vec4<f32>(away.xyz, 0.0) * (1.0 - away.w / max(obstacle.margin, 0.0001)) * obstacle.strength;
+        }
+    }
+    let sdf_dst = sdf_distance(vPos.xyz);
+    if (sdf_dst < obstacles.sdf_margin) {
+        vVel += vec4<f32>(sdf_normal(vPos.xyz), 0.0) * (1.0 - sdf_dst / obstacles.sdf_margin) * obstacles.sdf_strength;
+    }
+
+    // Pull towards the attractors, a path pulls towards its closest segment.
+    var a = 0u;
+    loop {
+        if (a >= attractors.count) {
+            break;
+        }
+        var attractor = attractors.attractors[a];
+        var to = attractor_offset(attractor, vPos.xyz, wrap);
+        let segments = max(attractor.path_segments, 1u);
+        for (var s = 1u; s < segments; s++) {
+            let segment = attractors.attractors[a + s];
+            let to_segment = attractor_offset(segment, vPos.xyz, wrap);
+            if (to_segment.w < to.w) {
+                attractor = segment;
+                to = to_segment;
+            }
+        }
+        vVel += attractor_force(attractor, to);
+        a += segments;
+    }
+
+    // Only flying boids follow the rules, the others land, sit or take off.
+    state.y += delta_time;
+    let perch = closest_perch(vPos.xyz, wrap);
+    // The w of the state stays with the boid, unlike its index which changes when boids are despawned.
+    let wait = 0.5 + state.w;
+    state = next_state(state, perch, startled || panic > 0.0, wait, sp.speed * 0.5 * delta_time + PERCH_HEIGHT);
+    let behaviour = u32(state.x);
+
+    // The direction along the perch, so a boid keeps facing the way it flew in.
+    let old_vel = boids_src[index].vel;
+    var along = old_vel.xyz - perch.xyz * dot(old_vel.xyz, perch.xyz);
+    if (length(along) > 0.0) {
+        along = normalize(along);
+    }
+
+    if (behaviour == STATE_LANDING) {
+        // Glide down onto the perch.
+        vVel = vec4<f32>((along - perch.xyz) * sp.speed * 0.5, 0.0);
+    } else if (behaviour == STATE_PERCHED) {
+        // A tiny velocity, so the boid is still drawn facing the way it landed.
+        vVel = vec4<f32>(along * PERCH_HEIGHT, 0.0);
+        vPos = vec4<f32>(vPos.xyz - perch.xyz * (perch.w - PERCH_HEIGHT), vPos.w);
+    } else if (behaviour == STATE_TAKING_OFF) {
+        vVel = vec4<f32>(normalize(along + perch.xyz * 2.0) * sp.speed * (1.0 + panic), 0.0);
+    } else if (params.steering_model == STEERING_REYNOLDS) {
+        vVel = vec4<f32>(steer(sp, old_vel.xyz, vVel.xyz - old_vel.xyz, sp.speed * (1.0 + panic), delta_time), vVel.w);
+    } else {
+        // clamp velocity for a more pleasing simulation
+        vVel = normalize(vVel) * sp.speed * (1.0 + panic);
+    }
+
+    // The wings beat faster the faster the boid flies and fastest when it takes off, a perched boid keeps them still.
+    if (behaviour != STATE_PERCHED) {
+        var beats = sp.flapRate * length(vVel.xyz) / max(sp.speed, 0.001);
+        if (behaviour == STATE_TAKING_OFF) {
+            beats *= 1.5;
+        }
+        state.z = fract(state.z + beats * delta_time);
+    }
+
+    // kinematic update, the wind carries the boid along unless it is perched
+    if (behaviour != STATE_PERCHED) {
+        vPos = vPos + ((vVel + vec4<f32>(wind_at(vPos.xyz), 0.0)) * delta_time);
+    }
     
 
-    // Wrap around boundary
+    if (params.boundary_mode == BOUNDARY_WRAP) {
+        vPos = wrap_position(vPos);
+    } else if (params.boundary_mode == BOUNDARY_BOUNCE) {
+        // Reflect the boids that went through a wall back into the box and turn them around.
+        let outside = abs(vPos.xyz) > vec3<f32>(1.0);
+        let wall = sign(vPos.xyz);
+        vPos = vec4<f32>(select(vPos.xyz, 2.0 * wall - vPos.xyz, outside), vPos.w);
+        vVel = vec4<f32>(select(vVel.xyz, -wall * abs(vVel.xyz), outside), vVel.w);
+    }
+
+    // Write back
+    boids_dst[index].pos = vPos;
+    boids_dst[index].vel = vVel;
+    boids_dst[index].state = state;
+    
+}
+
+// Reynolds style steering, the force of the rules is limited to the max force and accelerates the mass of the boid.
+// The velocity turns at most the max turn rate towards the new velocity and its speed stays between the min and max speed.
+fn steer(sp: SpeciesParams, vel: vec3<f32>, force: vec3<f32>, max_speed: f32, delta_time: f32) -> vec3<f32> {
+    var steering = force;
+    if (length(force) > sp.maxForce) {
+        steering = normalize(force) * sp.maxForce;
+    }
+    var new_vel = vel + steering / max(sp.mass, 0.0001) * delta_time;
+
+    let speed = length(new_vel);
+    if (length(vel) > 0.0 && speed > 0.0) {
+        let heading = normalize(vel);
+        let wanted = new_vel / speed;
+        let max_angle = sp.maxTurnRate * delta_time;
+        let cos_angle = clamp(dot(heading, wanted), -1.0, 1.0);
+        // Turned back exactly, any side works as long as it is perpendicular.
+        var side = wanted - heading * cos_angle;
+        if (length(side) == 0.0) {
+            side = cross(heading, vec3<f32>(0.0, 1.0, 0.0));
+            if (length(side) == 0.0) {
+                side = vec3<f32>(1.0, 0.0, 0.0);
+            }
+        }
+        if (acos(cos_angle) > max_angle) {
+            new_vel = (heading * cos(max_angle) + normalize(side) * sin(max_angle)) * speed;
+        }
+    }
+
+    // A boid without a velocity has no direction to speed up in, so it starts flying along x.
+    var direction = vec3<f32>(1.0, 0.0, 0.0);
+    if (length(new_vel) > 0.0) {
+        direction = normalize(new_vel);
+    }
+    return direction * min(max(length(new_vel), sp.minSpeed), max_speed);
+}
+
+// Wrap around boundary
+fn wrap_position(position: vec4<f32>) -> vec4<f32> {
+    var vPos = position;
     if (vPos.x < -1.0) {
         vPos.x = 1.0 + (1.0 + vPos.x);
     }
@@ -220,9 +756,5 @@ fn main(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     if (vPos.z > 1.0) {
         vPos.z = -1.0 + (vPos.z - 1.0);
     }
-
-    // Write back
-    boids_dst[index].pos = vPos;
-    boids_dst[index].vel = vVel;
-    
+    return vPos;
 }
@@ -0,0 +1,154 @@
+// Builds the grid the boids shader uses to find its neighbours, without it having to leave the GPU.
+// It is a counting sort of the boids by the cell they are in:
+// assign_cells counts the boids of every cell, prefix_sum turns those counts into the end index of every cell,
+// and scatter writes the index of every boid into its place in crow_idxs.
+// The cell counts have to be cleared before assign_cells is run.
+// When the world is open, centroid is run first to move the grid to the centroid of the flock.
+// Only the boids before the alive count are sorted, the buffers can hold more boids than are alive.
+// In an open world the boids that are outside of the grid are left out, they have no neighbours until they are back in it.
+
+#import "shaders/boid_types.wgsl"::{Params, Boid, BOUNDARY_OPEN}
+
+@group(0) @binding(0)
+var<uniform> params: Params;
+
+@group(0) @binding(1)
+var<storage> boids: array<Boid>;
+// Amount of boids in every cell
+@group(0) @binding(2)
+var<storage, read_write> cell_counts: array<atomic<u32>>;
+// The cell of every boid and the place of the boid within that cell
+@group(0) @binding(3)
+var<storage, read_write> boid_cells: array<vec2<u32>>;
+// Same layout as the one the boids shader reads, the end index in crow_idxs of every cell
+@group(0) @binding(4)
+var<storage, read_write> amount_of_crows_vec: array<u32>;
+@group(0) @binding(5)
+var<storage, read_write> crow_idxs: array<u32>;
+// Center of the grid, only moved away from the origin when the world is open
+@group(0) @binding(6)
+var<storage, read_write> grid_origin: vec4<f32>;
+@group(0) @binding(7)
+var<uniform> alive_count: u32;
+
+const PREFIX_SUM_THREADS: u32 = 256u;
+// Cell of the boids that are not sorted into the grid.
+const OUTSIDE_GRID: u32 = 0xffffffffu;
+
+var<workgroup> chunk_sums: array<u32, 256>;
+var<workgroup> position_sums: array<vec3<f32>, 256>;
+
+fn grid_coordinate(coord: f32) -> u32 {
+    let cell = u32(max((coord / params.cell_size) + (params.grid_size * 0.5), 0.0));
+    return min(cell, u32(params.grid_size) - 1u);
+}
+
+// The other modes keep the boids in the box, so the coordinates at the very edge are clamped into the grid instead.
+fn outside_grid(grid_pos: vec3<f32>) -> bool {
+    let half_size = params.grid_size * params.cell_size * 0.5;
+    return params.boundary_mode == BOUNDARY_OPEN && (any(grid_pos < vec3<f32>(-half_size)) || any(grid_pos >= vec3<f32>(half_size)));
+}
+
+fn grid_index(pos: vec4<f32>) -> u32 {
+    let grid_size = u32(params.grid_size);
+    let grid_pos = pos.xyz - grid_origin.xyz;
+    if (outside_grid(grid_pos)) {
+        return OUTSIDE_GRID;
+    }
+    return grid_coordinate(grid_pos.x) * grid_size * grid_size + grid_coordinate(grid_pos.y) * grid_size + grid_coordinate(grid_pos.z);
+}
+
+// Ran as a single workgroup, every thread sums the positions of a part of the boids and the sums are reduced in workgroup memory.
+@compute @workgroup_size(256)
+fn centroid(@builtin(local_invocation_id) local_id: vec3<u32>) {
+    let total_boids = alive_count;
+
+    var sum = vec3<f32>(0.0, 0.0, 0.0);
+    for (var i = local_id.x; i < total_boids; i += PREFIX_SUM_THREADS) {
+        sum += boids[i].pos.xyz;
+    }
+    position_sums[local_id.x] = sum;
+    workgroupBarrier();
+
+    for (var stride = PREFIX_SUM_THREADS / 2u; stride > 0u; stride /= 2u) {
+        if (local_id.x < stride) {
+            position_sums[local_id.x] += position_sums[local_id.x + stride];
+        }
+        workgroupBarrier();
+    }
+
+    if (local_id.x == 0u && total_boids > 0u) {
+        grid_origin = vec4<f32>(position_sums[0] / f32(total_boids), 0.0);
+    }
+}
+
+@compute @workgroup_size(32)
+fn assign_cells(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = invocation_id.x;
+    if (index >= alive_count) {
+        return;
+    }
+
+    let cell = grid_index(boids[index].pos);
+    if (cell == OUTSIDE_GRID) {
+        boid_cells[index] = vec2<u32>(OUTSIDE_GRID, 0u);
+        return;
+    }
+    let place_in_cell = atomicAdd(&cell_counts[cell], 1u);
+    boid_cells[index] = vec2<u32>(cell, place_in_cell);
+}
+
+// Ran as a single workgroup, every thread sums a chunk of the cells,
+// the sums of the chunks are scanned in workgroup memory and then every thread writes its own chunk.
+@compute @workgroup_size(256)
+fn prefix_sum(@builtin(local_invocation_id) local_id: vec3<u32>) {
+    let total_cells = arrayLength(&amount_of_crows_vec);
+    let chunk_size = (total_cells + PREFIX_SUM_THREADS - 1u) / PREFIX_SUM_THREADS;
+    let start = local_id.x * chunk_size;
+    let end = min(start + chunk_size, total_cells);
+
+    var sum: u32 = 0u;
+    for (var i = start; i < end; i++) {
+        sum += atomicLoad(&cell_counts[i]);
+    }
+    chunk_sums[local_id.x] = sum;
+    workgroupBarrier();
+
+    // Inclusive scan of the chunk sums
+    for (var offset = 1u; offset < PREFIX_SUM_THREADS; offset *= 2u) {
+        var value: u32 = 0u;
+        if (local_id.x >= offset) {
+            value = chunk_sums[local_id.x - offset];
+        }
+        workgroupBarrier();
+        chunk_sums[local_id.x] += value;
+        workgroupBarrier();
+    }
+
+    var running: u32 = 0u;
+    if (local_id.x > 0u) {
+        running = chunk_sums[local_id.x - 1u];
+    }
+    for (var i = start; i < end; i++) {
+        running += atomicLoad(&cell_counts[i]);
+        amount_of_crows_vec[i] = running;
+    }
+}
+
+@compute @workgroup_size(32)
+fn scatter(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = invocation_id.x;
+    if (index >= alive_count) {
+        return;
+    }
+
+    let boid_cell = boid_cells[index];
+    if (boid_cell.x == OUTSIDE_GRID) {
+        return;
+    }
+    var start_idx: u32 = 0u;
+    if (boid_cell.x > 0u) {
+        start_idx = amount_of_crows_vec[boid_cell.x - 1u];
+    }
+    crow_idxs[start_idx + boid_cell.y] = index;
+}
@@ -1,29 +1,141 @@
 // Instancing shader from the bevy example:
 // https://github.com/bevyengine/bevy/blob/release-0.12.1/assets/shaders/instancing.wgsl
 // Modified to be able to orient the birds towards the velocity
+// The boids are read straight from the storage buffer of the compute shader using the instance index.
+// Instances after the alive count are moved outside of the view, as the amount of instances can be more than there are boids alive.
+// The colour and size of a crow come from its species, which is stored in the w of its position.
+// The wings of crow1.obj are bent up and down with the wing phase in the z of the state of the boid.
+// The crows are lit with the pbr lighting of bevy, shadow_vertex draws them into the shadow maps of the lights and the prepass.
+// prepass_fragment writes the normals and the motion vectors of the crows when the view has those prepasses.
+// Every species is drawn in the phase of its alpha mode, the pipelines of the other phases hide its boids.
 // Also has a commented line that would modify the color of the crow based on the velocity.
-#import bevy_pbr::mesh_functions::{get_model_matrix, mesh_position_local_to_clip}
+#import bevy_pbr::{
+    mesh_view_bindings::view,
+    view_transformations::position_world_to_clip,
+    pbr_types::pbr_input_new,
+    pbr_functions::{prepare_world_normal, calculate_view, apply_pbr_lighting, main_pass_post_lighting_processing},
+    mesh_types::MESH_FLAGS_SHADOW_RECEIVER_BIT,
+    prepass_bindings,
+}
+#import bevy_render::globals::Globals
+#import "shaders/boid_types.wgsl"::{Boid, STATE_LANDING, STATE_PERCHED, STATE_TAKING_OFF}
+
+struct BoidsInstance {
+    colors: array<vec4<f32>, 4>,
+    sizes: vec4<f32>,
+    alpha_modes: vec4<u32>,
+    alpha_cutoffs: vec4<f32>,
+    world_scale: f32,
+    scale: f32,
+}
+
+@group(2) @binding(0)
+var<storage, read> boids: array<Boid>;
+@group(2) @binding(1)
+var<uniform> boids_instance: BoidsInstance;
+@group(2) @binding(2)
+var<uniform> alive_count: u32;
+
+// Has to match Species::alpha_shader_value
+const ALPHA_MODE_OPAQUE: u32 = 0u;
+const ALPHA_MODE_MASK: u32 = 1u;
+const ALPHA_MODE_BLEND: u32 = 2u;
+
+// Distance from the middle of crow1.obj where the wings start and end, along its x.
+const WING_ROOT: f32 = 2.0;
+const WING_TIP: f32 = 13.3;
+// Radians the wings are raised and lowered at the root, the tips go a bit further so the wings bend.
+const FLAP_ANGLE: f32 = 0.6;
+const TAU: f32 = 6.2831853;
+// Feathers are not shiny.
+const ROUGHNESS: f32 = 0.8;
 
 struct Vertex {
+    @builtin(instance_index) instance_index: u32,
     @location(0) position: vec3<f32>,
     @location(1) normal: vec3<f32>,
     @location(2) uv: vec2<f32>,
-
-    @location(3) i_pos_scale: vec4<f32>,
-    @location(4) i_vel: vec4<f32>,
-    @location(5) i_color: vec4<f32>,
 };
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) color: vec4<f32>,
     @location(1) vel: vec4<f32>,
+    @location(2) world_position: vec4<f32>,
+    @location(3) world_normal: vec3<f32>,
+    @location(4) @interpolate(flat) species: u32,
 };
 
-@vertex
-fn vertex(vertex: Vertex) -> VertexOutput {
+// A vertex of the mesh, either in the space of the mesh or placed in the world.
+struct BoidVertex {
+    position: vec3<f32>,
+    normal: vec3<f32>,
+};
+
+// Rotates the vertices of the wings around the root of the wing, the body stays where it is.
+// The normals are rotated with them, so the wings are lit from the right side.
+fn flap_wing(vertex: BoidVertex, state: vec4<f32>) -> BoidVertex {
+    let from_root = abs(vertex.position.x) - WING_ROOT;
+    if (from_root <= 0.0) {
+        return vertex;
+    }
+    let side = sign(vertex.position.x);
+    let behaviour = u32(state.x);
+    if (behaviour == STATE_PERCHED) {
+        // The wings are folded against the body.
+        return BoidVertex(vec3<f32>(side * (WING_ROOT + from_root * 0.15), vertex.position.yz), vertex.normal);
+    }
+
+    // Landing boids glide with their wings spread, taking off they flap the hardest.
+    var amplitude = 1.0;
+    if (behaviour == STATE_LANDING) {
+        amplitude = 0.2;
+    } else if (behaviour == STATE_TAKING_OFF) {
+        amplitude = 1.4;
+    }
+    let bend = 1.0 + 0.5 * from_root / (WING_TIP - WING_ROOT);
+    let angle = sin(state.z * TAU) * FLAP_ANGLE * amplitude * bend;
+    let normal_x = side * vertex.normal.x;
+    return BoidVertex(
+        vec3<f32>(
+            side * (WING_ROOT + from_root * cos(angle) - vertex.position.y * sin(angle)),
+            from_root * sin(angle) + vertex.position.y * cos(angle),
+            vertex.position.z
+        ),
+        vec3<f32>(
+            side * (normal_x * cos(angle) - vertex.normal.y * sin(angle)),
+            normal_x * sin(angle) + vertex.normal.y * cos(angle),
+            vertex.normal.z
+        )
+    );
+}
+
+// Whether the boids of the species are drawn by this pipeline.
+// The depth pipeline of the shadows and the prepass draws every species that writes its depth.
+fn in_this_phase(species: u32) -> bool {
+    let alpha_mode = boids_instance.alpha_modes[species];
+    // A masked species is the same colour all over, so it is hidden entirely when its alpha is below the cutoff.
+    if (alpha_mode == ALPHA_MODE_MASK && boids_instance.colors[species].a < boids_instance.alpha_cutoffs[species]) {
+        return false;
+    }
+#ifdef DEPTH_PREPASS
+    return alpha_mode != ALPHA_MODE_BLEND;
+#else ifdef BLEND_ALPHA
+    return alpha_mode == ALPHA_MODE_BLEND;
+#else ifdef MAY_DISCARD
+    return alpha_mode == ALPHA_MODE_MASK;
+#else
+    return alpha_mode == ALPHA_MODE_OPAQUE;
+#endif
+}
+
+// Flaps the wings, then turns the crow towards its velocity and moves it to the boid.
+// The crows entity has no transform, so the boids are placed straight in the world.
+fn place_vertex(boid: Boid, vertex: Vertex) -> BoidVertex {
+    let species = min(u32(boid.pos.w), 3u);
+
     //Flip the velocity, so the crows front is at the front.
-    let i_vel = vertex.i_vel * -1.0f;
+    let i_vel = boid.vel * -1.0f;
     let yaw = atan2(i_vel.x, i_vel.z);
     let pitch = atan2(i_vel.y, length(i_vel.xz));
 
@@ -38,32 +150,164 @@ fn vertex(vertex: Vertex) -> VertexOutput {
         vec3<f32>(0.0, sin(pitch), cos(pitch))
     );
 
-    // Rotate the position
-    let pos = rotation * vertex.position;
-    
-    let position = pos * vertex.i_pos_scale.w + vertex.i_pos_scale.xyz;
-    var out: VertexOutput;
+    // Flap the wings, then rotate the position and the normal
+    let flapped = flap_wing(BoidVertex(vertex.position, vertex.normal), boid.state);
+    let pos = rotation * flapped.position;
 
+    // From the -1 to 1 simulation space to the world, the same as SimulationConfig::simulation_to_world.
+    // The box is centered on the origin, like the predators, the obstacles and the ground.
+    let world_pos = boids_instance.world_scale * boid.pos.xyz;
 
+    let position = pos * boids_instance.scale * boids_instance.sizes[species] + world_pos;
+    return BoidVertex(position, normalize(rotation * flapped.normal));
+}
 
-    // NOTE: Passing 0 as the instance_index to get_model_matrix() is a hack
-    // for this example as the instance_index builtin would map to the wrong
-    // index in the Mesh array. This index could be passed in via another
-    // uniform instead but it's unnecessary for the example.
-    out.clip_position = mesh_position_local_to_clip(
-        get_model_matrix(0u),
-        vec4<f32>(position, 1.0)
-    );
-    out.color = vertex.i_color;
-    out.vel = i_vel;
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+    let species = min(u32(boids[vertex.instance_index].pos.w), 3u);
+    if (vertex.instance_index >= alive_count || !in_this_phase(species)) {
+        // Every vertex ends up at the same point outside of the clip space, so nothing is drawn.
+        out.clip_position = vec4<f32>(2.0, 2.0, 2.0, 1.0);
+        out.color = vec4<f32>(0.0);
+        out.vel = vec4<f32>(0.0);
+        out.world_position = vec4<f32>(0.0);
+        out.world_normal = vec3<f32>(0.0, 1.0, 0.0);
+        out.species = 0u;
+        return out;
+    }
+
+    let boid = boids[vertex.instance_index];
+    let placed = place_vertex(boid, vertex);
+
+    out.clip_position = position_world_to_clip(placed.position);
+    out.world_position = vec4<f32>(placed.position, 1.0);
+    out.world_normal = placed.normal;
+    out.color = boids_instance.colors[species];
+    out.species = species;
+    out.vel = boid.vel * -1.0;
     return out;
 }
 
 @fragment
-fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+fn fragment(in: VertexOutput, @builtin(front_facing) is_front: bool) -> @location(0) vec4<f32> {
+    //return vec4<f32>(abs(normalize(in.vel.xyz)), 0.3);
+
+    // The same lighting as a StandardMaterial with the colour of the species.
+    var pbr_input = pbr_input_new();
+    pbr_input.material.base_color = in.color;
+    pbr_input.material.perceptual_roughness = ROUGHNESS;
+    pbr_input.frag_coord = in.clip_position;
+    pbr_input.world_position = in.world_position;
+    pbr_input.world_normal = prepare_world_normal(in.world_normal, true, is_front);
+    pbr_input.N = normalize(pbr_input.world_normal);
+    pbr_input.is_orthographic = view.projection[3].w == 1.0;
+    pbr_input.V = calculate_view(in.world_position, pbr_input.is_orthographic);
+    pbr_input.flags = MESH_FLAGS_SHADOW_RECEIVER_BIT;
+
+    let color = apply_pbr_lighting(pbr_input);
+#ifdef MAY_DISCARD
+    if (color.a < boids_instance.alpha_cutoffs[in.species]) {
+        discard;
+    }
+#endif
+    return main_pass_post_lighting_processing(pbr_input, color);
+}
+
+#ifdef MOTION_VECTOR_PREPASS
+// The globals are in another binding in the view bind group of the prepass than in mesh_view_bindings.
+@group(0) @binding(1)
+var<uniform> prepass_globals: Globals;
+#endif
+
+struct ShadowOutput {
+    @builtin(position) clip_position: vec4<f32>,
+#ifdef DEPTH_CLAMP_ORTHO
+    @location(0) clip_position_unclamped: vec4<f32>,
+#endif
+#ifdef NORMAL_PREPASS
+    @location(1) world_normal: vec3<f32>,
+#endif
+#ifdef MOTION_VECTOR_PREPASS
+    @location(2) world_position: vec4<f32>,
+    @location(3) previous_world_position: vec4<f32>,
+#endif
+};
 
-    let vel_n = normalize(in.vel);
+// Only the depth of the crows is drawn into the shadow maps, the same as the prepass of bevy.
+@vertex
+fn shadow_vertex(vertex: Vertex) -> ShadowOutput {
+    var out: ShadowOutput;
+    let species = min(u32(boids[vertex.instance_index].pos.w), 3u);
+    if (vertex.instance_index >= alive_count || !in_this_phase(species)) {
+        out.clip_position = vec4<f32>(2.0, 2.0, 2.0, 1.0);
+#ifdef DEPTH_CLAMP_ORTHO
+        out.clip_position_unclamped = out.clip_position;
+#endif
+#ifdef NORMAL_PREPASS
+        out.world_normal = vec3<f32>(0.0, 1.0, 0.0);
+#endif
+#ifdef MOTION_VECTOR_PREPASS
+        out.world_position = vec4<f32>(0.0);
+        out.previous_world_position = vec4<f32>(0.0);
+#endif
+        return out;
+    }
+
+    let boid = boids[vertex.instance_index];
+    let placed = place_vertex(boid, vertex);
+    out.clip_position = position_world_to_clip(placed.position);
+#ifdef DEPTH_CLAMP_ORTHO
+    // Crows behind the near plane of a directional light still cast a shadow.
+    out.clip_position_unclamped = out.clip_position;
+    out.clip_position.z = min(out.clip_position.z, 1.0);
+#endif
+#ifdef NORMAL_PREPASS
+    out.world_normal = placed.normal;
+#endif
+#ifdef MOTION_VECTOR_PREPASS
+    // The crows have no transform of the previous frame, so where the crow was is estimated from the velocity of its boid.
+    // The flapping of the wings is left out of the motion vectors.
+    out.world_position = vec4<f32>(placed.position, 1.0);
+    let world_velocity = boid.vel.xyz * boids_instance.world_scale;
+    out.previous_world_position = vec4<f32>(placed.position - world_velocity * prepass_globals.delta_time, 1.0);
+#endif
+    return out;
+}
+
+// Not PREPASS_FRAGMENT, which would leave the lighting out of pbr_functions.
+#ifdef PREPASS_OUTPUTS
+struct PrepassOutput {
+#ifdef NORMAL_PREPASS
+    @location(0) normal: vec4<f32>,
+#endif
+#ifdef MOTION_VECTOR_PREPASS
+    @location(1) motion_vector: vec2<f32>,
+#endif
+}
 
-    //return vec4<f32>(abs(vel_n.x), abs(vel_n.y), abs(vel_n.z), 0.3);
-    return in.color;
+// Writes the normals and the motion vectors the same way as the prepass of bevy.
+@fragment
+fn prepass_fragment(in: ShadowOutput, @builtin(front_facing) is_front: bool) -> PrepassOutput {
+    var out: PrepassOutput;
+#ifdef NORMAL_PREPASS
+    let world_normal = normalize(prepare_world_normal(in.world_normal, true, is_front));
+    out.normal = vec4<f32>(world_normal * 0.5 + vec3<f32>(0.5), 1.0);
+#endif
+#ifdef MOTION_VECTOR_PREPASS
+    let clip_position_t = view.unjittered_view_proj * in.world_position;
+    let clip_position = clip_position_t.xy / clip_position_t.w;
+    let previous_clip_position_t = prepass_bindings::previous_view_proj * in.previous_world_position;
+    let previous_clip_position = previous_clip_position_t.xy / previous_clip_position_t.w;
+    out.motion_vector = (clip_position - previous_clip_position) * vec2<f32>(0.5, -0.5);
+#endif
+    return out;
+}
+#endif
+
+#ifdef DEPTH_CLAMP_ORTHO
+@fragment
+fn shadow_fragment(in: ShadowOutput) -> @builtin(frag_depth) f32 {
+    return in.clip_position_unclamped.z;
 }
+#endif
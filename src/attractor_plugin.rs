@@ -0,0 +1,119 @@
+//! This file is responsible for the attractors that pull the boids towards them, ie. a tree to roost on or a flight path to follow.
+//! Every frame the Attractor components are collected into the Attractors resource, which both simulation backends read.
+//! Use `--attractor roost`, `path` or `camera` to let the flock roost on a tree, fly along a loop or chase the camera.
+
+use bevy::prelude::*;
+use crate::shared::*;
+
+pub struct AttractorPlugin {
+    pub demo: Option<AttractorDemo>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AttractorDemo {
+    //Pulls the flock to the crown of the tree of the obstacle demo.
+    Roost,
+    //A loop through the box that the flock follows.
+    Path,
+    //The flock chases the camera around.
+    Camera,
+}
+
+impl AttractorPlugin {
+    //Reads the demo from the command line, ie. `cargo run -- --attractor path`
+    pub fn from_args() -> Self {
+        let args: Vec<String> = std::env::args().collect();
+        let demo = args.windows(2)
+            .find(|pair| pair[0] == "--attractor")
+            .and_then(|pair| match pair[1].as_str() {
+                "roost" => Some(AttractorDemo::Roost),
+                "path" => Some(AttractorDemo::Path),
+                "camera" => Some(AttractorDemo::Camera),
+                _ => None,
+            });
+        Self { demo }
+    }
+}
+
+impl Plugin for AttractorPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Attractors>()
+            .add_systems(PostUpdate, collect_attractors);
+        match self.demo {
+            Some(AttractorDemo::Roost) => {
+                app.add_systems(Startup, spawn_roost);
+            },
+            Some(AttractorDemo::Path) => {
+                app.add_systems(Startup, spawn_flight_path);
+            },
+            //The camera is spawned by the scene setup, so it is only found after startup.
+            Some(AttractorDemo::Camera) => {
+                app.add_systems(Update, attract_to_camera);
+            },
+            None => {}
+        }
+    }
+}
+
+fn spawn_roost(mut commands: Commands, config: Res<SimulationConfig>) {
+    commands.spawn((
+        SpatialBundle::from_transform(Transform::from_translation(config.simulation_to_world(Vec3::new(-0.5, -0.45, -0.3)))),
+        Attractor::new(AttractorShape::Point).with_weight(2.0),
+    ));
+}
+
+fn spawn_flight_path(mut commands: Commands, config: Res<SimulationConfig>) {
+    let waypoints = (0..8)
+        .map(|i| {
+            let angle = i as f32 / 8.0 * std::f32::consts::TAU;
+            config.simulation_to_world(Vec3::new(angle.cos() * 0.6, (angle * 2.0).sin() * 0.3, angle.sin() * 0.6))
+        })
+        .collect();
+    commands.spawn((
+        SpatialBundle::default(),
+        Attractor::new(AttractorShape::Path { waypoints, looped: true }).with_weight(1.5),
+    ));
+}
+
+fn attract_to_camera(mut commands: Commands, cameras: Query<Entity, (With<Camera3d>, Without<Attractor>)>) {
+    for camera in &cameras {
+        commands.entity(camera).insert(Attractor::new(AttractorShape::Point).with_falloff(0.0));
+    }
+}
+
+fn collect_attractors(
+    config: Res<SimulationConfig>,
+    query: Query<(&Transform, &Attractor)>,
+    mut attractors: ResMut<Attractors>,
+) {
+    attractors.0.clear();
+    for (transform, attractor) in &query {
+        let shape = attractor.shape.shader_value() as f32;
+        let data = |a: Vec3, b: Vec3, path_segments: u32| AttractorData {
+            a: config.world_to_simulation(a).extend(shape),
+            b: config.world_to_simulation(b).extend(attractor.weight),
+            falloff: attractor.falloff,
+            path_segments,
+            _padding0: 0.0,
+            _padding1: 0.0,
+        };
+        match &attractor.shape {
+            AttractorShape::Point => attractors.0.push(data(transform.translation, transform.translation, 1)),
+            AttractorShape::Line { half_length } => {
+                let axis = transform.rotation * Vec3::Y * *half_length * transform.scale.y;
+                attractors.0.push(data(transform.translation - axis, transform.translation + axis, 1));
+            },
+            AttractorShape::Path { waypoints, looped } => {
+                let points: Vec<Vec3> = waypoints.iter().map(|waypoint| transform.transform_point(*waypoint)).collect();
+                let mut segments: Vec<(Vec3, Vec3)> = points.windows(2).map(|pair| (pair[0], pair[1])).collect();
+                if *looped && points.len() > 2 {
+                    segments.push((points[points.len() - 1], points[0]));
+                }
+                for (i, (start, end)) in segments.iter().enumerate() {
+                    let path_segments = if i == 0 { segments.len() as u32 } else { 0 };
+                    attractors.0.push(data(*start, *end, path_segments));
+                }
+            },
+        }
+    }
+}
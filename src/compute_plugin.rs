@@ -1,227 +1,538 @@
-//! This file is responsible for updating the boids by executing the compute shader.
-//! In order to get this to work on web we have used some Arc pointers.
+//! This file is responsible for updating the boids by executing the compute shaders.
+//! The compute shaders run as a node in the render graph of bevy, using its render device, queue and pipeline cache,
+//! so there is a single GPU context and the instancing plugin can draw the boids straight from the storage buffers.
+//! The boids are only read back to the cpu when requested through the BoidReadback resource, for which we have used some Arc pointers.
+//! Boids can be added and removed while running with the SpawnBoids and DespawnBoids events.
+//! The buffers are made for a capacity of boids and an alive count tells the shaders how many of those are alive,
+//! the buffers are replaced with bigger ones when the spawned boids don't fit anymore.
+//! The Predators, Obstacles and Attractors are uploaded to storage buffers whenever they change, which are also replaced when they don't fit.
+//! The ObstacleSdf is uploaded to a 3d texture, which the simulation shader reads the distance to the mesh obstacles from.
+//! The WindData is uploaded to a uniform every frame, as the gusts and turbulence change over time.
 //! This file is inspired by multiple examples:
 //! https://github.com/gfx-rs/wgpu/blob/trunk/examples/src/hello_compute/mod.rs
 //! To gain an understanding of how to copy to and read from buffers that are sent to the GPU and back
 //! https://github.com/gfx-rs/wgpu/blob/trunk/examples/src/boids/mod.rs
 //! To modify the first example in order to work with the boids algorithm.
-//! https://github.com/bevyengine/bevy/blob/main/examples/shader/compute_shader_game_of_life.rs
-//! To understand how to modify the wgpu example to bevy and some general understanding of bevys render pass.
+//! https://github.com/bevyengine/bevy/blob/release-0.12.1/examples/shader/compute_shader_game_of_life.rs
+//! To understand how to run a compute shader as a node in the render graph of bevy.
 //! https://docs.rs/bevy_render/latest/src/bevy_render/lib.rs.html#70-72
 //! To understand how bevy handles async calls on wasm.
 
-use std::borrow::Cow;
+use std::{borrow::Cow, collections::VecDeque};
 use bevy::{
-    core::Pod, ecs::system::SystemState, prelude::*, render::{
-        renderer::RenderDevice,
+    core::Pod, prelude::*, render::{
+        extract_resource::ExtractResourcePlugin,
+        render_graph::{self, RenderGraph},
+        renderer::{RenderContext, RenderDevice, RenderQueue},
         render_resource::*,
-    }, tasks::{ComputeTaskPool, IoTaskPool}
+        main_graph::node::CAMERA_DRIVER,
+        Extract, ExtractSchedule, MainWorld, Render, RenderApp, RenderSet,
+    }, tasks::AsyncComputeTaskPool
 };
+use bytemuck::Zeroable;
 use std::sync::{Arc, Mutex};
-use wgpu::Queue;
-use rand::distributions::{Distribution, Uniform};
 use crate::shared::*;
 
+const BOIDS_NODE: &str = "boids_compute";
+
+//Smallest amount of boids the buffers are made for, so they are never empty.
+const MIN_CAPACITY: u32 = 32;
+
 pub struct ComputePlugin;
 
 impl Plugin for ComputePlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, run_compute);
-        app.add_systems(PostUpdate, update_compute_resources);
+        app.add_systems(Update, update_simulation_step);
         app.add_systems(PostUpdate, update_boids);
-
-        let params = [
-            0.5, //speed
-            0.02, //seperation d
-            0.05, // alignment d
-            0.1, // cohesion d
-            1.0, // seperation s
-            1., // alignment s
-            1., // cohesion s
-            GRID_SIZE,
-            CELL_SIZE
-        ];
-
-        //Init grid
-        let mut grid = Grid::new(GRID_SIZE as usize, CELL_SIZE);
-        let mut initial_boids_data: Vec<Boid> = Vec::with_capacity(NUM_BOIDS as usize);
-        let mut rng = rand::thread_rng();
-        let unif = Uniform::new_inclusive(-1., 1.);
-
-        for i in 0..NUM_BOIDS {
-            let x_coords =  unif.sample(&mut rng) as f32;
-            let y_coords = unif.sample(&mut rng) as f32;
-            let z_coords = unif.sample(&mut rng) as f32;
-            let transform = Transform::from_xyz(x_coords, y_coords, z_coords).with_scale(Vec3::splat(0.02));
-            grid.add_with_transform(&transform, &(i as usize));
-            initial_boids_data.push(Boid {
-                pos: Vec4::new(
-                    x_coords,
-                    y_coords,
-                    z_coords,
-                    0 as f32),
-                vel: Vec4::new(
-                    unif.sample(&mut rng) as f32,
-                    unif.sample(&mut rng) as f32,
-                    unif.sample(&mut rng) as f32,
-                    0 as f32)
-            });
+        app.init_resource::<SimulationTime>();
+        app.init_resource::<SimulationStep>();
+        app.init_resource::<SimulationFrame>();
+        app.init_resource::<BoidReadback>();
+        app.init_resource::<BoidCount>();
+        app.init_resource::<Predators>();
+        app.init_resource::<Obstacles>();
+        app.init_resource::<ObstacleSdf>();
+        app.init_resource::<Attractors>();
+        app.init_resource::<WindData>();
+        app.add_event::<SpawnBoids>();
+        app.add_event::<DespawnBoids>();
+        if !app.is_plugin_added::<ExtractResourcePlugin<Flocks>>() {
+            app.add_plugins(ExtractResourcePlugin::<Flocks>::default());
         }
-        
-        let mut amount_of_crows_vec: Vec<u32> = Vec::with_capacity(grid.size.pow(3));
-        let mut crow_idxs: Vec<u32> = Vec::with_capacity(NUM_BOIDS as usize);
-        
-        for x in 0..grid.size {
-            for y in 0..grid.size {
-                for z in 0..grid.size {
-                    let amount_of_crows = grid.grid[x][y][z].crows.len();
-                    for crow in &grid.grid[x][y][z].crows {
-                        crow_idxs.push(crow.clone() as u32);
-                    }
-                    let current_amount = amount_of_crows_vec.last();
-                    
-                    let mut total_amount = amount_of_crows as u32;
-                    match current_amount {
-                        Some(val) => total_amount += val,
-                        None => {},
-                    }
-                    amount_of_crows_vec.push(total_amount)
-                }
-            }
+        if !app.is_plugin_added::<ExtractResourcePlugin<SimulationConfig>>() {
+            app.add_plugins(ExtractResourcePlugin::<SimulationConfig>::default());
         }
+        app.add_plugins(ExtractResourcePlugin::<Predators>::default());
+        app.add_plugins(ExtractResourcePlugin::<Obstacles>::default());
+        app.add_plugins(ExtractResourcePlugin::<ObstacleSdf>::default());
+        app.add_plugins(ExtractResourcePlugin::<Attractors>::default());
+        app.add_plugins(ExtractResourcePlugin::<WindData>::default());
 
-        // app.insert_resource(grid);
+        let config = app.world.get_resource_or_insert_with(SimulationConfig::default).clone();
+        let species = app.world.get_resource_or_insert_with(Flocks::default).species.len() as u32;
 
-        let future_compute_recourses_wrapper = Arc::new(Mutex::new(None));
-        app.insert_resource(FutureComputeResources(future_compute_recourses_wrapper.clone()));
-
-        #[cfg(not(target_arch = "wasm32"))]
-        {
-            // env_logger::init();
-            pollster::block_on(prepare_compute(future_compute_recourses_wrapper.clone(), params, initial_boids_data, amount_of_crows_vec, crow_idxs));
-        }
-        #[cfg(target_arch = "wasm32")]
-        {
-            IoTaskPool::get().spawn_local(prepare_compute(future_compute_recourses_wrapper.clone(), params, initial_boids_data, amount_of_crows_vec, crow_idxs)).detach();
-            // wasm_bindgen_futures::spawn_local(async move {prepare_compute(app, &vec![1233, 22343, 3234234, 42234, 52423]).await});
+        //The grid is built by the grid_build shader before every step, so only the boids have to be initialized.
+        //They are uploaded once the render device is available.
+        if !app.world.contains_resource::<InitialBoids>() {
+            let boids = config.spawn.spawn(config.num_boids, config.seed, species)
+                .unwrap_or_else(|e| panic!("Failed to spawn the boids: {e}"));
+            app.insert_resource(InitialBoids(boids));
         }
+
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .init_resource::<BoidLifecycle>()
+            .add_systems(ExtractSchedule, ((extract_simulation_step, extract_boid_readback).chain(), extract_boid_upload, extract_boid_lifecycle, extract_boid_count))
+            .add_systems(Render, (upload_boids, prepare_boid_lifecycle, prepare_environment, prepare_compute_step).chain().in_set(RenderSet::Prepare))
+            .add_systems(Render, start_boid_readback.in_set(RenderSet::Cleanup));
+
+        //Run the compute shaders before any of the cameras are rendered.
+        let mut render_graph = render_app.world.resource_mut::<RenderGraph>();
+        render_graph.add_node(BOIDS_NODE, BoidsNode);
+        render_graph.add_node_edge(BOIDS_NODE, CAMERA_DRIVER);
     }
 
+    //Bevy only calls finish once the renderer has been initialized, which is also when the render device is available.
     fn finish(&self, app: &mut App){
-        if let Some(future_compute_resources) = app.world.remove_resource::<FutureComputeResources>() {
-            let cr: ComputeResources = future_compute_resources.0.lock().unwrap().take().unwrap();
-            app.insert_resource(cr);
-        }
+        let config = app.world.resource::<SimulationConfig>().clone();
+        let flocks = app.world.resource::<Flocks>().clone();
+        let InitialBoids(initial_boids_data) = app.world.remove_resource::<InitialBoids>().unwrap();
+        app.world.resource_mut::<BoidCount>().alive = initial_boids_data.len() as u32;
+
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app.init_resource::<BoidsPipeline>();
+        let cr = prepare_compute(
+            render_app.world.resource::<RenderDevice>(),
+            render_app.world.resource::<BoidsPipeline>(),
+            config,
+            flocks,
+            initial_boids_data
+        );
+        render_app.insert_resource(cr.boid_buffer());
+        render_app.insert_resource(cr);
     }
 }
 
+//The boids that are being read back and the amount of steps that had been simulated by then.
 #[derive(Resource)]
-struct FutureBoid(Arc<Mutex<Option<Vec<Boid>>>>);
+struct FutureBoid(Arc<Mutex<Option<Vec<Boid>>>>, u64);
 
+//Readback that has been requested this frame, the boids are copied to the staging buffer by the node.
 #[derive(Resource)]
-struct FutureComputeResources(Arc<Mutex<Option<ComputeResources>>>);
+struct PendingReadback(Arc<Mutex<Option<Vec<Boid>>>>);
+
+//The steps the compute shaders should run this frame, extracted to the render world.
+#[derive(Resource, Clone, Copy, Default)]
+struct SimulationStep {
+    steps: u32,
+    dt: f32,
+}
+
+//Boids that have been spawned and despawned in the main world, but haven't been handed to the node yet.
+#[derive(Resource, Default)]
+struct BoidLifecycle {
+    spawn: Vec<Boid>,
+    despawn: VecDeque<DespawnBoids>,
+}
+
+//Has to match the Lifecycle struct of the boid_lifecycle shader.
+#[derive(Pod, Zeroable, Clone, Copy)]
+#[repr(C)]
+struct LifecycleUniform {
+    despawn_center: Vec4,
+    despawn_radius: f32,
+    spawn_count: u32,
+    capacity: u32,
+    //1 when the BoundaryMode is Wrap.
+    wrap: u32,
+}
+
+//After despawning only the GPU knows how many boids are alive, so the alive count is read back.
+struct AliveCountReadback {
+    count: Arc<Mutex<Option<u32>>>,
+    //Boids spawned after the count was copied, they are not in the count yet.
+    spawned_since: u32,
+    //The boids have been uploaded again after the count was copied, so the count is of no use anymore.
+    replaced: bool,
+}
 
 #[derive(Resource)]
-struct ComputeResources {
-    device: RenderDevice,
-    queue: Queue,
-    // dt_uniform: Buffer,
-    staging_buffer_boids: Buffer,
-    boid_buffers: Vec<Buffer>,
-    storage_buffer_aoc: Buffer,
-    storage_buffer_cidxs: Buffer,
-    pipeline: ComputePipeline,
-    bind_groups: Vec<BindGroup>,
-    boids_buffer_size: u64,
-    // aoc_buffer_size: u64,
-    // cidxs_buffer_size: u64,
-    current_frame: usize,
+struct BoidsPipeline {
+    boids_layout: BindGroupLayout,
+    grid_centroid_layout: BindGroupLayout,
+    grid_assign_layout: BindGroupLayout,
+    grid_prefix_sum_layout: BindGroupLayout,
+    grid_scatter_layout: BindGroupLayout,
+    lifecycle_layout: BindGroupLayout,
+    boids_pipeline: CachedComputePipelineId,
+    grid_centroid_pipeline: CachedComputePipelineId,
+    grid_assign_pipeline: CachedComputePipelineId,
+    grid_prefix_sum_pipeline: CachedComputePipelineId,
+    grid_scatter_pipeline: CachedComputePipelineId,
+    spawn_pipeline: CachedComputePipelineId,
+    finish_spawn_pipeline: CachedComputePipelineId,
+    compact_pipeline: CachedComputePipelineId,
+    finish_compact_pipeline: CachedComputePipelineId,
+    //Set by the node once the pipeline cache has compiled all of the shaders.
+    ready: bool,
 }
 
+impl FromWorld for BoidsPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let device = world.resource::<RenderDevice>();
 
-async fn prepare_compute(
-    // app: &mut App,
-    future_resources_wrapper: Arc<Mutex<Option<ComputeResources>>>,
-    params: [f32; 9],
-    boids_vec: Vec<Boid>,
-    amount_of_crows_vec: Vec<u32>,
-    crow_idxs_vec: Vec<u32>) {
-    let boids: &[Boid] = &boids_vec;
-    let amount_of_crows: &[u32] = &amount_of_crows_vec;
-    let crow_idxs: &[u32] = &crow_idxs_vec;
-    // Instantiates instance of WebGPU
-    let instance = wgpu::Instance::default();
-    // info!("Info actually works on this one!");
-
-    // `request_adapter` instantiates the general connection to the GPU
-    let adapter = instance
-        .request_adapter(&wgpu::RequestAdapterOptions::default())
-        .await
-        .unwrap();
-
-    let (wgpu_device, queue) = adapter
-        .request_device(
-            &wgpu::DeviceDescriptor {
-                label: None,
-                features: wgpu::Features::empty(),
-                limits: wgpu::Limits::downlevel_defaults(),
-            },
-            None,
-        )
-        .await
-        .unwrap();
+        // The layouts have to match the bindings of the shaders, every grid entry point only uses some of them.
+        let boids_layout = create_compute_layout(device, "boids layout", &[
+            uniform_entry(0),
+            uniform_entry(1),
+            storage_entry(2, true),
+            storage_entry(3, false),
+            storage_entry(4, true),
+            storage_entry(5, true),
+            storage_entry(6, true),
+            uniform_entry(7),
+            storage_entry(8, true),
+            storage_entry(9, true),
+            texture_3d_entry(10),
+            //The last of the 8 storage buffers a shader stage can use.
+            storage_entry(11, true),
+            uniform_entry(12),
+        ]);
+        let grid_centroid_layout = create_compute_layout(device, "grid centroid layout", &[
+            storage_entry(1, true),
+            storage_entry(6, false),
+            uniform_entry(7),
+        ]);
+        let grid_assign_layout = create_compute_layout(device, "grid assign layout", &[
+            uniform_entry(0),
+            storage_entry(1, true),
+            storage_entry(2, false),
+            storage_entry(3, false),
+            storage_entry(6, false),
+            uniform_entry(7),
+        ]);
+        let grid_prefix_sum_layout = create_compute_layout(device, "grid prefix sum layout", &[
+            storage_entry(2, false),
+            storage_entry(4, false),
+        ]);
+        let grid_scatter_layout = create_compute_layout(device, "grid scatter layout", &[
+            storage_entry(3, false),
+            storage_entry(4, false),
+            storage_entry(5, false),
+            uniform_entry(7),
+        ]);
+        //Shared by all entry points of the lifecycle shader.
+        let lifecycle_layout = create_compute_layout(device, "boid lifecycle layout", &[
+            uniform_entry(0),
+            storage_entry(1, true),
+            storage_entry(2, false),
+            storage_entry(3, true),
+            storage_entry(4, false),
+            storage_entry(5, false),
+        ]);
 
-    let device: RenderDevice = wgpu_device.into();
+        let boids_shader = world.resource::<AssetServer>().load("shaders/boids_grid.wgsl");
+        let grid_shader = world.resource::<AssetServer>().load("shaders/grid_build.wgsl");
+        let lifecycle_shader = world.resource::<AssetServer>().load("shaders/boid_lifecycle.wgsl");
 
-    let cs_module = device.create_shader_module(ShaderModuleDescriptor {
-        label: None,
-        source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("../assets/shaders/boids_grid.wgsl"))),
-    });
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let boids_pipeline = queue_pipeline(pipeline_cache, &boids_layout, &boids_shader, "main");
+        let grid_centroid_pipeline = queue_pipeline(pipeline_cache, &grid_centroid_layout, &grid_shader, "centroid");
+        let grid_assign_pipeline = queue_pipeline(pipeline_cache, &grid_assign_layout, &grid_shader, "assign_cells");
+        let grid_prefix_sum_pipeline = queue_pipeline(pipeline_cache, &grid_prefix_sum_layout, &grid_shader, "prefix_sum");
+        let grid_scatter_pipeline = queue_pipeline(pipeline_cache, &grid_scatter_layout, &grid_shader, "scatter");
+        let spawn_pipeline = queue_pipeline(pipeline_cache, &lifecycle_layout, &lifecycle_shader, "spawn");
+        let finish_spawn_pipeline = queue_pipeline(pipeline_cache, &lifecycle_layout, &lifecycle_shader, "finish_spawn");
+        let compact_pipeline = queue_pipeline(pipeline_cache, &lifecycle_layout, &lifecycle_shader, "compact");
+        let finish_compact_pipeline = queue_pipeline(pipeline_cache, &lifecycle_layout, &lifecycle_shader, "finish_compact");
 
-    //Create Uniform Buffer for Params
-    let param_buffer = create_uniform_buffer(&device, &params);
+        BoidsPipeline {
+            boids_layout,
+            grid_centroid_layout,
+            grid_assign_layout,
+            grid_prefix_sum_layout,
+            grid_scatter_layout,
+            lifecycle_layout,
+            boids_pipeline,
+            grid_centroid_pipeline,
+            grid_assign_pipeline,
+            grid_prefix_sum_pipeline,
+            grid_scatter_pipeline,
+            spawn_pipeline,
+            finish_spawn_pipeline,
+            compact_pipeline,
+            finish_compact_pipeline,
+            ready: false,
+        }
+    }
+}
 
-    //Create Uniform Buffer for DeltaTiem
-    let dt_buffer = create_uniform_buffer(&device, &vec![0.004f32]);
-    
+//The compiled pipelines, in the order they are dispatched.
+struct BoidsPipelines<'a> {
+    spawn: &'a ComputePipeline,
+    finish_spawn: &'a ComputePipeline,
+    compact: &'a ComputePipeline,
+    finish_compact: &'a ComputePipeline,
+    grid_centroid: &'a ComputePipeline,
+    grid_assign: &'a ComputePipeline,
+    grid_prefix_sum: &'a ComputePipeline,
+    grid_scatter: &'a ComputePipeline,
+    boids: &'a ComputePipeline,
+}
 
-    
-    //Create buffer src and dst buffers
-    let (_, _, storage_buffer_boids_src) = create_buffers(&device, boids);
-    let (boids_size, staging_buffer_boids, storage_buffer_boids_dst) = create_buffers(&device, boids);
+impl BoidsPipeline {
+    fn ids(&self) -> [CachedComputePipelineId; 9] {
+        [
+            self.spawn_pipeline,
+            self.finish_spawn_pipeline,
+            self.compact_pipeline,
+            self.finish_compact_pipeline,
+            self.grid_centroid_pipeline,
+            self.grid_assign_pipeline,
+            self.grid_prefix_sum_pipeline,
+            self.grid_scatter_pipeline,
+            self.boids_pipeline,
+        ]
+    }
 
-    //Create buffers for grid values
-    let (grid_aoc_size, _, storage_buffer_aoc) = create_buffers(&device, amount_of_crows);
-    let (crowd_idxs_size, _, crow_idx_buffer) = create_buffers(&device, crow_idxs);
+    //The pipeline cache compiles the shaders in the background, this returns None until the node has seen all of them are done.
+    fn pipelines<'a>(&self, pipeline_cache: &'a PipelineCache) -> Option<BoidsPipelines<'a>> {
+        if !self.ready {
+            return None;
+        }
+        Some(BoidsPipelines {
+            spawn: pipeline_cache.get_compute_pipeline(self.spawn_pipeline)?,
+            finish_spawn: pipeline_cache.get_compute_pipeline(self.finish_spawn_pipeline)?,
+            compact: pipeline_cache.get_compute_pipeline(self.compact_pipeline)?,
+            finish_compact: pipeline_cache.get_compute_pipeline(self.finish_compact_pipeline)?,
+            grid_centroid: pipeline_cache.get_compute_pipeline(self.grid_centroid_pipeline)?,
+            grid_assign: pipeline_cache.get_compute_pipeline(self.grid_assign_pipeline)?,
+            grid_prefix_sum: pipeline_cache.get_compute_pipeline(self.grid_prefix_sum_pipeline)?,
+            grid_scatter: pipeline_cache.get_compute_pipeline(self.grid_scatter_pipeline)?,
+            boids: pipeline_cache.get_compute_pipeline(self.boids_pipeline)?,
+        })
+    }
+}
 
-    let boids_storage_buffers = vec![storage_buffer_boids_src, storage_buffer_boids_dst];
+fn create_compute_layout(device: &RenderDevice, label: &'static str, entries: &[BindGroupLayoutEntry]) -> BindGroupLayout {
+    device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some(label),
+        entries,
+    })
+}
 
-    let compute_pipeline = device.create_compute_pipeline(&RawComputePipelineDescriptor {
-        label: None,
-        layout: None,
-        module: &cs_module,
-        entry_point: "main",
+fn uniform_entry(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+fn storage_entry(binding: u32, read_only: bool) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+fn texture_3d_entry(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Texture {
+            //32 bit floats can't be filtered, the shader interpolates them itself.
+            sample_type: TextureSampleType::Float { filterable: false },
+            view_dimension: TextureViewDimension::D3,
+            multisampled: false,
+        },
+        count: None,
+    }
+}
+
+fn queue_pipeline(pipeline_cache: &PipelineCache, layout: &BindGroupLayout, shader: &Handle<Shader>, entry_point: &'static str) -> CachedComputePipelineId {
+    pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+        label: Some(Cow::Borrowed(entry_point)),
+        layout: vec![layout.clone()],
+        push_constant_ranges: Vec::new(),
+        shader: shader.clone(),
+        shader_defs: vec![],
+        entry_point: Cow::Borrowed(entry_point),
+    })
+}
+
+//All buffers of the simulation, the ones with something for every boid are made for the capacity.
+struct ComputeBuffers {
+    dt_uniform: Buffer,
+    param_buffer: Buffer,
+    storage_buffer_aoc: Buffer,
+    storage_buffer_cell_counts: Buffer,
+    storage_buffer_grid_origin: Buffer,
+    //Read as a uniform by the simulation and instancing shaders, changed as storage by the lifecycle shader.
+    alive_count_buffer: Buffer,
+    compacted_count_buffer: Buffer,
+    lifecycle_uniform: Buffer,
+    staging_buffer_alive_count: Buffer,
+    capacity: u32,
+    boid_buffers: Vec<Buffer>,
+    //The boids that are spawned this frame, copied behind the alive boids by the lifecycle shader.
+    spawn_buffer: Buffer,
+    crow_idx_buffer: Buffer,
+    storage_buffer_boid_cells: Buffer,
+    //Holds the boids followed by the alive count, so a readback knows how many of them are alive.
+    staging_buffer_boids: Buffer,
+    //The amount of predators padded to 16 bytes, followed by the predators.
+    predator_buffer: Buffer,
+    //The amount of obstacles and the resolution, margin and strength of the sdf, followed by the obstacles.
+    obstacle_buffer: Buffer,
+    sdf_texture: Texture,
+    sdf_view: TextureView,
+    //The amount of attractors padded to 16 bytes, followed by the attractors.
+    attractor_buffer: Buffer,
+    wind_uniform: Buffer,
+}
+
+impl ComputeBuffers {
+    fn new(device: &RenderDevice, config: &SimulationConfig, flocks: &Flocks, boids: &[Boid]) -> Self {
+        let alive = boids.len() as u32;
+        let capacity = alive.max(MIN_CAPACITY);
+        let mut boids = boids.to_vec();
+        boids.resize(capacity as usize, Boid::zeroed());
+
+        //Filled in by the grid_build shader
+        let amount_of_crows: &[u32] = &vec![0u32; config.total_cells()];
+        let cell_counts: &[u32] = &vec![0u32; config.total_cells()];
+        //Stays at the origin unless the world is open
+        let grid_origin: &[[f32; 4]] = &[[0.0f32; 4]];
+        //Replaced once the sdf has been baked
+        let (sdf_texture, sdf_view) = create_sdf_texture(device, 1);
+
+        ComputeBuffers {
+            //Written before every dispatch
+            dt_uniform: create_uniform_buffer(device, &[0.0f32]),
+            param_buffer: create_uniform_buffer(device, &[flocks.to_params(config)]),
+            storage_buffer_aoc: create_storage_buffer(device, amount_of_crows),
+            storage_buffer_cell_counts: create_storage_buffer(device, cell_counts),
+            storage_buffer_grid_origin: create_storage_buffer(device, grid_origin),
+            alive_count_buffer: device.create_buffer_with_data(&BufferInitDescriptor {
+                label: Some("Alive Count Buffer"),
+                contents: bytemuck::bytes_of(&alive),
+                usage: BufferUsages::UNIFORM
+                    | BufferUsages::STORAGE
+                    | BufferUsages::COPY_DST
+                    | BufferUsages::COPY_SRC,
+            }),
+            compacted_count_buffer: create_storage_buffer(device, &[0u32]),
+            lifecycle_uniform: create_uniform_buffer(device, &[LifecycleUniform::zeroed()]),
+            staging_buffer_alive_count: create_staging_buffer(device, 4),
+            capacity,
+            //Both start with the boids, so it doesn't matter which one the first step reads from.
+            boid_buffers: vec![create_storage_buffer(device, &boids), create_storage_buffer(device, &boids)],
+            spawn_buffer: create_empty_storage_buffer(device, boids_size(capacity)),
+            crow_idx_buffer: create_empty_storage_buffer(device, capacity as u64 * 4),
+            storage_buffer_boid_cells: create_empty_storage_buffer(device, capacity as u64 * 8),
+            staging_buffer_boids: create_staging_buffer(device, boids_size(capacity) + 4),
+            //Room for a single predator, obstacle and attractor, so they can always be bound.
+            //Both start empty, as the zeroed header says there are none.
+            predator_buffer: create_empty_storage_buffer(device, list_size::<PredatorData>(1)),
+            obstacle_buffer: create_empty_storage_buffer(device, list_size::<ObstacleData>(1)),
+            sdf_texture,
+            sdf_view,
+            attractor_buffer: create_empty_storage_buffer(device, list_size::<AttractorData>(1)),
+            wind_uniform: create_uniform_buffer(device, &[WindData::default()]),
+        }
+    }
+
+    //Replaces the buffers with something for every boid with bigger ones, the boids are copied over.
+    fn grow(&mut self, device: &RenderDevice, queue: &RenderQueue, capacity: u32) {
+        let boid_buffers: Vec<Buffer> = (0..2).map(|_| create_empty_storage_buffer(device, boids_size(capacity))).collect();
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("grow boid buffers"),
+        });
+        for (old, new) in self.boid_buffers.iter().zip(&boid_buffers) {
+            encoder.copy_buffer_to_buffer(old, 0, new, 0, boids_size(self.capacity));
+        }
+        queue.submit([encoder.finish()]);
+
+        self.capacity = capacity;
+        self.boid_buffers = boid_buffers;
+        self.spawn_buffer = create_empty_storage_buffer(device, boids_size(capacity));
+        self.crow_idx_buffer = create_empty_storage_buffer(device, capacity as u64 * 4);
+        self.storage_buffer_boid_cells = create_empty_storage_buffer(device, capacity as u64 * 8);
+        self.staging_buffer_boids = create_staging_buffer(device, boids_size(capacity) + 4);
+    }
+}
+
+fn boids_size(capacity: u32) -> u64 {
+    capacity as u64 * std::mem::size_of::<Boid>() as u64
+}
+
+//Size of a buffer with a 16 byte header followed by the items.
+fn list_size<T>(items: usize) -> u64 {
+    16 + (items * std::mem::size_of::<T>()) as u64
+}
+
+fn create_sdf_texture(device: &RenderDevice, resolution: u32) -> (Texture, TextureView) {
+    let texture = device.create_texture(&TextureDescriptor {
+        label: Some("Obstacle SDF"),
+        size: Extent3d {
+            width: resolution,
+            height: resolution,
+            depth_or_array_layers: resolution,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: TextureDimension::D3,
+        format: TextureFormat::R32Float,
+        usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+        view_formats: &[],
     });
+    let view = texture.create_view(&TextureViewDescriptor::default());
+    (texture, view)
+}
+
+struct ComputeBindGroups {
+    boids: Vec<BindGroup>,
+    //Only dispatched when the world is open, for both src buffers.
+    grid_centroid: Vec<BindGroup>,
+    //Assigning the cells reads the boids, so just like the boids it has a bind group for both src buffers.
+    grid_assign: Vec<BindGroup>,
+    grid_prefix_sum: BindGroup,
+    grid_scatter: BindGroup,
+    //Reads from one boid buffer and writes to the other, the same way around as the boids bind groups.
+    lifecycle: Vec<BindGroup>,
+}
 
-    // Instantiates the bind group, once again specifying the binding of buffers.
-    // This will throw an error in case the wgsl does not use all the binds I think.
-    let bind_group_layout: BindGroupLayout = compute_pipeline.get_bind_group_layout(0).into();
+//Has to be called again whenever the buffers grow.
+fn create_bind_groups(device: &RenderDevice, pipeline: &BoidsPipeline, buffers: &ComputeBuffers) -> ComputeBindGroups {
+    let boids_storage_buffers = &buffers.boid_buffers;
 
     // We create 2 bind groups in order to swap the src and dst of the boids, this allows us to stay consistent
     // We only need one staging buffer for both, as they are the same size.
     let mut bindgroups: Vec<BindGroup> = Vec::with_capacity(2);
     for i in 0..2 {
         bindgroups.push(device.create_bind_group(
-            None, 
-            &bind_group_layout, 
+            None,
+            &pipeline.boids_layout,
             &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: param_buffer.as_entire_binding()
+                    resource: buffers.param_buffer.as_entire_binding()
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: dt_buffer.as_entire_binding(),
+                    resource: buffers.dt_uniform.as_entire_binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
@@ -233,36 +544,257 @@ async fn prepare_compute(
                 },
                 BindGroupEntry {
                     binding: 4,
-                    resource: storage_buffer_aoc.as_entire_binding()
+                    resource: buffers.storage_buffer_aoc.as_entire_binding()
                 },
                 BindGroupEntry {
                     binding: 5,
-                    resource: crow_idx_buffer.as_entire_binding()
+                    resource: buffers.crow_idx_buffer.as_entire_binding()
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: buffers.storage_buffer_grid_origin.as_entire_binding()
+                },
+                BindGroupEntry {
+                    binding: 7,
+                    resource: buffers.alive_count_buffer.as_entire_binding()
+                },
+                BindGroupEntry {
+                    binding: 8,
+                    resource: buffers.predator_buffer.as_entire_binding()
+                },
+                BindGroupEntry {
+                    binding: 9,
+                    resource: buffers.obstacle_buffer.as_entire_binding()
+                },
+                BindGroupEntry {
+                    binding: 10,
+                    resource: BindingResource::TextureView(&buffers.sdf_view)
+                },
+                BindGroupEntry {
+                    binding: 11,
+                    resource: buffers.attractor_buffer.as_entire_binding()
+                },
+                BindGroupEntry {
+                    binding: 12,
+                    resource: buffers.wind_uniform.as_entire_binding()
                 }
             ]
         ))
     }
 
-    let compute_resources: ComputeResources = ComputeResources {
-        device: device,
-        queue: queue,
-        // dt_uniform: dt_buffer,
-        staging_buffer_boids: staging_buffer_boids,
-        boid_buffers: boids_storage_buffers,
-        storage_buffer_aoc: storage_buffer_aoc,
-        storage_buffer_cidxs: crow_idx_buffer,
-        pipeline: compute_pipeline,
-        bind_groups: bindgroups,
-        boids_buffer_size: boids_size,
-        // aoc_buffer_size: grid_aoc_size,
-        // cidxs_buffer_size: crowd_idxs_size,
-        current_frame: 0
-    };
+    let mut grid_centroid_bind_groups: Vec<BindGroup> = Vec::with_capacity(2);
+    for i in 0..2 {
+        grid_centroid_bind_groups.push(device.create_bind_group(
+            None,
+            &pipeline.grid_centroid_layout,
+            &[
+                BindGroupEntry {
+                    binding: 1,
+                    resource: boids_storage_buffers[i].as_entire_binding()
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: buffers.storage_buffer_grid_origin.as_entire_binding()
+                },
+                BindGroupEntry {
+                    binding: 7,
+                    resource: buffers.alive_count_buffer.as_entire_binding()
+                }
+            ]
+        ))
+    }
+
+    let mut grid_assign_bind_groups: Vec<BindGroup> = Vec::with_capacity(2);
+    for i in 0..2 {
+        grid_assign_bind_groups.push(device.create_bind_group(
+            None,
+            &pipeline.grid_assign_layout,
+            &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: buffers.param_buffer.as_entire_binding()
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: boids_storage_buffers[i].as_entire_binding()
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: buffers.storage_buffer_cell_counts.as_entire_binding()
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: buffers.storage_buffer_boid_cells.as_entire_binding()
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: buffers.storage_buffer_grid_origin.as_entire_binding()
+                },
+                BindGroupEntry {
+                    binding: 7,
+                    resource: buffers.alive_count_buffer.as_entire_binding()
+                }
+            ]
+        ))
+    }
+
+    let grid_prefix_sum_bind_group = device.create_bind_group(
+        None,
+        &pipeline.grid_prefix_sum_layout,
+        &[
+            BindGroupEntry {
+                binding: 2,
+                resource: buffers.storage_buffer_cell_counts.as_entire_binding()
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: buffers.storage_buffer_aoc.as_entire_binding()
+            }
+        ]
+    );
+
+    let grid_scatter_bind_group = device.create_bind_group(
+        None,
+        &pipeline.grid_scatter_layout,
+        &[
+            BindGroupEntry {
+                binding: 3,
+                resource: buffers.storage_buffer_boid_cells.as_entire_binding()
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: buffers.storage_buffer_aoc.as_entire_binding()
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: buffers.crow_idx_buffer.as_entire_binding()
+            },
+            BindGroupEntry {
+                binding: 7,
+                resource: buffers.alive_count_buffer.as_entire_binding()
+            }
+        ]
+    );
+
+    let mut lifecycle_bind_groups: Vec<BindGroup> = Vec::with_capacity(2);
+    for i in 0..2 {
+        lifecycle_bind_groups.push(device.create_bind_group(
+            None,
+            &pipeline.lifecycle_layout,
+            &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: buffers.lifecycle_uniform.as_entire_binding()
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: boids_storage_buffers[i].as_entire_binding()
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: boids_storage_buffers[(i + 1) % 2].as_entire_binding()
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: buffers.spawn_buffer.as_entire_binding()
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: buffers.alive_count_buffer.as_entire_binding()
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: buffers.compacted_count_buffer.as_entire_binding()
+                }
+            ]
+        ))
+    }
+
+    ComputeBindGroups {
+        boids: bindgroups,
+        grid_centroid: grid_centroid_bind_groups,
+        grid_assign: grid_assign_bind_groups,
+        grid_prefix_sum: grid_prefix_sum_bind_group,
+        grid_scatter: grid_scatter_bind_group,
+        lifecycle: lifecycle_bind_groups,
+    }
+}
+
+#[derive(Resource)]
+struct ComputeResources {
+    buffers: ComputeBuffers,
+    bind_groups: ComputeBindGroups,
+    current_frame: usize,
+    //The steps the node runs this frame and the frame it starts from, decided in prepare_compute_step.
+    steps: u32,
+    first_frame: usize,
+    //At least the amount of boids that are alive, the shaders are dispatched and the crows are drawn for this many boids.
+    //It is exact, unless the alive count is still being read back after despawning.
+    max_alive: u32,
+    //The boids the node spawns and removes this frame, decided in prepare_boid_lifecycle.
+    spawn_count: u32,
+    despawn: Option<DespawnBoids>,
+    //The node copies the alive count to its staging buffer this frame.
+    copy_alive_count: bool,
+    alive_count_readback: Option<AliveCountReadback>,
+    //Boids have been despawned since the alive count was last copied.
+    alive_count_outdated: bool,
+    config: SimulationConfig,
+    //The species whose params are currently in the param buffer, used to only upload them when they changed.
+    flocks: Flocks,
+}
+
+
+fn prepare_compute(
+    device: &RenderDevice,
+    pipeline: &BoidsPipeline,
+    config: SimulationConfig,
+    flocks: Flocks,
+    boids_vec: Vec<Boid>) -> ComputeResources {
+    let buffers = ComputeBuffers::new(device, &config, &flocks, &boids_vec);
+    let bind_groups = create_bind_groups(device, pipeline, &buffers);
+
+    ComputeResources {
+        buffers,
+        bind_groups,
+        current_frame: 0,
+        steps: 0,
+        first_frame: 0,
+        max_alive: boids_vec.len() as u32,
+        spawn_count: 0,
+        despawn: None,
+        copy_alive_count: false,
+        alive_count_readback: None,
+        alive_count_outdated: false,
+        config: config,
+        flocks,
+    }
+}
 
-    let mut future_compute_resources_inner = future_resources_wrapper.lock().unwrap();
+impl ComputeResources {
+    //The buffer the last step has written the boids to.
+    fn latest_boid_buffer(&self) -> &Buffer {
+        &self.buffers.boid_buffers[(self.current_frame + 1) % 2]
+    }
 
-    *future_compute_resources_inner = Some(compute_resources);
+    fn boid_buffer(&self) -> BoidBuffer {
+        BoidBuffer {
+            buffer: self.latest_boid_buffer().clone(),
+            alive_count: self.buffers.alive_count_buffer.clone(),
+            length: self.max_alive,
+        }
+    }
 
+    //Makes sure the buffers have room for the amount of boids, at least doubling them so they don't grow every frame.
+    fn reserve(&mut self, device: &RenderDevice, queue: &RenderQueue, pipeline: &BoidsPipeline, boids: u32) {
+        if boids <= self.buffers.capacity {
+            return
+        }
+        let capacity = boids.max(self.buffers.capacity * 2);
+        info!("Growing the boid buffers from {} to {} boids", self.buffers.capacity, capacity);
+        self.buffers.grow(device, queue, capacity);
+        self.bind_groups = create_bind_groups(device, pipeline, &self.buffers);
+    }
 }
 
 fn create_uniform_buffer<T: Pod>(device: &RenderDevice, data: &[T]) -> Buffer{
@@ -273,217 +805,520 @@ fn create_uniform_buffer<T: Pod>(device: &RenderDevice, data: &[T]) -> Buffer{
     })
 }
 
-fn create_buffers<T: Pod>(device: &RenderDevice, data: &[T]) -> (u64, Buffer, Buffer) {
-    let size = std::mem::size_of_val(data) as wgpu::BufferAddress;
-
-    let staging_buffer = device.create_buffer(&BufferDescriptor { 
-        label: None,
-        size: size,
-        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST, 
-        mapped_at_creation: false,
-    });
-
-    let storage_buffer = device.create_buffer_with_data(&BufferInitDescriptor {
+fn create_storage_buffer<T: Pod>(device: &RenderDevice, data: &[T]) -> Buffer {
+    device.create_buffer_with_data(&BufferInitDescriptor {
         label: Some("Storage Buffer Boids"),
         contents: bytemuck::cast_slice(data),
         usage: BufferUsages::STORAGE
             | BufferUsages::COPY_DST
             | BufferUsages::COPY_SRC,
-    });
+    })
+}
 
-    (size, staging_buffer, storage_buffer)
+fn create_empty_storage_buffer(device: &RenderDevice, size: u64) -> Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: Some("Storage Buffer Boids"),
+        size,
+        usage: BufferUsages::STORAGE
+            | BufferUsages::COPY_DST
+            | BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    })
 }
 
-fn update_compute_resources(
-    world: &mut World
-) {
-    if let Some(future_compute) = world.remove_resource::<FutureComputeResources>() {
-        let maybe_cr = future_compute.0.lock().unwrap().take();
-        match maybe_cr {
-            Some(cr) => {
-                world.insert_resource(cr);
-            },
-            None => {
-                world.insert_resource(future_compute);
-            }
-        }
-    }
+fn create_staging_buffer(device: &RenderDevice, size: u64) -> Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: None,
+        size,
+        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
 }
 
+//Moves the boids into the BoidReadback resource once the readback has finished.
 fn update_boids(
-    mut world: &mut World
+    world: &mut World
 ) {
     if let Some(future_boids_wrapper_res) = world.remove_resource::<FutureBoid>() {
         let maybe_boids: Option<Vec<Boid>> = future_boids_wrapper_res.0.lock().unwrap().take();
         match maybe_boids {
             Some(boids) => {
-                // info!("Got Results!");
-                let mut system_state: SystemState<Query<(Entity, &mut InstanceMaterialData)>> = SystemState::new(&mut world);
-                let mut boid_instances = system_state.get_mut(&mut world);
-    
-                for (_, mut instance_data) in &mut boid_instances {
-                    for (index, instance) in instance_data.0.iter_mut().enumerate() {
-                        if index < NUM_BOIDS as usize {
-                            let world_pos = Vec3::new(
-                                20. * (boids[index].pos.x),
-                                20. * (boids[index].pos.y) + 20 as f32,
-                                20. * (boids[index].pos.z)
-                            );
-    
-                            instance.vel = boids[index].vel;
-                            instance.position = world_pos;
-                        }
-                    }
-                }
+                let mut readback = world.resource_mut::<BoidReadback>();
+                readback.boids = Some(boids);
+                readback.frame = future_boids_wrapper_res.1;
             },
             None => {
                 world.insert_resource(future_boids_wrapper_res);
-                // info!("No Boid Vec after compute call!");
             }
         }
+    }
+}
 
-    } else {
-        //Do nothing
+//Decides how many steps the render world has to simulate this frame.
+fn update_simulation_step(
+    time: Res<Time>,
+    config: Res<SimulationConfig>,
+    frame: Res<SimulationFrame>,
+    mut simulation_time: ResMut<SimulationTime>,
+    mut simulation_step: ResMut<SimulationStep>,
+) {
+    simulation_time.accumulated += time.delta_seconds();
+    let (steps, dt) = simulation_time.consume(&config, &frame);
+    *simulation_step = SimulationStep { steps, dt };
+}
+
+//The steps are only run once the pipeline cache has compiled the shaders, only those are counted in the SimulationFrame.
+fn extract_simulation_step(
+    mut commands: Commands,
+    mut main_world: ResMut<MainWorld>,
+    pipeline: Res<BoidsPipeline>,
+    pipeline_cache: Res<PipelineCache>,
+) {
+    let mut simulation_step = *main_world.resource::<SimulationStep>();
+    if pipeline.pipelines(&pipeline_cache).is_none() {
+        simulation_step.steps = 0;
+    }
+    main_world.resource_mut::<SimulationFrame>().steps += simulation_step.steps as u64;
+    commands.insert_resource(simulation_step);
+}
+
+//Starts a readback when it has been requested and there is none running yet.
+fn extract_boid_readback(
+    mut commands: Commands,
+    mut main_world: ResMut<MainWorld>,
+) {
+    if !main_world.resource::<BoidReadback>().requested || main_world.contains_resource::<FutureBoid>() {
         return
     }
+    let future_boids_wrapper: Arc<Mutex<Option<Vec<Boid>>>> = Arc::new(Mutex::new(None));
+    //The node copies the boids after the steps of this frame, which extract_simulation_step has already counted.
+    let frame = main_world.resource::<SimulationFrame>().steps;
+    main_world.insert_resource(FutureBoid(future_boids_wrapper.clone(), frame));
+    main_world.resource_mut::<BoidReadback>().requested = false;
+    commands.insert_resource(PendingReadback(future_boids_wrapper));
 }
 
+//Collects the spawned and despawned boids.
+fn extract_boid_lifecycle(
+    mut spawn_events: Extract<EventReader<SpawnBoids>>,
+    mut despawn_events: Extract<EventReader<DespawnBoids>>,
+    mut lifecycle: ResMut<BoidLifecycle>,
+) {
+    for SpawnBoids(boids) in spawn_events.read() {
+        lifecycle.spawn.extend_from_slice(boids);
+    }
+    lifecycle.despawn.extend(despawn_events.read().copied());
+}
 
-fn run_compute( 
-    world: &mut World,
+//Tells the main world how many boids there are, a system of its own as Extract already reads the MainWorld.
+fn extract_boid_count(
+    mut main_world: ResMut<MainWorld>,
+    cr: Res<ComputeResources>,
 ) {
-    // mut boid_instances = world.query<Query<(Entity, &mut InstanceMaterialData)>>();
-    //println!("Running compute!");
-    if let Some(mut cr) = world.remove_resource::<ComputeResources>() {
-        cr.current_frame = (cr.current_frame + 1) % 2;
-        let future_boids_wrapper: Arc<Mutex<Option<Vec<Boid>>>> = Arc::new(Mutex::new(None));
-        let future_compute_recourses_wrapper = Arc::new(Mutex::new(None));
-        world.insert_resource(FutureBoid(future_boids_wrapper.clone()));
-        world.insert_resource(FutureComputeResources(future_compute_recourses_wrapper.clone()));
+    main_world.resource_mut::<BoidCount>().alive = cr.max_alive;
+}
 
-        ComputeTaskPool::get().spawn_local(run_compute_inner(future_compute_recourses_wrapper.clone(), cr, future_boids_wrapper.clone())).detach();
-        //If you are running natively and want to limit the framerate to the boids algorithm, use pollster instead:
-        //pollster::block_on(run_compute_inner(future_compute_recourses_wrapper.clone(), cr, future_boids_wrapper.clone()));
+//Uploads the values of this frame and decides which buffers the node reads from and writes to.
+fn prepare_compute_step(
+    mut cr: ResMut<ComputeResources>,
+    simulation_step: Res<SimulationStep>,
+    flocks: Res<Flocks>,
+    queue: Res<RenderQueue>,
+    mut boid_buffer: ResMut<BoidBuffer>,
+) {
+    cr.steps = simulation_step.steps;
+    cr.first_frame = cr.current_frame;
+    if simulation_step.steps > 0 {
+        queue.write_buffer(&cr.buffers.dt_uniform, 0, bytemuck::bytes_of(&simulation_step.dt));
 
-    } else {
-        //info!("Resource does not exist!");
+        //Upload the params again if they have been changed, ie. by the params ui.
+        if *flocks != cr.flocks {
+            queue.write_buffer(&cr.buffers.param_buffer, 0, bytemuck::bytes_of(&flocks.to_params(&cr.config)));
+            cr.flocks = flocks.clone();
+        }
+
+        cr.current_frame = (cr.current_frame + simulation_step.steps as usize) % 2;
+    }
+
+    //Let the instancing plugin draw from the buffer the last step writes to.
+    *boid_buffer = cr.boid_buffer();
+}
+
+//Uploads the predators, obstacles, sdf, attractors and wind when they have changed, replacing the buffers and texture when they don't fit.
+#[allow(clippy::too_many_arguments)]
+fn prepare_environment(
+    mut cr: ResMut<ComputeResources>,
+    predators: Res<Predators>,
+    obstacles: Res<Obstacles>,
+    sdf: Res<ObstacleSdf>,
+    attractors: Res<Attractors>,
+    wind: Res<WindData>,
+    pipeline: Res<BoidsPipeline>,
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+) {
+    let mut replaced = false;
+    if predators.is_changed() {
+        let header = [predators.0.len() as u32, 0, 0, 0];
+        replaced |= upload_list(&device, &queue, &mut cr.buffers.predator_buffer, header, &predators.0);
+    }
+    if obstacles.is_changed() || sdf.is_changed() {
+        let header = [obstacles.0.len() as u32, sdf.resolution, sdf.margin.to_bits(), sdf.strength.to_bits()];
+        replaced |= upload_list(&device, &queue, &mut cr.buffers.obstacle_buffer, header, &obstacles.0);
+    }
+    if attractors.is_changed() {
+        let header = [attractors.0.len() as u32, 0, 0, 0];
+        replaced |= upload_list(&device, &queue, &mut cr.buffers.attractor_buffer, header, &attractors.0);
+    }
+    if wind.is_changed() {
+        queue.write_buffer(&cr.buffers.wind_uniform, 0, bytemuck::bytes_of(&*wind));
+    }
+    if sdf.is_changed() && sdf.resolution > 0 {
+        if cr.buffers.sdf_texture.width() != sdf.resolution {
+            (cr.buffers.sdf_texture, cr.buffers.sdf_view) = create_sdf_texture(&device, sdf.resolution);
+            replaced = true;
+        }
+        queue.write_texture(
+            cr.buffers.sdf_texture.as_image_copy(),
+            bytemuck::cast_slice(&sdf.distances),
+            ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(sdf.resolution * 4),
+                rows_per_image: Some(sdf.resolution),
+            },
+            cr.buffers.sdf_texture.size(),
+        );
+    }
+    if replaced {
+        cr.bind_groups = create_bind_groups(&device, &pipeline, &cr.buffers);
+    }
+}
+
+//Writes the header followed by the items, returns whether the buffer has been replaced because they didn't fit.
+fn upload_list<T: Pod>(device: &RenderDevice, queue: &RenderQueue, buffer: &mut Buffer, header: [u32; 4], items: &[T]) -> bool {
+    let replaced = list_size::<T>(items.len()) > buffer.size();
+    if replaced {
+        *buffer = create_empty_storage_buffer(device, list_size::<T>(items.len().next_power_of_two()));
+    }
+    queue.write_buffer(buffer, 0, bytemuck::bytes_of(&header));
+    queue.write_buffer(buffer, 16, bytemuck::cast_slice(items));
+    replaced
+}
+
+//Decides which boids the node spawns and removes this frame, growing the buffers when the spawned boids don't fit.
+//All boids spawned this frame are spawned at once, but only a single despawn is run every frame.
+#[allow(clippy::too_many_arguments)]
+fn prepare_boid_lifecycle(
+    mut cr: ResMut<ComputeResources>,
+    mut lifecycle: ResMut<BoidLifecycle>,
+    config: Res<SimulationConfig>,
+    pipeline: Res<BoidsPipeline>,
+    pipeline_cache: Res<PipelineCache>,
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+) {
+    cr.spawn_count = 0;
+    cr.despawn = None;
+    cr.copy_alive_count = false;
+
+    let read_count = cr.alive_count_readback.as_ref().and_then(|readback| {
+        let count = readback.count.lock().unwrap().take()?;
+        Some((!readback.replaced).then_some(count + readback.spawned_since))
+    });
+    if let Some(count) = read_count {
+        if let Some(count) = count {
+            cr.max_alive = count.min(cr.max_alive);
+        }
+        cr.alive_count_readback = None;
+    }
+
+    //Keep the boids until the shaders can run.
+    if pipeline.pipelines(&pipeline_cache).is_none() {
         return
     }
+
+    if !lifecycle.spawn.is_empty() {
+        let spawned = std::mem::take(&mut lifecycle.spawn);
+        let spawn_count = spawned.len() as u32;
+        let max_alive = cr.max_alive + spawn_count;
+        cr.reserve(&device, &queue, &pipeline, max_alive);
+        queue.write_buffer(&cr.buffers.spawn_buffer, 0, bytemuck::cast_slice(&spawned));
+        cr.spawn_count = spawn_count;
+        cr.max_alive = max_alive;
+        if let Some(readback) = &mut cr.alive_count_readback {
+            readback.spawned_since += spawn_count;
+        }
+    }
+
+    if let Some(despawn) = lifecycle.despawn.pop_front() {
+        cr.despawn = Some(despawn);
+        cr.alive_count_outdated = true;
+        //The boids that stay alive are moved to the other buffer, which is where the next step reads from.
+        cr.current_frame = (cr.current_frame + 1) % 2;
+    }
+
+    //Only a single alive count is read back at a time, the next one is started once it has finished.
+    if cr.alive_count_outdated && cr.alive_count_readback.is_none() {
+        cr.alive_count_outdated = false;
+        cr.copy_alive_count = true;
+        cr.alive_count_readback = Some(AliveCountReadback {
+            count: Arc::new(Mutex::new(None)),
+            spawned_since: 0,
+            replaced: false,
+        });
+    }
+
+    if cr.spawn_count > 0 || cr.despawn.is_some() {
+        let despawn = cr.despawn.unwrap_or(DespawnBoids { center: Vec3::ZERO, radius: -1.0 });
+        let lifecycle_uniform = LifecycleUniform {
+            despawn_center: despawn.center.extend(0.0),
+            despawn_radius: despawn.radius,
+            spawn_count: cr.spawn_count,
+            capacity: cr.buffers.capacity,
+            wrap: (config.boundary_mode == BoundaryMode::Wrap) as u32,
+        };
+        queue.write_buffer(&cr.buffers.lifecycle_uniform, 0, bytemuck::bytes_of(&lifecycle_uniform));
+    }
 }
 
-async fn run_compute_inner(
-    future_resources_wrapper: Arc<Mutex<Option<ComputeResources>>>,
-    cr: ComputeResources,
-    future_boids_wrapper: Arc<Mutex<Option<Vec<Boid>>>>
+fn extract_boid_upload(
+    mut commands: Commands,
+    mut main_world: ResMut<MainWorld>,
 ) {
-    // info!("Started running compute inner!");
-    let boids = run_compute_shader(&cr).await;
+    if let Some(upload) = main_world.remove_resource::<UploadBoids>() {
+        commands.insert_resource(upload);
+    }
+}
 
-    //Update the Rendered items with the positions and rotations and create a new grid
-    let mut grid = Grid::new(GRID_SIZE as usize, CELL_SIZE);
-    for (i, boid) in boids.iter().enumerate() {
-        let transform = Transform::from_xyz(boid.pos.x, boid.pos.y, boid.pos.z);
-        grid.add_with_transform(&transform, &i);
+//Replaces the boids in both buffers, so it doesn't matter which one the next step reads from.
+fn upload_boids(
+    mut commands: Commands,
+    upload: Option<Res<UploadBoids>>,
+    mut cr: ResMut<ComputeResources>,
+    pipeline: Res<BoidsPipeline>,
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+) {
+    let Some(upload) = upload else {
+        return
+    };
+    let alive = upload.0.len() as u32;
+    cr.reserve(&device, &queue, &pipeline, alive);
+    for buffer in &cr.buffers.boid_buffers {
+        queue.write_buffer(buffer, 0, bytemuck::cast_slice(&upload.0));
+    }
+    queue.write_buffer(&cr.buffers.alive_count_buffer, 0, bytemuck::bytes_of(&alive));
+    cr.max_alive = alive;
+    if let Some(readback) = &mut cr.alive_count_readback {
+        readback.replaced = true;
+    }
+    commands.remove_resource::<UploadBoids>();
+}
 
+//Maps the staging buffers once the node has copied the boids or the alive count into them.
+fn start_boid_readback(
+    mut commands: Commands,
+    pending_readback: Option<Res<PendingReadback>>,
+    cr: Res<ComputeResources>,
+    device: Res<RenderDevice>,
+) {
+    if let (true, Some(readback)) = (cr.copy_alive_count, &cr.alive_count_readback) {
+        AsyncComputeTaskPool::get().spawn(read_alive_count(device.clone(), cr.buffers.staging_buffer_alive_count.clone(), readback.count.clone())).detach();
     }
 
-    let mut amount_of_crows_vec: Vec<u32> = Vec::with_capacity(grid.size.pow(3));
-    let mut crow_idxs: Vec<u32> = Vec::with_capacity(NUM_BOIDS as usize);
+    let Some(pending_readback) = pending_readback else {
+        return
+    };
+    AsyncComputeTaskPool::get().spawn(read_boids(device.clone(), cr.buffers.staging_buffer_boids.clone(), pending_readback.0.clone())).detach();
+    commands.remove_resource::<PendingReadback>();
+}
+
+struct BoidsNode;
 
-    for x in 0..grid.size {
-        for y in 0..grid.size {
-            for z in 0..grid.size {
-                    //let grid_idx = x * grid.size.pow(2) + y * grid.size + z;
-                let amount_of_crows = grid.grid[x][y][z].crows.len();
-                for crow in &grid.grid[x][y][z].crows {
-                    //This should just be the idx ideally.
-                    crow_idxs.push(crow.clone() as u32);
-                }
-                let current_amount = amount_of_crows_vec.last();
-                    
-                let mut total_amount = amount_of_crows as u32;
-                match current_amount {
-                    Some(val) => total_amount += val,
-                    None => {},
-                }         
-                amount_of_crows_vec.push(total_amount)
+impl render_graph::Node for BoidsNode {
+    //Waits until every pipeline has been compiled, the same as the game of life example of bevy.
+    //https://github.com/bevyengine/bevy/blob/release-0.12.1/examples/shader/compute_shader_game_of_life.rs
+    fn update(&mut self, world: &mut World) {
+        let pipeline = world.resource::<BoidsPipeline>();
+        if pipeline.ready {
+            return;
+        }
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let mut ready = true;
+        for id in pipeline.ids() {
+            match pipeline_cache.get_compute_pipeline_state(id) {
+                CachedPipelineState::Ok(_) => {},
+                //The cache tries again once the shader and its imports have been loaded.
+                CachedPipelineState::Err(PipelineCacheError::ShaderNotLoaded(_) | PipelineCacheError::ShaderImportNotYetAvailable) => ready = false,
+                CachedPipelineState::Err(err) => panic!("Failed to compile the boid shaders: {err}"),
+                _ => ready = false,
             }
         }
+        world.resource_mut::<BoidsPipeline>().ready = ready;
     }
 
+    fn run(
+        &self,
+        _graph: &mut render_graph::RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), render_graph::NodeRunError> {
+        let cr = world.resource::<ComputeResources>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let encoder = render_context.command_encoder();
+
+        if let Some(pipelines) = world.resource::<BoidsPipeline>().pipelines(pipeline_cache) {
+            run_lifecycle_shader(encoder, cr, &pipelines);
+            run_compute_shader(encoder, cr, &pipelines);
+        }
+
+        if cr.copy_alive_count {
+            encoder.copy_buffer_to_buffer(&cr.buffers.alive_count_buffer, 0, &cr.buffers.staging_buffer_alive_count, 0, 4);
+        }
+
+        // Sets adds copy operation to command encoder.
+        // Will copy data from storage buffer on GPU to staging buffer on CPU.
+        // The alive count is copied behind the boids, so the readback knows how many of them are alive.
+        if world.contains_resource::<PendingReadback>() {
+            let boids_size = boids_size(cr.buffers.capacity);
+            encoder.copy_buffer_to_buffer(cr.latest_boid_buffer(), 0, &cr.buffers.staging_buffer_boids, 0, boids_size);
+            encoder.copy_buffer_to_buffer(&cr.buffers.alive_count_buffer, 0, &cr.buffers.staging_buffer_boids, boids_size, 4);
+        }
 
-    cr.queue.write_buffer(&cr.storage_buffer_aoc, 0, bytemuck::cast_slice(&amount_of_crows_vec));
-    cr.queue.write_buffer(&cr.storage_buffer_cidxs, 0, bytemuck::cast_slice(&crow_idxs));
+        Ok(())
+    }
+}
+
+//Spawns and removes the boids of this frame, before the steps are run.
+fn run_lifecycle_shader(encoder: &mut CommandEncoder, cr: &ComputeResources, pipelines: &BoidsPipelines) {
+    if cr.spawn_count == 0 && cr.despawn.is_none() {
+        return
+    }
+    //The buffer the previous frame has written to, a despawn has already moved current_frame to the other buffer.
+    let latest = if cr.despawn.is_some() { cr.first_frame } else { (cr.first_frame + 1) % 2 };
 
-    let mut future_compute_resources_inner = future_resources_wrapper.lock().unwrap();
+    let mut cpass = encoder.begin_compute_pass(&ComputePassDescriptor {
+        label: Some("boid lifecycle"),
+    });
+    //The spawned boids are written to the latest buffer, which is the dst of the other bind group.
+    if cr.spawn_count > 0 {
+        cpass.set_pipeline(pipelines.spawn);
+        cpass.set_bind_group(0, &cr.bind_groups.lifecycle[(latest + 1) % 2], &[]);
+        cpass.dispatch_workgroups((cr.spawn_count + 31) / 32, 1, 1);
 
-    *future_compute_resources_inner = Some(cr);
+        cpass.set_pipeline(pipelines.finish_spawn);
+        cpass.dispatch_workgroups(1, 1, 1);
+    }
 
-    let mut future_boids_inner = future_boids_wrapper.lock().unwrap();
-    *future_boids_inner = Some(boids);
-    // info!("Finished compute inner");
+    //Copies the boids that stay alive from the latest buffer to the other one.
+    if cr.despawn.is_some() {
+        cpass.set_pipeline(pipelines.compact);
+        cpass.set_bind_group(0, &cr.bind_groups.lifecycle[latest], &[]);
+        cpass.dispatch_workgroups((cr.max_alive + 31) / 32, 1, 1);
 
+        cpass.set_pipeline(pipelines.finish_compact);
+        cpass.dispatch_workgroups(1, 1, 1);
+    }
 }
 
-async fn run_compute_shader(cr: &ComputeResources) -> Vec<Boid>{
-    let mut encoder =
-        cr.device.create_command_encoder(&CommandEncoderDescriptor { label: None });
-    {
+//Runs the compute shader for the steps of this frame, swapping the src and dst buffers after every step.
+fn run_compute_shader(encoder: &mut CommandEncoder, cr: &ComputeResources, pipelines: &BoidsPipelines) {
+    let boid_workgroups = (cr.max_alive + 31) / 32;
+    for step in 0..cr.steps as usize {
+        let frame = (cr.first_frame + step + 1) % 2;
+
+        //Build the grid of the src boids before updating them, the counts have to start at 0 for the counting sort.
+        encoder.clear_buffer(&cr.buffers.storage_buffer_cell_counts, 0, None);
         let mut cpass = encoder.begin_compute_pass(&ComputePassDescriptor {
             label: None,
         });
-        cpass.set_pipeline(&cr.pipeline);
-        cpass.set_bind_group(0, &cr.bind_groups[cr.current_frame], &[]);
-        cpass.insert_debug_marker("compute collatz iterations");
-        cpass.dispatch_workgroups(NUM_BOIDS / 32 as u32, 1, 1); // Number of cells to run, the (x,y,z) size of item being processed
-    }
+        //Move the grid along with the flock, as there are no walls that keep the boids inside of it.
+        if cr.config.boundary_mode == BoundaryMode::Open {
+            cpass.set_pipeline(pipelines.grid_centroid);
+            cpass.set_bind_group(0, &cr.bind_groups.grid_centroid[frame], &[]);
+            cpass.dispatch_workgroups(1, 1, 1);
+        }
+
+        cpass.set_pipeline(pipelines.grid_assign);
+        cpass.set_bind_group(0, &cr.bind_groups.grid_assign[frame], &[]);
+        cpass.dispatch_workgroups(boid_workgroups, 1, 1);
 
-    // Sets adds copy operation to command encoder.
-    // Will copy data from storage buffer on GPU to staging buffer on CPU.
-    //encoder.copy_buffer_to_buffer(&cr.storage_buffer, 0, &cr.staging_buffer, 0, cr.buffer_size);
-    encoder.copy_buffer_to_buffer(&cr.boid_buffers[(cr.current_frame + 1) % 2], 0, &cr.staging_buffer_boids, 0, cr.boids_buffer_size);
+        cpass.set_pipeline(pipelines.grid_prefix_sum);
+        cpass.set_bind_group(0, &cr.bind_groups.grid_prefix_sum, &[]);
+        cpass.dispatch_workgroups(1, 1, 1);
 
-    // Submits command encoder for processing
-    cr.queue.submit(Some(encoder.finish()));
+        cpass.set_pipeline(pipelines.grid_scatter);
+        cpass.set_bind_group(0, &cr.bind_groups.grid_scatter, &[]);
+        cpass.dispatch_workgroups(boid_workgroups, 1, 1);
+
+        cpass.set_pipeline(pipelines.boids);
+        cpass.set_bind_group(0, &cr.bind_groups.boids[frame], &[]);
+        cpass.dispatch_workgroups(boid_workgroups, 1, 1); // Number of cells to run, the (x,y,z) size of item being processed
+    }
+}
 
+async fn read_boids(
+    device: RenderDevice,
+    staging_buffer_boids: Buffer,
+    future_boids_wrapper: Arc<Mutex<Option<Vec<Boid>>>>
+) {
     // Note that we're not calling `.await` here.
-    // let buffer_slice = cr.staging_buffer.slice(..);
-    let buffer_boids_slice = cr.staging_buffer_boids.slice(..);
+    let buffer_boids_slice = staging_buffer_boids.slice(..);
     // Sets the buffer up for mapping, sending over the result of the mapping back to us when it is finished.
-    // let (sender, receiver) = flume::bounded(1);
     let (sender_boids, receiver_boids) = flume::bounded(1);
-    // buffer_slice.map_async(MapMode::Read, move |v| sender.send(v).unwrap());
     buffer_boids_slice.map_async(MapMode::Read, move |v| sender_boids.send(v).unwrap());
 
     // Poll the device in a blocking manner so that our future resolves.
     // In an actual application, `device.poll(...)` should
     // be called in an event loop or on another thread.
-    cr.device.wgpu_device().poll(wgpu::MaintainBase::Wait);
-    // device.poll(wgpu::Maintain::wait()).panic_on_timeout();
+    device.wgpu_device().poll(wgpu::MaintainBase::Wait);
 
     // Awaits until `buffer_future` can be read from
     if let Ok(Ok(())) = receiver_boids.recv_async().await {
         // Gets contents of buffer
         let data = buffer_boids_slice.get_mapped_range();
         // Since contents are got in bytes, this converts these bytes back to u32
-        let result: Vec<Boid> = bytemuck::cast_slice(&data).to_vec();
-        
-        //cr.queue.write_buffer(&cr.storage_buffer, 0, bytemuck::cast_slice(&vec![1, 2, 3, 4, 5]));
+        // Only the boids before the alive count, which is copied behind the boids, are alive.
+        let (boids, alive_count) = data.split_at(data.len() - 4);
+        let alive_count: u32 = bytemuck::pod_read_unaligned(alive_count);
+        let mut result: Vec<Boid> = bytemuck::cast_slice(boids).to_vec();
+        result.truncate(alive_count as usize);
+
         // With the current interface, we have to make sure all mapped views are
         // dropped before we unmap the buffer.
         drop(data);
-        cr.staging_buffer_boids.unmap(); // Unmaps buffer from memory
+        staging_buffer_boids.unmap(); // Unmaps buffer from memory
                                 // If you are familiar with C++ these 2 lines can be thought of similarly to:
                                 //   delete myPointer;
                                 //   myPointer = NULL;
                                 // It effectively frees the memory
-        
+
         // Returns data from buffer
-        //Some(result)
-        //println!("Size of Boids buffer: [{}]", result[0].pos.x);
-        result
+        let mut future_boids_inner = future_boids_wrapper.lock().unwrap();
+        *future_boids_inner = Some(result);
     } else {
-        panic!("failed to run compute on gpu!")
+        panic!("failed to read the boids back from the gpu!")
     }
 }
 
+//Same as read_boids, but for the alive count after despawning.
+async fn read_alive_count(
+    device: RenderDevice,
+    staging_buffer_alive_count: Buffer,
+    alive_count: Arc<Mutex<Option<u32>>>
+) {
+    let buffer_slice = staging_buffer_alive_count.slice(..);
+    let (sender, receiver) = flume::bounded(1);
+    buffer_slice.map_async(MapMode::Read, move |v| sender.send(v).unwrap());
+    device.wgpu_device().poll(wgpu::MaintainBase::Wait);
+
+    if let Ok(Ok(())) = receiver.recv_async().await {
+        let data = buffer_slice.get_mapped_range();
+        let count: u32 = bytemuck::pod_read_unaligned(&data);
+        drop(data);
+        staging_buffer_alive_count.unmap();
+        *alive_count.lock().unwrap() = Some(count);
+    } else {
+        panic!("failed to read the alive count back from the gpu!")
+    }
+}
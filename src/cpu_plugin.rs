@@ -0,0 +1,990 @@
+//! This file is responsible for updating the boids on the cpu instead of with the compute shaders.
+//! It is a reference implementation of boids_grid.wgsl, following the same rules with the same Params,
+//! so the behaviour of the boids can be checked on machines without a GPU.
+//! The boids are sorted into the Grid from shared.rs before every step, just like the grid_build shader does on the GPU.
+//! After updating, the boids are uploaded to the BoidBuffer so the instancing plugin can draw them the same way.
+//! Spawned and despawned boids are simply added to and removed from the vec, the BoidBuffer is replaced when they don't fit anymore.
+
+use bevy::{
+    prelude::*, render::{
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        renderer::{RenderDevice, RenderQueue},
+        render_resource::*,
+        Render, RenderApp, RenderSet,
+    }
+};
+use crate::shared::*;
+
+pub struct CpuPlugin;
+
+impl Plugin for CpuPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, (replace_cpu_boids, spawn_cpu_boids, update_cpu_boids, read_cpu_boids).chain());
+        app.init_resource::<SimulationTime>();
+        app.init_resource::<SimulationFrame>();
+        app.init_resource::<BoidReadback>();
+        app.init_resource::<BoidCount>();
+        app.init_resource::<Predators>();
+        app.init_resource::<Obstacles>();
+        app.init_resource::<ObstacleSdf>();
+        app.init_resource::<Attractors>();
+        app.init_resource::<WindData>();
+        app.add_event::<SpawnBoids>();
+        app.add_event::<DespawnBoids>();
+        app.add_plugins(ExtractResourcePlugin::<CpuBoids>::default());
+        if !app.is_plugin_added::<ExtractResourcePlugin<SimulationConfig>>() {
+            app.add_plugins(ExtractResourcePlugin::<SimulationConfig>::default());
+        }
+
+        let config = app.world.get_resource_or_insert_with(SimulationConfig::default).clone();
+        let species = app.world.get_resource_or_insert_with(Flocks::default).species.len() as u32;
+
+        let boids = match app.world.remove_resource::<InitialBoids>() {
+            Some(InitialBoids(boids)) => boids,
+            None => config.spawn.spawn(config.num_boids, config.seed, species)
+                .unwrap_or_else(|e| panic!("Failed to spawn the boids: {e}")),
+        };
+        app.insert_resource(CpuBoids(boids));
+        app.insert_resource(Grid::new(config.grid_size as usize, config.cell_size));
+
+        //Without a render app, ie. in headless mode, the boids are not drawn so they don't have to be uploaded.
+        if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app.add_systems(Render, upload_cpu_boids.in_set(RenderSet::Prepare));
+        }
+    }
+
+    //The buffer the boids are uploaded to can only be created once the render device is available.
+    fn finish(&self, app: &mut App) {
+        let num_boids = app.world.resource::<CpuBoids>().0.len() as u32;
+
+        let Ok(render_app) = app.get_sub_app_mut(RenderApp) else {
+            return
+        };
+        let device = render_app.world.resource::<RenderDevice>();
+        let boid_buffer = BoidBuffer {
+            buffer: create_boid_buffer(device, num_boids),
+            alive_count: device.create_buffer(&BufferDescriptor {
+                label: Some("Alive Count Buffer"),
+                size: std::mem::size_of::<u32>() as u64,
+                usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            length: num_boids,
+        };
+        render_app.insert_resource(boid_buffer);
+    }
+}
+
+//Never empty, so it can always be bound.
+fn create_boid_buffer(device: &RenderDevice, num_boids: u32) -> Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: Some("Storage Buffer Boids"),
+        size: (std::mem::size_of::<Boid>() * num_boids.max(1) as usize) as u64,
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+//The boids that are simulated on the cpu, extracted whenever they changed to upload them to the BoidBuffer.
+#[derive(Resource, Clone, ExtractResource)]
+pub struct CpuBoids(pub Vec<Boid>);
+
+#[allow(clippy::too_many_arguments)]
+fn update_cpu_boids(
+    time: Res<Time>,
+    config: Res<SimulationConfig>,
+    flocks: Res<Flocks>,
+    mut simulation_time: ResMut<SimulationTime>,
+    mut frame: ResMut<SimulationFrame>,
+    predators: Res<Predators>,
+    obstacles: Res<Obstacles>,
+    sdf: Res<ObstacleSdf>,
+    attractors: Res<Attractors>,
+    wind: Res<WindData>,
+    mut boids: ResMut<CpuBoids>,
+    mut grid: ResMut<Grid>,
+) {
+    simulation_time.accumulated += time.delta_seconds();
+    let (steps, dt) = simulation_time.consume(&config, &frame);
+    let params = flocks.to_params(&config);
+    let environment = Environment {
+        predators: &predators.0,
+        obstacles: &obstacles.0,
+        sdf: &sdf,
+        attractors: &attractors.0,
+        wind: &wind,
+    };
+    for _ in 0..steps {
+        boids.0 = step_boids(&boids.0, &params, &environment, dt, &mut grid);
+    }
+    frame.steps += steps as u64;
+}
+
+//Replaces the simulated boids, the boids are already on the cpu so there is nothing to upload.
+fn replace_cpu_boids(
+    mut commands: Commands,
+    upload: Option<Res<UploadBoids>>,
+    mut boids: ResMut<CpuBoids>,
+) {
+    let Some(upload) = upload else {
+        return
+    };
+    boids.0 = upload.0.clone();
+    commands.remove_resource::<UploadBoids>();
+}
+
+//Adds the spawned boids at the end and removes the despawned ones, the remaining boids keep their order.
+//When the world wraps around, the boids just across the edge from the center are removed as well.
+//The compute plugin removes the same boids, but compacts them in parallel, so the order of its remaining boids can differ.
+fn spawn_cpu_boids(
+    mut spawn_events: EventReader<SpawnBoids>,
+    mut despawn_events: EventReader<DespawnBoids>,
+    mut boids: ResMut<CpuBoids>,
+    mut count: ResMut<BoidCount>,
+    config: Res<SimulationConfig>,
+) {
+    for SpawnBoids(spawned) in spawn_events.read() {
+        boids.0.extend_from_slice(spawned);
+    }
+    let wrap = config.boundary_mode == BoundaryMode::Wrap;
+    for despawn in despawn_events.read() {
+        boids.0.retain(|boid| {
+            let mut offset = (boid.pos.truncate() - despawn.center).extend(0.0);
+            if wrap {
+                offset = minimum_image(offset);
+            }
+            offset.length() > despawn.radius
+        });
+    }
+    count.alive = boids.0.len() as u32;
+}
+
+//The boids are already on the cpu, so a readback can be answered right away.
+fn read_cpu_boids(
+    boids: Res<CpuBoids>,
+    frame: Res<SimulationFrame>,
+    mut readback: ResMut<BoidReadback>,
+) {
+    if readback.requested {
+        readback.requested = false;
+        readback.boids = Some(boids.0.clone());
+        readback.frame = frame.steps;
+    }
+}
+
+fn upload_cpu_boids(
+    boids: Res<CpuBoids>,
+    mut boid_buffer: ResMut<BoidBuffer>,
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+) {
+    if !boids.is_changed() {
+        return
+    }
+    let num_boids = boids.0.len() as u32;
+    if std::mem::size_of_val(boids.0.as_slice()) as u64 > boid_buffer.buffer.size() {
+        //Doubled, so spawning a few boids every frame doesn't create a new buffer every frame.
+        boid_buffer.buffer = create_boid_buffer(&device, num_boids.max(boid_buffer.length * 2));
+    }
+    queue.write_buffer(&boid_buffer.buffer, 0, bytemuck::cast_slice(&boids.0));
+    queue.write_buffer(&boid_buffer.alive_count, 0, bytemuck::bytes_of(&num_boids));
+    boid_buffer.length = num_boids;
+}
+
+// Has to match BoundaryMode::shader_value and the constants in boid_types.wgsl
+const BOUNDARY_WRAP: u32 = 0;
+const BOUNDARY_BOUNCE: u32 = 1;
+const BOUNDARY_STEER_BACK: u32 = 2;
+const BOUNDARY_OPEN: u32 = 3;
+
+// Has to match ObstacleShape::shader_value and the constants in boids_grid.wgsl
+const SHAPE_CUBOID: u32 = 1;
+const SHAPE_CAPSULE: u32 = 2;
+
+// Has to match NeighbourMode::shader_value and the constants in boids_grid.wgsl
+const NEIGHBOUR_TOPOLOGICAL: u32 = 1;
+
+// Has to match SteeringModel::shader_value and the constants in boids_grid.wgsl
+const STEERING_REYNOLDS: u32 = 1;
+
+// Has to match the state of Boid and the constants in boid_types.wgsl
+const STATE_FLYING: u32 = 0;
+const STATE_LANDING: u32 = 1;
+const STATE_PERCHED: u32 = 2;
+const STATE_TAKING_OFF: u32 = 3;
+const PERCH_SLOPE: f32 = 0.7;
+const PERCH_HEIGHT: f32 = 0.005;
+const TAKE_OFF_TIME: f32 = 0.5;
+
+// Has to match AttractorShape::shader_value and the constants in boids_grid.wgsl
+const ATTRACTOR_POINT: u32 = 0;
+const ATTRACTOR_PATH: u32 = 2;
+
+// Has to match SpeciesInteraction::shader_value and the constants in boids_grid.wgsl
+const INTERACTION_IGNORE: u32 = 0;
+const INTERACTION_AVOID: u32 = 1;
+
+//Everything besides the boids that the boids react to.
+pub struct Environment<'a> {
+    pub predators: &'a [PredatorData],
+    pub obstacles: &'a [ObstacleData],
+    pub sdf: &'a ObstacleSdf,
+    pub attractors: &'a [AttractorData],
+    pub wind: &'a WindData,
+}
+
+//One step of the simulation, the same as a single dispatch of the grid_build and boids_grid shaders.
+pub fn step_boids(boids: &[Boid], params: &Params, environment: &Environment, delta_time: f32, grid: &mut Grid) -> Vec<Boid> {
+    //When the world is open the grid follows the centroid of the flock, like the centroid entry point of grid_build.
+    let grid_origin = if params.boundary_mode == BOUNDARY_OPEN && !boids.is_empty() {
+        boids.iter().map(|boid| boid.pos.truncate()).sum::<Vec3>() / boids.len() as f32
+    } else {
+        Vec3::ZERO
+    };
+
+    grid.clear();
+    for (idx, boid) in boids.iter().enumerate() {
+        let grid_pos = boid.pos.truncate() - grid_origin;
+        if outside_grid(grid_pos, params) {
+            continue;
+        }
+        grid.add_with_transform(&Transform::from_translation(grid_pos), &idx);
+    }
+
+    boids.iter()
+        .enumerate()
+        .map(|(index, boid)| step_boid(index, boid, boids, params, environment, delta_time, grid, grid_origin))
+        .collect()
+}
+
+//Same as the grid_build shader, in an open world the boids outside of the grid are not sorted into it.
+//The other modes keep the boids in the box, so the coordinates at the very edge are clamped into the grid instead.
+fn outside_grid(grid_pos: Vec3, params: &Params) -> bool {
+    let half_size = params.grid_size * params.cell_size * 0.5;
+    params.boundary_mode == BOUNDARY_OPEN && (grid_pos.cmplt(Vec3::splat(-half_size)).any() || grid_pos.cmpge(Vec3::splat(half_size)).any())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn step_boid(index: usize, boid: &Boid, boids: &[Boid], params: &Params, environment: &Environment, delta_time: f32, grid: &Grid, grid_origin: Vec3) -> Boid {
+    let mut v_pos = boid.pos;
+    let mut v_vel = boid.vel;
+    let mut state = boid.state;
+    let species = species_index(v_pos, params);
+    let sp = &params.species[species];
+
+    let grid_size = grid.size as i32;
+    let grid_pos = v_pos.truncate() - grid_origin;
+    let grid_x = grid.cooridnate_to_grid_coordinate(grid_pos.x) as i32;
+    let grid_y = grid.cooridnate_to_grid_coordinate(grid_pos.y) as i32;
+    let grid_z = grid.cooridnate_to_grid_coordinate(grid_pos.z) as i32;
+
+    // Only a toroidal world searches the cells on the other side of the grid.
+    let wrap = params.boundary_mode == BOUNDARY_WRAP;
+    // With less than 3 cells the neighbour on both sides is the same cell, so it should only be visited once.
+    let mut max_delta = if wrap { 1.min(grid_size - 2) } else { 1 };
+    // A boid outside of the grid has no neighbours, so no cells are searched.
+    if outside_grid(grid_pos, params) {
+        max_delta = -2;
+    }
+
+    let mut total_seperation = Vec4::ZERO;
+    let mut total_alignment = Vec4::ZERO;
+    let mut total_cohesion = Vec4::ZERO;
+    let mut alignment_count = 0;
+    let mut cohesion_count = 0;
+    let mut startled = false;
+
+    // The nearest neighbours found so far sorted by distance, with their offset and index.
+    let topological = params.neighbour_mode == NEIGHBOUR_TOPOLOGICAL;
+    let k = params.nearest_count.clamp(1, MAX_NEAREST) as usize;
+    let mut nearest: Vec<(f32, Vec4, usize)> = Vec::with_capacity(k + 1);
+
+    for dx in -1..=max_delta {
+        for dy in -1..=max_delta {
+            for dz in -1..=max_delta {
+                let mut cell = IVec3::new(grid_x + dx, grid_y + dy, grid_z + dz);
+                if wrap {
+                    cell = (cell + IVec3::splat(grid_size)) % grid_size;
+                } else if cell.cmplt(IVec3::ZERO).any() || cell.cmpge(IVec3::splat(grid_size)).any() {
+                    continue;
+                }
+
+                for &i in &grid.grid[cell.x as usize][cell.y as usize][cell.z as usize].crows {
+                    if i == index {
+                        continue;
+                    }
+
+                    let interaction = params.interactions[species][species_index(boids[i].pos, params)];
+                    if interaction == INTERACTION_IGNORE {
+                        continue;
+                    }
+
+                    // The position of the other boid as seen from this boid, so flocks stay together across the edges.
+                    // The w is the species, so it is left out of the offset.
+                    let mut offset = (boids[i].pos - v_pos).truncate().extend(0.0);
+                    if wrap {
+                        offset = minimum_image(offset);
+                    }
+                    let pos = v_pos + offset;
+                    let vel = boids[i].vel;
+
+                    let dst = offset.length();
+
+                    if !can_see(sp, v_vel.truncate(), offset.truncate()) {
+                        continue;
+                    }
+
+                    // A neighbour taking off startles the boids around it, so the whole flock scatters.
+                    let other_state = boids[i].state.x as u32;
+                    if other_state == STATE_TAKING_OFF && dst < sp.cohesion_distance {
+                        startled = true;
+                    }
+
+                    // Boids of a species that is avoided are kept away from as far as the boid can see.
+                    if interaction == INTERACTION_AVOID {
+                        if 0.0 < dst && dst < sp.cohesion_distance {
+                            total_seperation += offset.normalize() * -1.0 / dst;
+                        }
+                        continue;
+                    }
+
+                    if 0.0 < dst && dst < sp.seperation_distance {
+                        total_seperation += offset.normalize() * -1.0 / dst;
+                    }
+
+                    // Landing and perched boids are on the ground, the flying boids only keep away from them.
+                    if other_state == STATE_LANDING || other_state == STATE_PERCHED {
+                        continue;
+                    }
+
+                    if topological {
+                        // Insert into the sorted nearest neighbours, the farthest one drops off when there are k already.
+                        if nearest.len() < k || dst < nearest[k - 1].0 {
+                            let j = nearest.partition_point(|n| n.0 <= dst);
+                            nearest.insert(j, (dst, offset, i));
+                            nearest.truncate(k);
+                        }
+                        continue;
+                    }
+
+                    if dst < sp.alignment_distance {
+                        total_alignment += vel;
+                        alignment_count += 1;
+                    }
+                    if dst < sp.cohesion_distance {
+                        total_cohesion += pos;
+                        cohesion_count += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    for &(_, offset, i) in &nearest {
+        total_alignment += boids[i].vel;
+        total_cohesion += v_pos + offset;
+    }
+    alignment_count += nearest.len();
+    cohesion_count += nearest.len();
+
+    if alignment_count > 0 {
+        total_alignment /= alignment_count as f32;
+    }
+
+    if cohesion_count > 0 {
+        //Average position of the nearby crows
+        total_cohesion /= cohesion_count as f32;
+        //Turn that into a velocity vector from the Boid we are calculating.
+        total_cohesion -= v_pos;
+    }
+    if total_seperation.length() > 0.0 {
+        total_seperation = total_seperation.normalize();
+    }
+
+    v_vel = v_vel + (total_seperation * sp.seperation_scale) +
+        (total_alignment * sp.alignment_scale) +
+        (total_cohesion * sp.cohesion_scale);
+
+    if params.boundary_mode == BOUNDARY_STEER_BACK {
+        v_vel += steer_back(v_pos, params);
+    }
+
+    // Flee from the predators that are close, the closer the predator the harder and faster the boid flees.
+    let mut panic: f32 = 0.0;
+    for predator in environment.predators {
+        let mut away = (v_pos - predator.pos).truncate().extend(0.0);
+        if wrap {
+            away = minimum_image(away);
+        }
+        let dst = away.length();
+        if 0.0 < dst && dst < predator.pos.w {
+            let fear = 1.0 - dst / predator.pos.w;
+            v_vel += away.normalize() * fear * predator.flee_strength;
+            panic = panic.max(fear * predator.speed_boost);
+        }
+    }
+
+    // Steer away from the obstacles that are close, the closer the obstacle the harder the boid steers.
+    for obstacle in environment.obstacles {
+        let away = obstacle_distance(obstacle, v_pos.truncate(), wrap);
+        if away.w < obstacle.margin {
+            v_vel += away.truncate().extend(0.0) * (1.0 - away.w / obstacle.margin.max(0.0001)) * obstacle.strength;
+        }
+    }
+    let sdf = environment.sdf;
+    let sdf_dst = sdf.distance(v_pos.truncate());
+    if sdf_dst < sdf.margin {
+        v_vel += sdf.normal(v_pos.truncate()).extend(0.0) * (1.0 - sdf_dst / sdf.margin) * sdf.strength;
+    }
+
+    // Pull towards the attractors, a path pulls towards its closest segment.
+    let mut a = 0;
+    while a < environment.attractors.len() {
+        let mut attractor = &environment.attractors[a];
+        let mut to = attractor_offset(attractor, v_pos.truncate(), wrap);
+        let segments = attractor.path_segments.max(1) as usize;
+        for segment in &environment.attractors[a + 1..a + segments] {
+            let to_segment = attractor_offset(segment, v_pos.truncate(), wrap);
+            if to_segment.w < to.w {
+                attractor = segment;
+                to = to_segment;
+            }
+        }
+        v_vel += attractor_force(attractor, to);
+        a += segments;
+    }
+
+    // Only flying boids follow the rules, the others land, sit or take off.
+    state.y += delta_time;
+    let perch = closest_perch(environment.obstacles, v_pos.truncate(), wrap);
+    // The w of the state stays with the boid, unlike its index which changes when boids are despawned.
+    let wait = 0.5 + state.w;
+    state = next_state(state, perch, params, startled || panic > 0.0, wait, sp.speed * 0.5 * delta_time + PERCH_HEIGHT);
+    let behaviour = state.x as u32;
+
+    // The direction along the perch, so a boid keeps facing the way it flew in.
+    let old_vel = boid.vel.truncate();
+    let normal = perch.truncate();
+    let along = (old_vel - normal * old_vel.dot(normal)).normalize_or_zero();
+
+    if behaviour == STATE_LANDING {
+        // Glide down onto the perch.
+        v_vel = ((along - normal) * sp.speed * 0.5).extend(0.0);
+    } else if behaviour == STATE_PERCHED {
+        // A tiny velocity, so the boid is still drawn facing the way it landed.
+        v_vel = (along * PERCH_HEIGHT).extend(0.0);
+        v_pos = (v_pos.truncate() - normal * (perch.w - PERCH_HEIGHT)).extend(v_pos.w);
+    } else if behaviour == STATE_TAKING_OFF {
+        v_vel = ((along + normal * 2.0).normalize() * sp.speed * (1.0 + panic)).extend(0.0);
+    } else if params.steering_model == STEERING_REYNOLDS {
+        v_vel = steer(sp, old_vel, v_vel.truncate() - old_vel, sp.speed * (1.0 + panic), delta_time).extend(v_vel.w);
+    } else {
+        // clamp velocity for a more pleasing simulation
+        v_vel = v_vel.normalize() * sp.speed * (1.0 + panic);
+    }
+
+    // The wings beat faster the faster the boid flies and fastest when it takes off, a perched boid keeps them still.
+    if behaviour != STATE_PERCHED {
+        let mut beats = sp.flap_rate * v_vel.truncate().length() / sp.speed.max(0.001);
+        if behaviour == STATE_TAKING_OFF {
+            beats *= 1.5;
+        }
+        state.z = (state.z + beats * delta_time).fract();
+    }
+
+    // kinematic update, the wind carries the boid along unless it is perched
+    if behaviour != STATE_PERCHED {
+        v_pos = v_pos + ((v_vel + wind_at(environment.wind, v_pos.truncate()).extend(0.0)) * delta_time);
+    }
+
+    if params.boundary_mode == BOUNDARY_WRAP {
+        // Wrap around boundary
+        let wrapped = v_pos.truncate() - 2.0 * Vec3::select(v_pos.truncate().abs().cmpgt(Vec3::ONE), v_pos.truncate().signum(), Vec3::ZERO);
+        v_pos = wrapped.extend(v_pos.w);
+    } else if params.boundary_mode == BOUNDARY_BOUNCE {
+        // Reflect the boids that went through a wall back into the box and turn them around.
+        let outside = v_pos.truncate().abs().cmpgt(Vec3::ONE);
+        let wall = v_pos.truncate().signum();
+        v_pos = Vec3::select(outside, 2.0 * wall - v_pos.truncate(), v_pos.truncate()).extend(v_pos.w);
+        v_vel = Vec3::select(outside, -wall * v_vel.truncate().abs(), v_vel.truncate()).extend(v_vel.w);
+    }
+
+    Boid {
+        pos: v_pos,
+        vel: v_vel,
+        state,
+    }
+}
+
+// Same as the shader, boids of an unknown species use the params of the last species.
+fn species_index(pos: Vec4, params: &Params) -> usize {
+    (pos.w as u32).min(params.species_count - 1) as usize
+}
+
+// The shortest offset from one boid to another, which might be across the edge of the world.
+fn minimum_image(offset: Vec4) -> Vec4 {
+    (offset.truncate() - 2.0 * (offset.truncate() * 0.5).round()).extend(0.0)
+}
+
+// Whether a neighbour at the offset is within the view cone around the heading and outside of the blind spot, the same as can_see in boids_grid.wgsl.
+fn can_see(sp: &SpeciesParams, heading: Vec3, offset: Vec3) -> bool {
+    let dst = offset.length();
+    let speed = heading.length();
+    if dst == 0.0 || speed == 0.0 {
+        return true;
+    }
+    let cos_angle = (heading.dot(offset) / (dst * speed)).clamp(-1.0, 1.0);
+    cos_angle >= sp.view_cos && -cos_angle <= sp.blind_spot_cos
+}
+
+// The direction away from the obstacle in xyz and the signed distance to it in w, the same as obstacle_distance in boids_grid.wgsl.
+fn obstacle_distance(obstacle: &ObstacleData, pos: Vec3, wrap: bool) -> Vec4 {
+    let mut offset = (pos - obstacle.a.truncate()).extend(0.0);
+    if wrap {
+        offset = minimum_image(offset);
+    }
+    let p = offset.truncate();
+    let shape = obstacle.a.w as u32;
+
+    if shape == SHAPE_CUBOID {
+        let q = p.abs() - obstacle.b.truncate();
+        let outside = q.max(Vec3::ZERO);
+        if outside.length() > 0.0 {
+            return (p.signum() * outside).normalize().extend(outside.length());
+        }
+        // Inside of the box it is pushed out through the closest side.
+        let inside = q.max_element();
+        let normal = if q.x == inside {
+            Vec3::new(p.x.signum(), 0.0, 0.0)
+        } else if q.y == inside {
+            Vec3::new(0.0, p.y.signum(), 0.0)
+        } else {
+            Vec3::new(0.0, 0.0, p.z.signum())
+        };
+        return normal.extend(inside);
+    }
+
+    // A sphere is a capsule of which both ends are the center.
+    let mut closest = Vec3::ZERO;
+    if shape == SHAPE_CAPSULE {
+        let axis = obstacle.b.truncate() - obstacle.a.truncate();
+        closest = axis * (p.dot(axis) / axis.dot(axis).max(0.000001)).clamp(0.0, 1.0);
+    }
+    let away = p - closest;
+    if away.length() == 0.0 {
+        return Vec4::new(0.0, 1.0, 0.0, -obstacle.b.w);
+    }
+    away.normalize().extend(away.length() - obstacle.b.w)
+}
+
+// The closest side of a perch that faces up, the same as closest_perch in boids_grid.wgsl.
+fn closest_perch(obstacles: &[ObstacleData], pos: Vec3, wrap: bool) -> Vec4 {
+    let mut closest = Vec4::new(0.0, 1.0, 0.0, 1000.0);
+    for obstacle in obstacles.iter().filter(|obstacle| obstacle.perch != 0) {
+        let surface = obstacle_distance(obstacle, pos, wrap);
+        if surface.y >= PERCH_SLOPE && surface.w > -PERCH_HEIGHT && surface.w < closest.w {
+            closest = surface;
+        }
+    }
+    closest
+}
+
+// Moves the boid to its next state, the same as next_state in boids_grid.wgsl.
+fn next_state(state: Vec4, perch: Vec4, params: &Params, disturbed: bool, wait: f32, landing_step: f32) -> Vec4 {
+    let current = state.x as u32;
+    let next = match current {
+        STATE_FLYING if params.flight_time > 0.0 && state.y > params.flight_time * wait && perch.w < params.landing_distance => STATE_LANDING,
+        STATE_LANDING if disturbed => STATE_TAKING_OFF,
+        // The perch has moved away.
+        STATE_LANDING if perch.w > params.landing_distance * 2.0 => STATE_FLYING,
+        STATE_LANDING if perch.w <= landing_step => STATE_PERCHED,
+        STATE_PERCHED if disturbed || state.y > params.perch_time * wait || perch.w > PERCH_HEIGHT * 2.0 => STATE_TAKING_OFF,
+        STATE_TAKING_OFF if state.y > TAKE_OFF_TIME => STATE_FLYING,
+        _ => current,
+    };
+    if next != current {
+        return Vec4::new(next as f32, 0.0, state.z, state.w);
+    }
+    state
+}
+
+// Random value between -1 and 1 for a corner of the noise lattice, the same as lattice_hash in boids_grid.wgsl.
+fn lattice_hash(corner: IVec3) -> f32 {
+    let mut h = (corner.x as u32).wrapping_mul(73856093) ^ (corner.y as u32).wrapping_mul(19349663) ^ (corner.z as u32).wrapping_mul(83492791);
+    h = (h ^ (h >> 16)).wrapping_mul(2246822507);
+    h = (h ^ (h >> 13)).wrapping_mul(3266489909);
+    h ^= h >> 16;
+    h as f32 / 4294967295.0 * 2.0 - 1.0
+}
+
+fn value_noise(p: Vec3) -> f32 {
+    let cell = p.floor().as_ivec3();
+    let f = p - p.floor();
+    let u = f * f * (Vec3::splat(3.0) - 2.0 * f);
+    let mix = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let x00 = mix(lattice_hash(cell), lattice_hash(cell + IVec3::new(1, 0, 0)), u.x);
+    let x10 = mix(lattice_hash(cell + IVec3::new(0, 1, 0)), lattice_hash(cell + IVec3::new(1, 1, 0)), u.x);
+    let x01 = mix(lattice_hash(cell + IVec3::new(0, 0, 1)), lattice_hash(cell + IVec3::new(1, 0, 1)), u.x);
+    let x11 = mix(lattice_hash(cell + IVec3::new(0, 1, 1)), lattice_hash(cell + IVec3::new(1, 1, 1)), u.x);
+    mix(mix(x00, x10, u.y), mix(x01, x11, u.y), u.z)
+}
+
+// Three unrelated noise values, the potential the curl noise is taken of.
+fn noise_potential(p: Vec3) -> Vec3 {
+    Vec3::new(value_noise(p), value_noise(p + Vec3::new(31.4, 0.0, 0.0)), value_noise(p + Vec3::new(0.0, 0.0, 47.2)))
+}
+
+// The curl of the potential flows in swirls, without pushing the boids together or apart.
+fn curl_noise(p: Vec3) -> Vec3 {
+    let e = 0.01;
+    let dx = (noise_potential(p + Vec3::X * e) - noise_potential(p - Vec3::X * e)) / (2.0 * e);
+    let dy = (noise_potential(p + Vec3::Y * e) - noise_potential(p - Vec3::Y * e)) / (2.0 * e);
+    let dz = (noise_potential(p + Vec3::Z * e) - noise_potential(p - Vec3::Z * e)) / (2.0 * e);
+    Vec3::new(dy.z - dz.y, dz.x - dx.z, dx.y - dy.x)
+}
+
+// The velocity of the air at the position, the same as wind_at in boids_grid.wgsl.
+fn wind_at(wind: &WindData, pos: Vec3) -> Vec3 {
+    let mut velocity = wind.velocity.truncate();
+    if wind.velocity.w > 0.0 {
+        velocity += curl_noise(pos / wind.turbulence_scale + Vec3::new(0.0, wind.time * 0.1, 0.0)) * wind.velocity.w;
+    }
+    velocity
+}
+
+// The offset from the position to the closest point of the attractor in xyz and the distance to it in w.
+fn attractor_offset(attractor: &AttractorData, pos: Vec3, wrap: bool) -> Vec4 {
+    let mut offset = (pos - attractor.a.truncate()).extend(0.0);
+    if wrap {
+        offset = minimum_image(offset);
+    }
+    let p = offset.truncate();
+    let mut closest = Vec3::ZERO;
+    if attractor.a.w as u32 != ATTRACTOR_POINT {
+        let axis = attractor.b.truncate() - attractor.a.truncate();
+        closest = axis * (p.dot(axis) / axis.dot(axis).max(0.000001)).clamp(0.0, 1.0);
+    }
+    let to = closest - p;
+    to.extend(to.length())
+}
+
+// Pulls towards the closest point, a path segment also pulls along itself so the boids follow the path.
+fn attractor_force(attractor: &AttractorData, to: Vec4) -> Vec4 {
+    let mut strength = attractor.b.w;
+    if attractor.falloff > 0.0 {
+        strength *= attractor.falloff / (attractor.falloff + to.w);
+    }
+    let mut pull = Vec3::ZERO;
+    if to.w > 0.0 {
+        pull = to.truncate() / to.w;
+    }
+    let axis = attractor.b.truncate() - attractor.a.truncate();
+    if attractor.a.w as u32 == ATTRACTOR_PATH && axis.length() > 0.0 {
+        // Boids on the path are only pulled along it.
+        if attractor.falloff > 0.0 {
+            pull *= (to.w / attractor.falloff).min(1.0);
+        }
+        pull += axis.normalize();
+    }
+    (pull * strength).extend(0.0)
+}
+
+// Reynolds style steering, the same as steer in boids_grid.wgsl.
+fn steer(sp: &SpeciesParams, vel: Vec3, force: Vec3, max_speed: f32, delta_time: f32) -> Vec3 {
+    let mut steering = force;
+    if force.length() > sp.max_force {
+        steering = force.normalize() * sp.max_force;
+    }
+    let mut new_vel = vel + steering / sp.mass.max(0.0001) * delta_time;
+
+    let speed = new_vel.length();
+    if vel.length() > 0.0 && speed > 0.0 {
+        let heading = vel.normalize();
+        let wanted = new_vel / speed;
+        let max_angle = sp.max_turn_rate * delta_time;
+        let cos_angle = heading.dot(wanted).clamp(-1.0, 1.0);
+        // Turned back exactly, any side works as long as it is perpendicular.
+        let mut side = wanted - heading * cos_angle;
+        if side.length() == 0.0 {
+            side = heading.cross(Vec3::Y);
+            if side.length() == 0.0 {
+                side = Vec3::X;
+            }
+        }
+        if cos_angle.acos() > max_angle {
+            new_vel = (heading * max_angle.cos() + side.normalize() * max_angle.sin()) * speed;
+        }
+    }
+
+    // A boid without a velocity has no direction to speed up in, so it starts flying along x.
+    let direction = if new_vel.length() > 0.0 { new_vel.normalize() } else { Vec3::X };
+    direction * new_vel.length().max(sp.min_speed).min(max_speed)
+}
+
+// Pushes a boid that is within the margin of a wall back, stronger the closer it gets to the wall.
+fn steer_back(pos: Vec4, params: &Params) -> Vec4 {
+    let inner = 1.0 - params.boundary_margin;
+    let over = (pos.truncate().abs() - Vec3::splat(inner)).max(Vec3::ZERO) / params.boundary_margin.max(0.0001);
+    (-pos.truncate().signum() * over * params.boundary_strength).extend(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+    use bevy::{
+        asset::AssetMetaCheck,
+        render::{
+            pipelined_rendering::PipelinedRenderingPlugin,
+            renderer::{initialize_renderer, RenderInstance},
+            settings::{RenderCreation, WgpuSettings},
+            RenderPlugin,
+        },
+        time::TimeUpdateStrategy,
+        window::ExitCondition,
+        winit::WinitPlugin,
+    };
+    use crate::{compute_plugin::ComputePlugin, headless_plugin::HeadlessPlugin};
+    use super::*;
+
+    const STEPS: u64 = 10;
+    //Frames a backend gets to compile its shaders, simulate the steps and read the boids back.
+    const MAX_FRAMES: u32 = 10_000;
+    //Both backends sum the neighbours in another order, so the boids drift apart by a bit of rounding every step.
+    const TOLERANCE: f32 = 1e-3;
+    //Step of the single step tests, small enough that no boid moves out of its cell.
+    const DELTA_TIME: f32 = 0.01;
+
+    //The renderer on the fallback adapter of wgpu, ie. llvmpipe or lavapipe, so the test doesn't need a GPU.
+    fn software_renderer() -> Option<RenderCreation> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let options = wgpu::RequestAdapterOptions {
+            force_fallback_adapter: true,
+            ..default()
+        };
+        bevy::tasks::block_on(instance.request_adapter(&options))?;
+        let (device, queue, adapter_info, adapter) = bevy::tasks::block_on(initialize_renderer(&instance, &WgpuSettings::default(), &options));
+        Some(RenderCreation::manual(device, queue, adapter_info, adapter, RenderInstance(Arc::new(instance))))
+    }
+
+    //Runs the steps in the same way as the headless mode and reads the boids back.
+    fn simulate(mut app: App, config: &SimulationConfig) -> Vec<Boid> {
+        app.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f32(config.fixed_timestep.unwrap())));
+        while app.plugins_state() != bevy::app::PluginsState::Ready {
+            bevy::tasks::tick_global_task_pools_on_main_thread();
+        }
+        app.finish();
+        app.cleanup();
+
+        for _ in 0..MAX_FRAMES {
+            app.update();
+            if app.world.resource::<SimulationFrame>().steps < STEPS {
+                continue;
+            }
+            let mut readback = app.world.resource_mut::<BoidReadback>();
+            match readback.boids.take() {
+                Some(boids) => {
+                    assert_eq!(readback.frame, STEPS);
+                    return boids;
+                },
+                None => readback.requested = true,
+            }
+        }
+        panic!("the boids were not read back after {MAX_FRAMES} frames");
+    }
+
+    fn cpu_boids(config: &SimulationConfig) -> Vec<Boid> {
+        let mut app = App::new();
+        app.insert_resource(config.clone())
+            .insert_resource(Flocks::default())
+            .add_plugins(MinimalPlugins)
+            .add_plugins(CpuPlugin);
+        simulate(app, config)
+    }
+
+    fn gpu_boids(config: &SimulationConfig, render_creation: RenderCreation) -> Vec<Boid> {
+        let mut app = App::new();
+        app.insert_resource(config.clone())
+            .insert_resource(Flocks::default())
+            .insert_resource(AssetMetaCheck::Never)
+            .add_plugins(DefaultPlugins
+                .set(WindowPlugin {
+                    primary_window: None,
+                    exit_condition: ExitCondition::DontExit,
+                    close_when_requested: false,
+                })
+                .set(RenderPlugin { render_creation })
+                .disable::<WinitPlugin>()
+                .disable::<bevy::log::LogPlugin>()
+                .disable::<PipelinedRenderingPlugin>())
+            .add_plugins(ComputePlugin);
+        simulate(app, config)
+    }
+
+    fn boid(pos: Vec3, vel: Vec3) -> Boid {
+        Boid {
+            pos: pos.extend(0.0),
+            vel: vel.extend(0.0),
+            state: Vec4::ZERO,
+        }
+    }
+
+    //A single species of boids that see all around them with the given params.
+    fn flocks(params: BoidParams) -> Flocks {
+        Flocks::new(vec![Species {
+            params: BoidParams {
+                view_angle: 360.0,
+                blind_spot: 0.0,
+                ..params
+            },
+            ..Species::crows()
+        }])
+    }
+
+    //A single step of the boids without predators, obstacles, attractors or wind.
+    fn step(boids: &[Boid], flocks: &Flocks, boundary_mode: BoundaryMode) -> Vec<Boid> {
+        let config = SimulationConfig::default().with_boundary_mode(boundary_mode);
+        let sdf = ObstacleSdf::default();
+        let wind = WindData::default();
+        let environment = Environment {
+            predators: &[],
+            obstacles: &[],
+            sdf: &sdf,
+            attractors: &[],
+            wind: &wind,
+        };
+        let mut grid = Grid::new(config.grid_size as usize, config.cell_size);
+        step_boids(boids, &flocks.to_params(&config), &environment, DELTA_TIME, &mut grid)
+    }
+
+    #[test]
+    fn seperation_pushes_neighbours_apart() {
+        let flocks = flocks(BoidParams {
+            seperation_distance: 0.05,
+            alignment_scale: 0.0,
+            cohesion_scale: 0.0,
+            ..default()
+        });
+        let boids = [boid(Vec3::ZERO, Vec3::Z), boid(Vec3::new(0.02, 0.0, 0.0), Vec3::Z)];
+        let stepped = step(&boids, &flocks, BoundaryMode::Bounce);
+
+        assert!(stepped[0].vel.x < 0.0 && stepped[1].vel.x > 0.0, "the boids fly at {} and {}", stepped[0].vel, stepped[1].vel);
+        assert!(stepped[0].pos.distance(stepped[1].pos) > boids[0].pos.distance(boids[1].pos));
+        //The direct steering model flies every boid at the speed of its species.
+        let speed = flocks.species[0].params.speed;
+        assert!((stepped[0].vel.length() - speed).abs() < 1e-5);
+    }
+
+    #[test]
+    fn minimum_image_is_the_shortest_offset() {
+        let offset = minimum_image(Vec4::new(1.9, -0.4, -1.5, 3.0));
+        assert!(offset.abs_diff_eq(Vec4::new(-0.1, -0.4, 0.5, 0.0), 1e-5), "the offset is {offset}");
+    }
+
+    #[test]
+    fn wrap_sees_neighbours_across_the_edge() {
+        let flocks = flocks(BoidParams {
+            seperation_distance: 0.05,
+            alignment_scale: 0.0,
+            cohesion_scale: 0.0,
+            ..default()
+        });
+        let boids = [boid(Vec3::new(0.995, 0.0, 0.0), Vec3::Z), boid(Vec3::new(-0.995, 0.0, 0.0), Vec3::Z)];
+
+        //The other boid is just across the edge, so they push each other back into the middle.
+        let wrapped = step(&boids, &flocks, BoundaryMode::Wrap);
+        assert!(wrapped[0].vel.x < 0.0 && wrapped[1].vel.x > 0.0, "the boids fly at {} and {}", wrapped[0].vel, wrapped[1].vel);
+
+        //Without wrapping they are on opposite sides of the box and don't see each other.
+        let bounced = step(&boids, &flocks, BoundaryMode::Bounce);
+        assert_eq!(bounced[0].vel.x, 0.0);
+        assert_eq!(bounced[1].vel.x, 0.0);
+    }
+
+    #[test]
+    fn wrap_moves_boids_to_the_other_side() {
+        let flocks = flocks(BoidParams::default());
+        let speed = flocks.species[0].params.speed;
+        let boids = [boid(Vec3::new(0.999, 0.5, 0.0), Vec3::X * speed)];
+        let stepped = step(&boids, &flocks, BoundaryMode::Wrap);
+        assert!(stepped[0].pos.abs_diff_eq(Vec4::new(0.999 + speed * DELTA_TIME - 2.0, 0.5, 0.0, 0.0), 1e-5), "the boid is at {}", stepped[0].pos);
+    }
+
+    #[test]
+    fn topological_uses_the_k_nearest() {
+        let params = BoidParams {
+            alignment_distance: 0.1,
+            seperation_scale: 0.0,
+            cohesion_scale: 0.0,
+            ..default()
+        };
+        //The two nearest neighbours fly up, the farthest flies down much faster.
+        let boids = [
+            boid(Vec3::ZERO, Vec3::X),
+            boid(Vec3::new(0.03, 0.0, 0.0), Vec3::Y),
+            boid(Vec3::new(0.0, -0.04, 0.0), Vec3::Y),
+            boid(Vec3::new(0.0, 0.0, 0.06), Vec3::NEG_Y * 10.0),
+        ];
+
+        let mut topological = flocks(params);
+        topological.neighbour_mode = NeighbourMode::Topological { k: 2 };
+        let stepped = step(&boids, &topological, BoundaryMode::Wrap);
+        assert!(stepped[0].vel.y > 0.0, "the boid flies at {}", stepped[0].vel);
+
+        topological.neighbour_mode = NeighbourMode::Topological { k: 3 };
+        let stepped = step(&boids, &topological, BoundaryMode::Wrap);
+        assert!(stepped[0].vel.y < 0.0, "the boid flies at {}", stepped[0].vel);
+
+        let stepped = step(&boids, &flocks(params), BoundaryMode::Wrap);
+        assert!(stepped[0].vel.y < 0.0, "the boid flies at {}", stepped[0].vel);
+    }
+
+    #[test]
+    fn steer_limits_the_force_speed_and_turn_rate() {
+        let sp = BoidParams {
+            mass: 1.0,
+            max_force: 1.0,
+            min_speed: 0.2,
+            max_turn_rate: 90.0,
+            ..default()
+        }.to_species_params();
+        let vel = Vec3::X * 0.3;
+
+        //The force is cut to the max force, which accelerates the boid by max_force / mass every second.
+        let faster = steer(&sp, vel, Vec3::X * 100.0, 10.0, 0.1);
+        assert!((faster.length() - 0.4).abs() < 1e-5, "the boid flies at {faster}");
+
+        let capped = steer(&sp, vel, Vec3::X * 100.0, 0.35, 0.1);
+        assert!((capped.length() - 0.35).abs() < 1e-5, "the boid flies at {capped}");
+
+        //Braking never stops the boid, it keeps flying forward at the min speed.
+        let braked = steer(&sp, vel, Vec3::NEG_X * 100.0, 10.0, 0.25);
+        assert!(braked.abs_diff_eq(Vec3::X * 0.2, 1e-5), "the boid flies at {braked}");
+
+        //A sideways force turns the boid by at most the max turn rate.
+        let sp = SpeciesParams { max_force: 1000.0, ..sp };
+        let turned = steer(&sp, vel, Vec3::Y * 100.0, 10.0, 0.1);
+        assert!((turned.angle_between(Vec3::X) - 90f32.to_radians() * 0.1).abs() < 1e-5, "the boid flies at {turned}");
+    }
+
+    #[test]
+    #[ignore = "needs a fallback wgpu adapter"]
+    fn cpu_matches_gpu() {
+        let render_creation = software_renderer().expect("there is no fallback adapter to run the compute shaders on");
+        let config = HeadlessPlugin::headless_config(SimulationConfig::default()
+            .with_num_boids(1000)
+            .with_seed(9)
+            .with_steps(STEPS));
+
+        let cpu = cpu_boids(&config);
+        let gpu = gpu_boids(&config, render_creation);
+        assert_eq!(cpu.len(), gpu.len());
+        for (i, (cpu_boid, gpu_boid)) in cpu.iter().zip(&gpu).enumerate() {
+            //A boid that wrapped around on one backend but not yet on the other is still close.
+            let pos_error = minimum_image(cpu_boid.pos - gpu_boid.pos).truncate().length();
+            let vel_error = (cpu_boid.vel - gpu_boid.vel).truncate().length();
+            assert!(pos_error < TOLERANCE, "boid {i} is at {} on the cpu and at {} on the gpu", cpu_boid.pos, gpu_boid.pos);
+            assert!(vel_error < TOLERANCE, "boid {i} flies at {} on the cpu and at {} on the gpu", cpu_boid.vel, gpu_boid.vel);
+            assert_eq!(cpu_boid.state.x, gpu_boid.state.x, "boid {i} is in another state");
+        }
+    }
+}
@@ -0,0 +1,130 @@
+//! This file is responsible for running the simulation without a window, ie. for parameter sweeps on machines without a display.
+//! The simulation runs for the amount of steps in the SimulationConfig, after which the boids are read back and written to disk.
+//! With the cpu backend only the MinimalPlugins of bevy are needed,
+//! the compute shaders still need the renderer of bevy, which runs without a window when winit is disabled.
+//! https://github.com/bevyengine/bevy/blob/release-0.12.1/examples/app/headless.rs
+//! Was used to understand how to run bevy without a window.
+
+use std::{path::PathBuf, time::Duration};
+use bevy::{
+    app::{AppExit, ScheduleRunnerPlugin},
+    prelude::*,
+    time::TimeUpdateStrategy,
+    window::ExitCondition,
+    winit::WinitPlugin,
+};
+use crate::shared::*;
+use crate::snapshot_plugin::Snapshot;
+
+//Steps that are simulated when no amount of steps is given.
+const DEFAULT_STEPS: u64 = 1000;
+//Step size when no fixed timestep is given, the simulation doesn't depend on the speed of the machine in headless mode.
+const DEFAULT_TIMESTEP: f32 = 1.0 / 60.0;
+
+pub struct HeadlessPlugin {
+    pub output: PathBuf,
+}
+
+impl HeadlessPlugin {
+    //Reads the file the boids are written to from the command line, ie. `cargo run -- --headless --output boids.csv`
+    //When the file ends with .crows a snapshot is written instead, so the run can be continued with `--load`.
+    pub fn from_args() -> Self {
+        let args: Vec<String> = std::env::args().collect();
+        let output = args.windows(2)
+            .find(|pair| pair[0] == "--output")
+            .map(|pair| PathBuf::from(&pair[1]))
+            .unwrap_or_else(|| PathBuf::from("boids.csv"));
+        Self { output }
+    }
+
+    //Makes sure the run ends and every frame simulates the same amount of time, no matter how long the frame took.
+    pub fn headless_config(config: SimulationConfig) -> SimulationConfig {
+        let steps = config.steps.unwrap_or(DEFAULT_STEPS);
+        let timestep = config.fixed_timestep.unwrap_or(DEFAULT_TIMESTEP);
+        let max_timestep = config.max_timestep.max(timestep);
+        config
+            .with_steps(steps)
+            .with_fixed_timestep(timestep)
+            .with_max_timestep(max_timestep)
+    }
+
+    //The plugins of bevy the backend needs to run without a window.
+    pub fn add_bevy_plugins(app: &mut App, backend: SimulationBackend) {
+        match backend {
+            SimulationBackend::Cpu => {
+                app.add_plugins(MinimalPlugins);
+            },
+            SimulationBackend::Gpu => {
+                app.add_plugins(DefaultPlugins
+                    .set(WindowPlugin {
+                        primary_window: None,
+                        exit_condition: ExitCondition::DontExit,
+                        close_when_requested: false,
+                    })
+                    .disable::<WinitPlugin>())
+                    .add_plugins(ScheduleRunnerPlugin::run_loop(Duration::ZERO));
+            },
+        }
+    }
+}
+
+impl Plugin for HeadlessPlugin {
+    fn build(&self, app: &mut App) {
+        let timestep = app.world.resource::<SimulationConfig>().fixed_timestep.unwrap_or(DEFAULT_TIMESTEP);
+        app.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f32(timestep)));
+        app.insert_resource(HeadlessOutput(self.output.clone()));
+        app.add_systems(Last, finish_headless_run);
+    }
+}
+
+#[derive(Resource)]
+struct HeadlessOutput(PathBuf);
+
+//Reads the boids back once all steps have been simulated, writes them to disk and exits.
+//Printed instead of logged, as the MinimalPlugins of the cpu backend don't include the LogPlugin.
+fn finish_headless_run(
+    config: Res<SimulationConfig>,
+    flocks: Res<Flocks>,
+    frame: Res<SimulationFrame>,
+    output: Res<HeadlessOutput>,
+    mut readback: ResMut<BoidReadback>,
+    mut exit: EventWriter<AppExit>,
+) {
+    if frame.steps < config.steps.unwrap_or(DEFAULT_STEPS) {
+        return
+    }
+
+    match readback.boids.take() {
+        Some(boids) => {
+            let count = boids.len();
+            let result = if output.0.extension().is_some_and(|extension| extension == "crows") {
+                Snapshot {
+                    config: SimulationConfig {
+                        num_boids: count as u32,
+                        ..config.clone()
+                    },
+                    flocks: flocks.clone(),
+                    frame: readback.frame,
+                    boids,
+                }.save(&output.0)
+            } else {
+                write_boids_csv(&output.0, &boids)
+            };
+            if let Err(e) = result {
+                exit_with_error(&format!("failed to write the boids to {}: {e}", output.0.display()));
+            }
+            println!("Wrote {} boids after {} steps to {}", count, readback.frame, output.0.display());
+            exit.send(AppExit);
+        },
+        None => readback.requested = true,
+    }
+}
+
+//Writes the position, velocity, species and state of every boid on its own line.
+fn write_boids_csv(path: &PathBuf, boids: &[Boid]) -> std::io::Result<()> {
+    let mut csv = String::from("x,y,z,vx,vy,vz,species,state\n");
+    for boid in boids {
+        csv.push_str(&format!("{},{},{},{},{},{},{},{}\n", boid.pos.x, boid.pos.y, boid.pos.z, boid.vel.x, boid.vel.y, boid.vel.z, boid.pos.w as u32, boid.state.x as u32));
+    }
+    std::fs::write(path, csv)
+}
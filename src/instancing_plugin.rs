@@ -4,19 +4,35 @@
 //! The file is a modification from the example provided by bevy:
 //! https://github.com/bevyengine/bevy/blob/release-0.12.1/examples/shader/shader_instancing.rs
 //! We have modified some of the instancing data to include the velocity of a crow and we also use our own custom mesh.
+//! Instead of a vertex buffer with the instance data, the vertex shader reads the boids straight from the storage buffer of the compute plugin,
+//! so the positions never have to leave the GPU.
+//! The crows are lit like a StandardMaterial, and are also queued into the shadow phase of every light that casts shadows.
+//! The shadow pipeline only draws the depth, with the view bind group of the prepass like the shadows of bevy.
+//! In the prepass the same pipeline also writes the normals and the motion vectors when the view asks for them.
+//! The alpha mode of a species decides the phase its crows are drawn in, opaque species write their depth like any other opaque mesh.
+//! Every phase gets the whole instanced draw, the vertex shader hides the boids of the species that are drawn in another phase.
 
 use bevy::{
-    core_pipeline::core_3d::Transparent3d,
+    core_pipeline::{
+        core_3d::{AlphaMask3d, Opaque3d, Transparent3d},
+        prepass::{
+            MotionVectorPrepass, NormalPrepass, Opaque3dPrepass, MOTION_VECTOR_PREPASS_FORMAT,
+            NORMAL_PREPASS_FORMAT,
+        },
+    },
     ecs::{
         // query::QueryItem,
         system::{lifetimeless::*, SystemParamItem},
     },
+    core_pipeline::core_3d::CORE_3D_DEPTH_FORMAT,
     pbr::{
-        MeshPipeline, MeshPipelineKey, RenderMeshInstances, SetMeshBindGroup, SetMeshViewBindGroup,
+        LightEntity, MeshPipeline, MeshPipelineKey, PrepassPipeline, RenderMeshInstances, SetMeshBindGroup,
+        SetMeshViewBindGroup, SetPrepassViewBindGroup, Shadow, ViewLightEntities,
     },
     prelude::*,
     render::{
         extract_component::{ExtractComponent, ExtractComponentPlugin},
+        extract_resource::ExtractResourcePlugin,
         mesh::{GpuBufferInfo, MeshVertexBufferLayout},
         render_asset::RenderAssets,
         render_phase::{
@@ -24,10 +40,11 @@ use bevy::{
             RenderPhase, SetItemPipeline, TrackedRenderPass,
         },
         render_resource::*,
-        renderer::RenderDevice,
+        renderer::{RenderDevice, RenderQueue},
         view::{ExtractedView, NoFrustumCulling},
         Render, RenderApp, RenderSet,
     },
+    utils::HashMap,
 };
 // use bytemuck::{Pod, Zeroable};
 use bevy_obj::ObjPlugin;
@@ -44,20 +61,15 @@ impl Plugin for Instancing_Plugin {
     }
 }
 
-fn setup(mut commands: Commands, mut meshes: ResMut<Assets<Mesh>>, asset_server: Res<AssetServer>) {
+fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
     // println!("We get here");
     let mesh_handle: Handle<Mesh> = asset_server.load("crow1.obj");
     commands.spawn((
         mesh_handle,
         SpatialBundle::INHERITED_IDENTITY,
-        InstanceMaterialData(
-            (0..NUM_BOIDS).map(|_| InstanceData {
-                position: Vec3::new(0.0, 0.0, 0.0),
-                scale: 0.01,
-                vel: Vec4::new(0.0, 0.0, 0.0, 0.0),
-                color: Color::hsla(0.0, 0.0, 0.0, 1.0).as_rgba_f32()
-            }).collect()
-        ),
+        InstanceMaterialData {
+            scale: 0.01,
+        },
         NoFrustumCulling
     ));
 }
@@ -68,14 +80,27 @@ pub struct CustomMaterialPlugin;
 impl Plugin for CustomMaterialPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(ExtractComponentPlugin::<InstanceMaterialData>::default());
+        if !app.is_plugin_added::<ExtractResourcePlugin<SimulationConfig>>() {
+            app.add_plugins(ExtractResourcePlugin::<SimulationConfig>::default());
+        }
+        if !app.is_plugin_added::<ExtractResourcePlugin<Flocks>>() {
+            app.add_plugins(ExtractResourcePlugin::<Flocks>::default());
+        }
         app.sub_app_mut(RenderApp)
+            .add_render_command::<Opaque3d, DrawCustom>()
+            .add_render_command::<AlphaMask3d, DrawCustom>()
             .add_render_command::<Transparent3d, DrawCustom>()
+            .add_render_command::<Opaque3dPrepass, DrawBoidsDepth>()
+            .add_render_command::<Shadow, DrawBoidsDepth>()
             .init_resource::<SpecializedMeshPipelines<CustomPipeline>>()
+            .init_resource::<BoidsInstanceCache>()
             .add_systems(
                 Render,
                 (
                     queue_custom.in_set(RenderSet::QueueMeshes),
-                    prepare_instance_buffers.in_set(RenderSet::PrepareResources),
+                    queue_boids_prepass.in_set(RenderSet::QueueMeshes),
+                    queue_boids_shadows.in_set(RenderSet::QueueMeshes),
+                    prepare_boids_bind_groups.in_set(RenderSet::PrepareBindGroups),
                 ),
             );
     }
@@ -86,8 +111,19 @@ impl Plugin for CustomMaterialPlugin {
 }
 
 
-#[allow(clippy::too_many_arguments)]
+//Which alpha modes the species use, see Species::alpha_shader_value
+fn used_alpha_modes(flocks: &Flocks) -> [bool; 3] {
+    let mut used = [false; 3];
+    for species in flocks.species.iter().take(MAX_SPECIES) {
+        used[species.alpha_shader_value().0 as usize] = true;
+    }
+    used
+}
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
 fn queue_custom(
+    opaque_3d_draw_functions: Res<DrawFunctions<Opaque3d>>,
+    alpha_mask_3d_draw_functions: Res<DrawFunctions<AlphaMask3d>>,
     transparent_3d_draw_functions: Res<DrawFunctions<Transparent3d>>,
     custom_pipeline: Res<CustomPipeline>,
     msaa: Res<Msaa>,
@@ -95,14 +131,23 @@ fn queue_custom(
     pipeline_cache: Res<PipelineCache>,
     meshes: Res<RenderAssets<Mesh>>,
     render_mesh_instances: Res<RenderMeshInstances>,
+    flocks: Res<Flocks>,
     material_meshes: Query<Entity, With<InstanceMaterialData>>,
-    mut views: Query<(&ExtractedView, &mut RenderPhase<Transparent3d>)>,
+    mut views: Query<(
+        &ExtractedView,
+        &mut RenderPhase<Opaque3d>,
+        &mut RenderPhase<AlphaMask3d>,
+        &mut RenderPhase<Transparent3d>,
+    )>,
 ) {
-    let draw_custom = transparent_3d_draw_functions.read().id::<DrawCustom>();
+    let draw_opaque = opaque_3d_draw_functions.read().id::<DrawCustom>();
+    let draw_alpha_mask = alpha_mask_3d_draw_functions.read().id::<DrawCustom>();
+    let draw_transparent = transparent_3d_draw_functions.read().id::<DrawCustom>();
 
     let msaa_key = MeshPipelineKey::from_msaa_samples(msaa.samples());
+    let [opaque, alpha_mask, blend] = used_alpha_modes(&flocks);
 
-    for (view, mut transparent_phase) in &mut views {
+    for (view, mut opaque_phase, mut alpha_mask_phase, mut transparent_phase) in &mut views {
         let view_key = msaa_key | MeshPipelineKey::from_hdr(view.hdr);
         let rangefinder = view.rangefinder3d();
         for entity in &material_meshes {
@@ -113,13 +158,97 @@ fn queue_custom(
                 continue;
             };
             let key = view_key | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology);
-            let pipeline = pipelines
+            let mut specialize = |key| pipelines
+                .specialize(&pipeline_cache, &custom_pipeline, key, &mesh.layout)
+                .unwrap();
+            //The crows are spread over the whole box, so a single distance is only right for sorting against other meshes in the middle.
+            let distance = rangefinder
+                .distance_translation(&mesh_instance.transforms.transform.translation);
+            if opaque {
+                opaque_phase.add(Opaque3d {
+                    entity,
+                    pipeline: specialize(key),
+                    draw_function: draw_opaque,
+                    distance,
+                    batch_range: 0..1,
+                    dynamic_offset: None,
+                });
+            }
+            if alpha_mask {
+                alpha_mask_phase.add(AlphaMask3d {
+                    entity,
+                    pipeline: specialize(key | MeshPipelineKey::MAY_DISCARD),
+                    draw_function: draw_alpha_mask,
+                    distance,
+                    batch_range: 0..1,
+                    dynamic_offset: None,
+                });
+            }
+            if blend {
+                transparent_phase.add(Transparent3d {
+                    entity,
+                    pipeline: specialize(key | MeshPipelineKey::BLEND_ALPHA),
+                    draw_function: draw_transparent,
+                    distance,
+                    batch_range: 0..1,
+                    dynamic_offset: None,
+                });
+            }
+        }
+    }
+}
+
+//Every view with a prepass gets the crows, the pipeline writes the normals and motion vectors when the view has those textures.
+//In a deferred view the crows are still drawn forward, so they go into the forward part of the prepass like other forward meshes.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn queue_boids_prepass(
+    prepass_draw_functions: Res<DrawFunctions<Opaque3dPrepass>>,
+    custom_pipeline: Res<CustomPipeline>,
+    msaa: Res<Msaa>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<CustomPipeline>>,
+    pipeline_cache: Res<PipelineCache>,
+    meshes: Res<RenderAssets<Mesh>>,
+    render_mesh_instances: Res<RenderMeshInstances>,
+    flocks: Res<Flocks>,
+    material_meshes: Query<Entity, With<InstanceMaterialData>>,
+    mut views: Query<(
+        &ExtractedView,
+        &mut RenderPhase<Opaque3dPrepass>,
+        Has<NormalPrepass>,
+        Has<MotionVectorPrepass>,
+    )>,
+) {
+    let [opaque, alpha_mask, _] = used_alpha_modes(&flocks);
+    if !opaque && !alpha_mask {
+        return;
+    }
+    let draw_depth = prepass_draw_functions.read().id::<DrawBoidsDepth>();
+    let msaa_key = MeshPipelineKey::from_msaa_samples(msaa.samples());
+
+    for (view, mut prepass_phase, normal_prepass, motion_vector_prepass) in &mut views {
+        let mut view_key = msaa_key | MeshPipelineKey::DEPTH_PREPASS;
+        if normal_prepass {
+            view_key |= MeshPipelineKey::NORMAL_PREPASS;
+        }
+        if motion_vector_prepass {
+            view_key |= MeshPipelineKey::MOTION_VECTOR_PREPASS;
+        }
+        let rangefinder = view.rangefinder3d();
+        for entity in &material_meshes {
+            let Some(mesh_instance) = render_mesh_instances.get(&entity) else {
+                continue;
+            };
+            let Some(mesh) = meshes.get(mesh_instance.mesh_asset_id) else {
+                continue;
+            };
+            let key = view_key | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology);
+            let pipeline_id = pipelines
                 .specialize(&pipeline_cache, &custom_pipeline, key, &mesh.layout)
                 .unwrap();
-            transparent_phase.add(Transparent3d {
+            prepass_phase.add(Opaque3dPrepass {
                 entity,
-                pipeline,
-                draw_function: draw_custom,
+                pipeline_id,
+                draw_function: draw_depth,
                 distance: rangefinder
                     .distance_translation(&mesh_instance.transforms.transform.translation),
                 batch_range: 0..1,
@@ -129,26 +258,173 @@ fn queue_custom(
     }
 }
 
+//The boids are everywhere in the box, so they are drawn into every shadow map without checking what the light can see.
+//Like the prepass only the species that write their depth cast shadows, shadow_vertex hides the blended ones.
+#[allow(clippy::too_many_arguments)]
+fn queue_boids_shadows(
+    shadow_draw_functions: Res<DrawFunctions<Shadow>>,
+    custom_pipeline: Res<CustomPipeline>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<CustomPipeline>>,
+    pipeline_cache: Res<PipelineCache>,
+    meshes: Res<RenderAssets<Mesh>>,
+    render_mesh_instances: Res<RenderMeshInstances>,
+    flocks: Res<Flocks>,
+    material_meshes: Query<Entity, With<InstanceMaterialData>>,
+    view_lights: Query<&ViewLightEntities>,
+    mut view_light_shadow_phases: Query<(&LightEntity, &mut RenderPhase<Shadow>)>,
+) {
+    let [opaque, alpha_mask, _] = used_alpha_modes(&flocks);
+    if !opaque && !alpha_mask {
+        return;
+    }
+    let draw_shadow = shadow_draw_functions.read().id::<DrawBoidsDepth>();
+
+    for view_lights in &view_lights {
+        for view_light_entity in view_lights.lights.iter().copied() {
+            let Ok((light_entity, mut shadow_phase)) = view_light_shadow_phases.get_mut(view_light_entity) else {
+                continue;
+            };
+            let is_directional_light = matches!(light_entity, LightEntity::Directional { .. });
+            for entity in &material_meshes {
+                let Some(mesh_instance) = render_mesh_instances.get(&entity) else {
+                    continue;
+                };
+                if !mesh_instance.shadow_caster {
+                    continue;
+                }
+                let Some(mesh) = meshes.get(mesh_instance.mesh_asset_id) else {
+                    continue;
+                };
+                let mut key = MeshPipelineKey::from_primitive_topology(mesh.primitive_topology)
+                    | MeshPipelineKey::DEPTH_PREPASS;
+                if is_directional_light {
+                    key |= MeshPipelineKey::DEPTH_CLAMP_ORTHO;
+                }
+                let pipeline = match pipelines.specialize(&pipeline_cache, &custom_pipeline, key, &mesh.layout) {
+                    Ok(pipeline) => pipeline,
+                    Err(err) => {
+                        error!("{}", err);
+                        continue;
+                    }
+                };
+                shadow_phase.add(Shadow {
+                    entity,
+                    pipeline,
+                    draw_function: draw_shadow,
+                    distance: 0.0,
+                    batch_range: 0..1,
+                    dynamic_offset: None,
+                });
+            }
+        }
+    }
+}
+
+//Uniform with the values of InstanceMaterialData, the look of every species and the scale of the world, so the vertex shader can place the boids.
+#[derive(ShaderType, Clone, Copy, PartialEq)]
+struct BoidsInstanceUniform {
+    colors: [Vec4; MAX_SPECIES],
+    sizes: Vec4,
+    //See Species::alpha_shader_value
+    alpha_modes: UVec4,
+    alpha_cutoffs: Vec4,
+    world_scale: f32,
+    scale: f32,
+}
+
+//The uniform and the bind groups of every instanced mesh, kept between frames so they are not created again every frame.
+#[derive(Resource, Default)]
+struct BoidsInstanceCache(HashMap<Entity, BoidsInstanceBuffers>);
+
+struct BoidsInstanceBuffers {
+    uniform: UniformBuffer<BoidsInstanceUniform>,
+    //Keyed by the boid buffer and the alive count buffer they bind.
+    bind_groups: HashMap<(BufferId, BufferId), BindGroup>,
+}
+
 #[derive(Component)]
-pub struct InstanceBuffer {
-    buffer: Buffer,
-    length: usize,
+pub struct BoidsBindGroup {
+    bind_group: BindGroup,
+    length: u32,
 }
 
-fn prepare_instance_buffers(
+fn prepare_boids_bind_groups(
     mut commands: Commands,
     query: Query<(Entity, &InstanceMaterialData)>,
+    boid_buffer: Option<Res<BoidBuffer>>,
+    config: Res<SimulationConfig>,
+    flocks: Res<Flocks>,
+    custom_pipeline: Res<CustomPipeline>,
+    mut cache: ResMut<BoidsInstanceCache>,
     render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
 ) {
+    let Some(boid_buffer) = boid_buffer else {
+        return;
+    };
+    let mut colors = [Vec4::ONE; MAX_SPECIES];
+    let mut sizes = [1.0; MAX_SPECIES];
+    let mut alpha_modes = [0; MAX_SPECIES];
+    let mut alpha_cutoffs = [0.0; MAX_SPECIES];
+    for (i, species) in flocks.species.iter().take(MAX_SPECIES).enumerate() {
+        colors[i] = species.color.as_rgba_f32().into();
+        sizes[i] = species.size;
+        (alpha_modes[i], alpha_cutoffs[i]) = species.alpha_shader_value();
+    }
+    //Forget the meshes that are not drawn anymore.
+    cache.0.retain(|entity, _| query.contains(*entity));
     for (entity, instance_data) in &query {
-        let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            label: Some("instance data buffer"),
-            contents: bytemuck::cast_slice(instance_data.as_slice()),
-            usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+        let values = BoidsInstanceUniform {
+            colors,
+            sizes: Vec4::from_array(sizes),
+            alpha_modes: UVec4::from_array(alpha_modes),
+            alpha_cutoffs: Vec4::from_array(alpha_cutoffs),
+            world_scale: config.world_scale(),
+            scale: instance_data.scale,
+        };
+        //The uniform is created once and only written again when the species, the config or the scale have been changed.
+        let buffers = cache.0.entry(entity).or_insert_with(|| {
+            let mut uniform = UniformBuffer::from(values);
+            uniform.write_buffer(&render_device, &render_queue);
+            BoidsInstanceBuffers {
+                uniform,
+                bind_groups: HashMap::default(),
+            }
         });
-        commands.entity(entity).insert(InstanceBuffer {
-            buffer,
-            length: instance_data.len(),
+        if *buffers.uniform.get() != values {
+            buffers.uniform.set(values);
+            buffers.uniform.write_buffer(&render_device, &render_queue);
+        }
+
+        //The compute plugin swaps between two boid buffers every step, so both get a bind group.
+        //The buffers are only replaced when the boids don't fit anymore, then the bind groups of the old buffers are dropped.
+        let key = (boid_buffer.buffer.id(), boid_buffer.alive_count.id());
+        if !buffers.bind_groups.contains_key(&key) && buffers.bind_groups.len() >= 2 {
+            buffers.bind_groups.clear();
+        }
+        let uniform = &buffers.uniform;
+        let bind_group = buffers.bind_groups.entry(key).or_insert_with(|| render_device.create_bind_group(
+            "boids instance bind group",
+            &custom_pipeline.boids_layout,
+            &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: boid_buffer.buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: uniform.binding().unwrap(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: boid_buffer.alive_count.as_entire_binding(),
+                },
+            ],
+        ));
+        //The entities of the render world are cleared every frame, so the bind group is added to the mesh again.
+        commands.entity(entity).insert(BoidsBindGroup {
+            bind_group: bind_group.clone(),
+            length: boid_buffer.length,
         });
     }
 }
@@ -157,6 +433,11 @@ fn prepare_instance_buffers(
 pub struct CustomPipeline {
     shader: Handle<Shader>,
     mesh_pipeline: MeshPipeline,
+    //Layout of the view bind group of the shadow passes, which is only the view and the globals.
+    prepass_view_layout: BindGroupLayout,
+    //The same with the view projection of the last frame, for the views with motion vectors.
+    prepass_view_layout_motion_vectors: BindGroupLayout,
+    boids_layout: BindGroupLayout,
 }
 
 impl FromWorld for CustomPipeline {
@@ -165,10 +446,54 @@ impl FromWorld for CustomPipeline {
         let shader = asset_server.load("shaders/instancing.wgsl");
 
         let mesh_pipeline = world.resource::<MeshPipeline>();
+        let prepass_pipeline = world.resource::<PrepassPipeline<StandardMaterial>>();
+        let prepass_view_layout = prepass_pipeline.view_layout_no_motion_vectors.clone();
+        let prepass_view_layout_motion_vectors = prepass_pipeline.view_layout_motion_vectors.clone();
+
+        let render_device = world.resource::<RenderDevice>();
+        let boids_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("boids instance layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::VERTEX,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(Boid::min_size()),
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::VERTEX_FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(BoidsInstanceUniform::min_size()),
+                    },
+                    count: None,
+                },
+                //The amount of instances is only an upper bound, the vertex shader hides the boids after the alive count.
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::VERTEX,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: Some(u32::min_size()),
+                    },
+                    count: None,
+                },
+            ],
+        });
 
         CustomPipeline {
             shader,
             mesh_pipeline: mesh_pipeline.clone(),
+            prepass_view_layout,
+            prepass_view_layout_motion_vectors,
+            boids_layout,
         }
     }
 }
@@ -181,6 +506,9 @@ impl SpecializedMeshPipeline for CustomPipeline {
         key: Self::Key,
         layout: &MeshVertexBufferLayout,
     ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        if key.contains(MeshPipelineKey::DEPTH_PREPASS) {
+            return self.specialize_depth(key, layout);
+        }
         let mut descriptor = self.mesh_pipeline.specialize(key, layout)?;
 
         // meshes typically live in bind group 2. because we are using bindgroup 1
@@ -191,52 +519,175 @@ impl SpecializedMeshPipeline for CustomPipeline {
             .shader_defs
             .push("MESH_BINDGROUP_1".into());
 
+        // The mesh pipeline has no shader def for alpha blending, which the shader needs to only draw the blended species.
+        if key.intersection(MeshPipelineKey::BLEND_RESERVED_BITS) == MeshPipelineKey::BLEND_ALPHA {
+            descriptor.vertex.shader_defs.push("BLEND_ALPHA".into());
+            descriptor.fragment.as_mut().unwrap().shader_defs.push("BLEND_ALPHA".into());
+        }
+
         descriptor.vertex.shader = self.shader.clone();
-        descriptor.vertex.buffers.push(VertexBufferLayout {
-            array_stride: std::mem::size_of::<InstanceData>() as u64,
-            step_mode: VertexStepMode::Instance,
-            attributes: vec![
-                VertexAttribute {
-                    format: VertexFormat::Float32x4,
-                    offset: 0,
-                    shader_location: 3, // shader locations 0-2 are taken up by Position, Normal and UV attributes
-                },
-                VertexAttribute {
-                    format: VertexFormat::Float32x4,
-                    offset: VertexFormat::Float32x4.size(),
-                    shader_location: 4,
-                },
-                VertexAttribute {
-                    format: VertexFormat::Float32x4,
-                    offset: VertexFormat::Float32x4.size() * 2,
-                    shader_location: 5,
-                }
-            ],
-        });
+        // The boids are read from a storage buffer in bind group 2 instead of an instance vertex buffer.
+        descriptor.layout.push(self.boids_layout.clone());
         descriptor.fragment.as_mut().unwrap().shader = self.shader.clone();
         Ok(descriptor)
     }
 }
 
+impl CustomPipeline {
+    //Depth pipeline for the shadow maps and the prepass, the same as the prepass pipeline of bevy but with the vertex shader of the crows.
+    fn specialize_depth(
+        &self,
+        key: MeshPipelineKey,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mut shader_defs = vec!["MESH_BINDGROUP_1".into(), "DEPTH_PREPASS".into()];
+        let vertex_buffer_layout = layout.get_layout(&[
+            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+            Mesh::ATTRIBUTE_NORMAL.at_shader_location(1),
+            Mesh::ATTRIBUTE_UV_0.at_shader_location(2),
+        ])?;
+
+        //A directional light clamps the depth of the crows behind its near plane, which needs the fragment shader to write the real depth.
+        let clamp_depth = key.contains(MeshPipelineKey::DEPTH_CLAMP_ORTHO);
+        if clamp_depth {
+            shader_defs.push("DEPTH_CLAMP_ORTHO".into());
+        }
+
+        //The prepass has the normal and motion vector textures at the first two attachments, like the prepass pipeline of bevy.
+        let normal_prepass = key.contains(MeshPipelineKey::NORMAL_PREPASS);
+        let motion_vector_prepass = key.contains(MeshPipelineKey::MOTION_VECTOR_PREPASS);
+        if normal_prepass {
+            shader_defs.push("NORMAL_PREPASS".into());
+        }
+        if motion_vector_prepass {
+            shader_defs.push("MOTION_VECTOR_PREPASS".into());
+        }
+        let mut targets = vec![
+            normal_prepass.then_some(ColorTargetState {
+                format: NORMAL_PREPASS_FORMAT,
+                blend: None,
+                write_mask: ColorWrites::ALL,
+            }),
+            motion_vector_prepass.then_some(ColorTargetState {
+                format: MOTION_VECTOR_PREPASS_FORMAT,
+                blend: None,
+                write_mask: ColorWrites::ALL,
+            }),
+            None,
+            None,
+        ];
+        if targets.iter().all(Option::is_none) {
+            targets.clear();
+        }
+
+        let fragment = if clamp_depth {
+            Some(FragmentState {
+                shader: self.shader.clone(),
+                shader_defs: shader_defs.clone(),
+                entry_point: "shadow_fragment".into(),
+                targets: vec![],
+            })
+        } else if !targets.is_empty() {
+            shader_defs.push("PREPASS_OUTPUTS".into());
+            Some(FragmentState {
+                shader: self.shader.clone(),
+                shader_defs: shader_defs.clone(),
+                entry_point: "prepass_fragment".into(),
+                targets,
+            })
+        } else {
+            None
+        };
+
+        let view_layout = if motion_vector_prepass {
+            self.prepass_view_layout_motion_vectors.clone()
+        } else {
+            self.prepass_view_layout.clone()
+        };
+
+        Ok(RenderPipelineDescriptor {
+            label: Some("boids depth pipeline".into()),
+            layout: vec![
+                view_layout,
+                self.mesh_pipeline.mesh_layouts.model_only.clone(),
+                self.boids_layout.clone(),
+            ],
+            push_constant_ranges: vec![],
+            vertex: VertexState {
+                shader: self.shader.clone(),
+                shader_defs,
+                entry_point: "shadow_vertex".into(),
+                buffers: vec![vertex_buffer_layout],
+            },
+            fragment,
+            primitive: PrimitiveState {
+                topology: key.primitive_topology(),
+                cull_mode: None,
+                ..default()
+            },
+            depth_stencil: Some(DepthStencilState {
+                format: CORE_3D_DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: CompareFunction::GreaterEqual,
+                stencil: StencilState::default(),
+                bias: DepthBiasState::default(),
+            }),
+            multisample: MultisampleState {
+                count: key.msaa_samples(),
+                ..default()
+            },
+        })
+    }
+}
+
 type DrawCustom = (
     SetItemPipeline,
     SetMeshViewBindGroup<0>,
     SetMeshBindGroup<1>,
+    SetBoidsBindGroup<2>,
     DrawMeshInstanced,
 );
 
+type DrawBoidsDepth = (
+    SetItemPipeline,
+    SetPrepassViewBindGroup<0>,
+    SetMeshBindGroup<1>,
+    SetBoidsBindGroup<2>,
+    DrawMeshInstanced,
+);
+
+pub struct SetBoidsBindGroup<const I: usize>;
+
+impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetBoidsBindGroup<I> {
+    type Param = ();
+    type ViewWorldQuery = ();
+    type ItemWorldQuery = Read<BoidsBindGroup>;
+
+    #[inline]
+    fn render<'w>(
+        _item: &P,
+        _view: (),
+        boids_bind_group: &'w BoidsBindGroup,
+        _param: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        pass.set_bind_group(I, &boids_bind_group.bind_group, &[]);
+        RenderCommandResult::Success
+    }
+}
+
 pub struct DrawMeshInstanced;
 
 impl<P: PhaseItem> RenderCommand<P> for DrawMeshInstanced {
     type Param = (SRes<RenderAssets<Mesh>>, SRes<RenderMeshInstances>);
     type ViewWorldQuery = ();
-    type ItemWorldQuery = Read<InstanceBuffer>;
+    type ItemWorldQuery = Read<BoidsBindGroup>;
 
     #[inline]
     fn render<'w>(
         item: &P,
         _view: (),
-        instance_buffer: &'w InstanceBuffer,
+        boids_bind_group: &'w BoidsBindGroup,
         (meshes, render_mesh_instances): SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
@@ -249,7 +700,6 @@ impl<P: PhaseItem> RenderCommand<P> for DrawMeshInstanced {
         };
 
         pass.set_vertex_buffer(0, gpu_mesh.vertex_buffer.slice(..));
-        pass.set_vertex_buffer(1, instance_buffer.buffer.slice(..));
 
         match &gpu_mesh.buffer_info {
             GpuBufferInfo::Indexed {
@@ -258,10 +708,10 @@ impl<P: PhaseItem> RenderCommand<P> for DrawMeshInstanced {
                 count,
             } => {
                 pass.set_index_buffer(buffer.slice(..), 0, *index_format);
-                pass.draw_indexed(0..*count, 0, 0..instance_buffer.length as u32);
+                pass.draw_indexed(0..*count, 0, 0..boids_bind_group.length);
             }
             GpuBufferInfo::NonIndexed => {
-                pass.draw(0..gpu_mesh.vertex_count, 0..instance_buffer.length as u32);
+                pass.draw(0..gpu_mesh.vertex_count, 0..boids_bind_group.length);
             }
         }
         RenderCommandResult::Success
@@ -13,17 +13,78 @@ use std::f32::consts::PI;
 pub mod shared;
 use shared::*;
 
+pub mod spawn;
+use spawn::SpawnDistribution;
+
 mod compute_plugin;
 use compute_plugin::ComputePlugin;
 
+mod cpu_plugin;
+use cpu_plugin::CpuPlugin;
+
 mod instancing_plugin;
 use instancing_plugin::Instancing_Plugin;
 
+mod params_ui_plugin;
+use params_ui_plugin::ParamsUiPlugin;
+
+mod headless_plugin;
+use headless_plugin::HeadlessPlugin;
+
+mod snapshot_plugin;
+use snapshot_plugin::{Snapshot, SnapshotPlugin};
+
+mod predator_plugin;
+use predator_plugin::PredatorPlugin;
+
+mod obstacle_plugin;
+use obstacle_plugin::ObstaclePlugin;
+
+mod attractor_plugin;
+use attractor_plugin::AttractorPlugin;
+
+mod wind_plugin;
+use wind_plugin::WindPlugin;
+
 
 //Main, adding some useful plugins that allow for some easy logging.
 fn main() {
-    App::new()
-        .add_plugins(DefaultPlugins.set(WindowPlugin {
+    let mut config = SimulationConfig::from_args();
+    let flocks = Flocks::from_args();
+    let backend = config.backend;
+    let snapshot = snapshot_from_args();
+    if let Some(snapshot) = &snapshot {
+        //The backend and the amount of steps to simulate still come from the command line.
+        config = SimulationConfig {
+            backend,
+            steps: config.steps,
+            ..snapshot.config.clone()
+        };
+    }
+
+    //Runs the simulation without a window and writes the boids to disk, ie. `cargo run -- --headless --steps 1000 --output boids.csv`
+    if std::env::args().any(|arg| arg == "--headless") {
+        let mut app = App::new();
+        app.insert_resource(HeadlessPlugin::headless_config(config))
+            .insert_resource(flocks);
+        insert_initial_boids(&mut app, snapshot);
+        HeadlessPlugin::add_bevy_plugins(&mut app, backend);
+        add_simulation_plugin(&mut app, backend);
+        app.add_plugins(PredatorPlugin::from_args())
+            .add_plugins(ObstaclePlugin::from_args())
+            .add_plugins(AttractorPlugin::from_args())
+            .add_plugins(WindPlugin::from_args())
+            .add_plugins(HeadlessPlugin::from_args())
+            .insert_resource(AssetMetaCheck::Never)
+            .run();
+        return
+    }
+
+    let mut app = App::new();
+    app.insert_resource(config)
+        .insert_resource(flocks);
+    insert_initial_boids(&mut app, snapshot);
+    app.add_plugins(DefaultPlugins.set(WindowPlugin {
             primary_window: Some(Window {
                 present_mode: bevy::window::PresentMode::AutoNoVsync, // Doesn't limit framerate.
                 fit_canvas_to_parent: true, //Webapp will turn full screen using this.
@@ -33,34 +94,65 @@ fn main() {
         }))
         .add_plugins(LogDiagnosticsPlugin::default())
         .add_plugins(FrameTimeDiagnosticsPlugin::default())
-        .add_plugins(PanOrbitCameraPlugin)
-        .add_plugins(ComputePlugin)
-        .add_plugins(Instancing_Plugin)
+        .add_plugins(PanOrbitCameraPlugin);
+
+    add_simulation_plugin(&mut app, backend);
+
+    app.add_plugins(Instancing_Plugin)
+        .add_plugins(PredatorPlugin::from_args())
+        .add_plugins(ObstaclePlugin::from_args())
+        .add_plugins(AttractorPlugin::from_args())
+        .add_plugins(WindPlugin::from_args())
+        .add_plugins(ParamsUiPlugin)
+        .add_plugins(SnapshotPlugin::from_args())
         .insert_resource(AssetMetaCheck::Never)
         .insert_resource(ClearColor(Color::WHITE))
         .add_systems(Startup, setup)
-        .add_systems(Update, system)
+        .add_systems(Update, (system, spawn_and_despawn_crows, toggle_rules))
         .run()
 }
 
-#[derive(Resource)]
-pub struct Animations(Vec<Handle<AnimationClip>>);
+//Starts from a snapshot instead of random boids, ie. `cargo run -- --load snapshot.crows`
+fn snapshot_from_args() -> Option<Snapshot> {
+    let args: Vec<String> = std::env::args().collect();
+    let path = args.windows(2).find(|pair| pair[0] == "--load")?[1].clone();
+    match Snapshot::load(path.as_ref()) {
+        Ok(snapshot) => Some(snapshot),
+        Err(e) => exit_with_error(&format!("failed to load the snapshot from {path}: {e}")),
+    }
+}
 
-//Old animation function
-pub fn _run_animation(animations : Res<Animations>, mut players_query : Query<&mut AnimationPlayer, Added<AnimationPlayer>>){
-    let mut rng = thread_rng();
-    for mut player in &mut players_query{
-        player.play(animations.0[0].clone()).repeat();
-        player.seek_to(rng.gen_range(0..10000) as f32 / 10000.0);
-        player.set_speed((rng.gen_range(0..5000) as f32 / 10000.0) + 1.0);
+//Has to be inserted before the plugins, so they start with the state of the snapshot or the spawned boids.
+//The species of the snapshot replace the ones from the command line.
+fn insert_initial_boids(app: &mut App, snapshot: Option<Snapshot>) {
+    if let Some(snapshot) = snapshot {
+        app.insert_resource(snapshot.flocks)
+            .insert_resource(SimulationFrame { steps: snapshot.frame })
+            .insert_resource(InitialBoids(snapshot.boids));
+        return
     }
+    //Spawned here instead of by the simulation plugin, so a `--spawn-file` that can't be used ends the program with an error.
+    let config = app.world.resource::<SimulationConfig>();
+    let species = app.world.resource::<Flocks>().species.len() as u32;
+    match config.spawn.spawn(config.num_boids, config.seed, species) {
+        Ok(boids) => app.insert_resource(InitialBoids(boids)),
+        Err(e) => exit_with_error(&e.to_string()),
+    };
+}
+
+//The cpu backend is a lot slower, but follows the same rules as the compute shaders.
+fn add_simulation_plugin(app: &mut App, backend: SimulationBackend) {
+    match backend {
+        SimulationBackend::Gpu => app.add_plugins(ComputePlugin),
+        SimulationBackend::Cpu => app.add_plugins(CpuPlugin),
+    };
 }
 
 fn setup(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    asset_server: Res<AssetServer>
+    config: Res<SimulationConfig>
 ) {
 
     // Flying Camera
@@ -73,13 +165,17 @@ fn setup(
     ));
 
 
-    // plane
-    commands.spawn(PbrBundle {
-        mesh: meshes.add(Mesh::from(shape::Plane::from_size(BOX_SIZE*2.))),
-        material: materials.add(Color::rgb(0.3, 0.9, 0.3).into()),
-        transform: Transform::from_xyz(0., -BOX_SIZE*0.5, 0.),
-        ..default()
-    });
+    // plane, the crows fly over it instead of through it and can land on it
+    commands.spawn((
+        PbrBundle {
+            mesh: meshes.add(Mesh::from(shape::Plane::from_size(config.box_size*2.))),
+            material: materials.add(Color::rgb(0.3, 0.9, 0.3).into()),
+            transform: Transform::from_xyz(0., -config.box_size*0.5, 0.),
+            ..default()
+        },
+        Obstacle::new(ObstacleShape::Cuboid { half_extents: Vec3::new(config.box_size, 0.0, config.box_size) }),
+        Perch,
+    ));
     
     // ambient light
     commands.insert_resource(AmbientLight {
@@ -110,33 +206,47 @@ fn setup(
         .into(),
         ..default()
     });
+}
 
-    commands.insert_resource(Animations(vec![asset_server.load("crow1.glb#Animation0")]));
-
-    // let glb_scene: Handle<Mesh> = asset_server.load("crow.glb#Scene0");
-    
-
-
-    // let mut crows: Vec<CrowBundle> = Vec::with_capacity(NUM_BOIDS as usize);
-    // for i in 0..NUM_BOIDS {
-    //     crows.push(CrowBundle {
-    //         pbr: SceneBundle {
-    //             scene: asset_server.load("crow1.glb#Scene0"),
-    //             transform: Transform::default().with_scale(Vec3::splat(0.02)),
-    //             ..default()
-    //         },
-    //         boid_entity: BoidEntity(i as usize)
-    //     });
-    // }
-
-    // commands.spawn_batch(crows);
+//Press + to let a flock of a random species arrive at a random place and - to let the crows around a random place leave.
+fn spawn_and_despawn_crows(
+    keys: Res<Input<KeyCode>>,
+    flocks: Res<Flocks>,
+    mut spawn_events: EventWriter<SpawnBoids>,
+    mut despawn_events: EventWriter<DespawnBoids>,
+) {
+    let mut rng = thread_rng();
+    let center = Vec3::new(rng.gen_range(-0.8..0.8), rng.gen_range(-0.8..0.8), rng.gen_range(-0.8..0.8));
+    if keys.just_pressed(KeyCode::Equals) {
+        let species = rng.gen_range(0..flocks.species.len().max(1)) as f32;
+        let mut boids = SpawnDistribution::SphereShell { inner_radius: 0.0, outer_radius: 0.1 }.spawn(1000, rng.gen(), 1)
+            .expect("only spawning from a file can fail");
+        for boid in &mut boids {
+            boid.pos = (boid.pos.truncate() + center).extend(species);
+        }
+        spawn_events.send(SpawnBoids(boids));
+    }
+    if keys.just_pressed(KeyCode::Minus) {
+        despawn_events.send(DespawnBoids { center, radius: 0.3 });
+    }
+}
 
+//Press T to switch between the metric and the topological neighbour rule and R to switch between the steering models.
+fn toggle_rules(keys: Res<Input<KeyCode>>, mut flocks: ResMut<Flocks>) {
+    if keys.just_pressed(KeyCode::T) {
+        flocks.neighbour_mode = flocks.neighbour_mode.toggled();
+        info!("Neighbour mode: {:?}", flocks.neighbour_mode);
+    }
+    if keys.just_pressed(KeyCode::R) {
+        flocks.steering_model = flocks.steering_model.toggled();
+        info!("Steering model: {:?}", flocks.steering_model);
+    }
 }
 
-fn system(mut gizmos: Gizmos) {
+fn system(mut gizmos: Gizmos, config: Res<SimulationConfig>) {
     gizmos.cuboid(
 
-        Transform::from_xyz(0., 0., 0.).with_scale(Vec3::splat(BOX_SIZE)),
+        Transform::from_xyz(0., 0., 0.).with_scale(Vec3::splat(config.box_size)),
         Color::GREEN,
     );
 }
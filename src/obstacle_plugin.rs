@@ -0,0 +1,318 @@
+//! This file is responsible for the obstacles the boids fly around, ie. the ground, buildings and trees.
+//! Every frame the obstacles with a simple shape are collected into the Obstacles resource, which both simulation backends read.
+//! Obstacles with a mesh are baked into the ObstacleSdf, a grid with the distance to the closest mesh over the whole simulation space.
+//! The sdf is only baked again when a mesh obstacle has been added, moved or removed, as baking takes a while.
+//! Baking is done on the async compute task pool, a mesh obstacle that keeps moving is baked again once the previous bake is done.
+//! https://www.iquilezles.org/www/articles/distfunctions/distfunctions.htm
+//! Was used for the distance functions of the shapes.
+//! Real-Time Collision Detection by Christer Ericson, chapter 5.1.5
+//! Was used for the closest point on a triangle.
+//! Obstacles with a simple shape and a Perch component are places the boids can land on, ie. the ground.
+//! Use `--obstacles` to place a tree, a building and a ring in the box.
+
+use std::sync::{Arc, Mutex};
+use bevy::{prelude::*, render::{mesh::VertexAttributeValues, render_resource::PrimitiveTopology}, tasks::AsyncComputeTaskPool};
+use crate::shared::*;
+
+//Voxels of the sdf along every axis.
+const SDF_RESOLUTION: u32 = 64;
+
+pub struct ObstaclePlugin {
+    //Place some obstacles in the box at the start.
+    pub demo: bool,
+}
+
+impl ObstaclePlugin {
+    pub fn from_args() -> Self {
+        Self {
+            demo: std::env::args().any(|arg| arg == "--obstacles"),
+        }
+    }
+}
+
+impl Plugin for ObstaclePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Obstacles>()
+            .init_resource::<ObstacleSdf>()
+            .add_systems(PostUpdate, (collect_obstacles, bake_obstacle_sdf));
+        if self.demo {
+            app.add_systems(Startup, spawn_demo_obstacles);
+        }
+    }
+}
+
+//The meshes are only added when there is a renderer, in headless mode the ring can't be baked so it is left out.
+fn spawn_demo_obstacles(
+    mut commands: Commands,
+    config: Res<SimulationConfig>,
+    meshes: Option<ResMut<Assets<Mesh>>>,
+    materials: Option<ResMut<Assets<StandardMaterial>>>,
+) {
+    let world_scale = config.world_scale();
+    //The crown of the tree and the roof of the building are perches.
+    let shapes = [
+        //The trunk and crown of a tree
+        (Vec3::new(-0.5, -0.75, -0.3), ObstacleShape::Capsule { half_height: 0.2, radius: 0.03 }, false),
+        (Vec3::new(-0.5, -0.45, -0.3), ObstacleShape::Sphere { radius: 0.15 }, true),
+        //A building
+        (Vec3::new(0.5, -0.6, 0.4), ObstacleShape::Cuboid { half_extents: Vec3::new(0.15, 0.4, 0.15) }, true),
+    ];
+
+    let Some((mut meshes, mut materials)) = meshes.zip(materials) else {
+        for (pos, shape, perch) in shapes {
+            let mut obstacle = commands.spawn((
+                SpatialBundle::from_transform(Transform::from_translation(config.simulation_to_world(pos))),
+                Obstacle::new(scale_shape(shape, world_scale)),
+            ));
+            if perch {
+                obstacle.insert(Perch);
+            }
+        }
+        return
+    };
+
+    let material = materials.add(Color::rgb(0.5, 0.4, 0.3).into());
+    for (pos, shape, perch) in shapes {
+        let shape = scale_shape(shape, world_scale);
+        let mesh = match shape {
+            ObstacleShape::Sphere { radius } => Mesh::from(shape::UVSphere { radius, ..default() }),
+            ObstacleShape::Cuboid { half_extents } => Mesh::from(shape::Box::new(half_extents.x * 2.0, half_extents.y * 2.0, half_extents.z * 2.0)),
+            ObstacleShape::Capsule { half_height, radius } => Mesh::from(shape::Capsule { radius, depth: half_height * 2.0, ..default() }),
+            ObstacleShape::Mesh => unreachable!(),
+        };
+        let mut obstacle = commands.spawn((
+            PbrBundle {
+                mesh: meshes.add(mesh),
+                material: material.clone(),
+                transform: Transform::from_translation(config.simulation_to_world(pos)),
+                ..default()
+            },
+            Obstacle::new(shape),
+        ));
+        if perch {
+            obstacle.insert(Perch);
+        }
+    }
+
+    //A ring the boids can fly through, which only the sdf can describe.
+    commands.spawn((
+        PbrBundle {
+            mesh: meshes.add(Mesh::from(shape::Torus { radius: 0.3 * world_scale, ring_radius: 0.05 * world_scale, ..default() })),
+            material,
+            transform: Transform::from_translation(config.simulation_to_world(Vec3::new(0.0, 0.2, 0.0))).with_rotation(Quat::from_rotation_x(0.5)),
+            ..default()
+        },
+        Obstacle::new(ObstacleShape::Mesh),
+    ));
+}
+
+//The demo shapes are given in the -1 to 1 simulation space.
+fn scale_shape(shape: ObstacleShape, scale: f32) -> ObstacleShape {
+    match shape {
+        ObstacleShape::Sphere { radius } => ObstacleShape::Sphere { radius: radius * scale },
+        ObstacleShape::Cuboid { half_extents } => ObstacleShape::Cuboid { half_extents: half_extents * scale },
+        ObstacleShape::Capsule { half_height, radius } => ObstacleShape::Capsule { half_height: half_height * scale, radius: radius * scale },
+        ObstacleShape::Mesh => ObstacleShape::Mesh,
+    }
+}
+
+fn collect_obstacles(
+    config: Res<SimulationConfig>,
+    query: Query<(&Transform, &Obstacle, Has<Perch>)>,
+    mut obstacles: ResMut<Obstacles>,
+) {
+    let world_scale = config.world_scale();
+    obstacles.0 = query.iter()
+        .filter_map(|(transform, obstacle, perch)| {
+            let center = config.world_to_simulation(transform.translation);
+            let scale = transform.scale / world_scale;
+            let (a, b) = match obstacle.shape {
+                ObstacleShape::Sphere { radius } => (center, Vec4::new(0.0, 0.0, 0.0, radius * scale.max_element())),
+                ObstacleShape::Cuboid { half_extents } => (center, (half_extents * scale).extend(0.0)),
+                ObstacleShape::Capsule { half_height, radius } => {
+                    let axis = transform.rotation * Vec3::Y * half_height * scale.y;
+                    (center - axis, (center + axis).extend(radius * scale.x.max(scale.z)))
+                },
+                ObstacleShape::Mesh => return None,
+            };
+            Some(ObstacleData {
+                a: a.extend(obstacle.shape.shader_value() as f32),
+                b,
+                margin: obstacle.margin,
+                strength: obstacle.strength,
+                perch: perch as u32,
+                _padding0: 0.0,
+            })
+        })
+        .collect();
+}
+
+//The state of the bake of the sdf, which runs on another thread.
+#[derive(Default)]
+struct SdfBake {
+    //The mesh obstacles have changed since the last bake was started.
+    pending: bool,
+    //Set to the baked sdf by the task, None while no bake is running.
+    running: Option<Arc<Mutex<Option<ObstacleSdf>>>>,
+}
+
+//Bakes the mesh obstacles into the sdf when they have changed, waiting until all of their meshes have been loaded.
+//Only one bake runs at a time, the sdf is replaced once it is done.
+#[allow(clippy::type_complexity)]
+fn bake_obstacle_sdf(
+    changed: Query<&Obstacle, Or<(Changed<Obstacle>, Changed<Transform>, Changed<Handle<Mesh>>)>>,
+    mut removed: RemovedComponents<Obstacle>,
+    obstacles: Query<(&Obstacle, &Transform, &Handle<Mesh>)>,
+    meshes: Option<Res<Assets<Mesh>>>,
+    config: Res<SimulationConfig>,
+    mut sdf: ResMut<ObstacleSdf>,
+    mut bake: Local<SdfBake>,
+) {
+    if changed.iter().any(|obstacle| matches!(obstacle.shape, ObstacleShape::Mesh)) || removed.read().count() > 0 {
+        bake.pending = true;
+    }
+    if let Some(running) = &bake.running {
+        let Some(baked) = running.lock().unwrap().take() else {
+            return
+        };
+        *sdf = baked;
+        bake.running = None;
+    }
+    let Some(meshes) = meshes else {
+        return
+    };
+    if !bake.pending {
+        return
+    }
+
+    let mut triangles = Vec::new();
+    let mut margin: f32 = 0.0;
+    let mut strength: f32 = 0.0;
+    for (obstacle, transform, handle) in &obstacles {
+        if !matches!(obstacle.shape, ObstacleShape::Mesh) {
+            continue;
+        }
+        let Some(mesh) = meshes.get(handle) else {
+            return
+        };
+        triangles.extend(mesh_triangles(mesh, transform, &config));
+        margin = margin.max(obstacle.margin);
+        strength = strength.max(obstacle.strength);
+    }
+    bake.pending = false;
+
+    if triangles.is_empty() {
+        *sdf = ObstacleSdf::default();
+        return
+    }
+    info!("Baking {} triangles into the obstacle sdf", triangles.len());
+    let running = Arc::new(Mutex::new(None));
+    bake.running = Some(running.clone());
+    AsyncComputeTaskPool::get().spawn(async move {
+        let baked = ObstacleSdf {
+            resolution: SDF_RESOLUTION,
+            distances: bake_sdf(&triangles, SDF_RESOLUTION),
+            margin,
+            strength,
+        };
+        *running.lock().unwrap() = Some(baked);
+    }).detach();
+}
+
+//The triangles of the mesh in the -1 to 1 simulation space.
+fn mesh_triangles(mesh: &Mesh, transform: &Transform, config: &SimulationConfig) -> Vec<[Vec3; 3]> {
+    let Some(VertexAttributeValues::Float32x3(positions)) = mesh.attribute(Mesh::ATTRIBUTE_POSITION) else {
+        warn!("Obstacle mesh without positions can't be baked");
+        return Vec::new()
+    };
+    if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+        warn!("Only obstacle meshes with a triangle list can be baked");
+        return Vec::new()
+    }
+    let matrix = transform.compute_matrix();
+    let positions: Vec<Vec3> = positions.iter()
+        .map(|pos| config.world_to_simulation(matrix.transform_point3(Vec3::from(*pos))))
+        .collect();
+    let indices: Vec<usize> = match mesh.indices() {
+        Some(indices) => indices.iter().collect(),
+        None => (0..positions.len()).collect(),
+    };
+    indices.chunks_exact(3)
+        .map(|triangle| [positions[triangle[0]], positions[triangle[1]], positions[triangle[2]]])
+        .collect()
+}
+
+//Only the voxels within SDF_BAND of a triangle are visited, so baking doesn't take every voxel times every triangle.
+//The sign comes from the side of the closest triangle the voxel is on.
+fn bake_sdf(triangles: &[[Vec3; 3]], resolution: u32) -> Vec<f32> {
+    let res = resolution as i32;
+    let voxel_size = 2.0 / resolution as f32;
+    let to_voxel = |pos: Vec3| ((pos + Vec3::ONE) / voxel_size).floor().as_ivec3();
+    let mut distances = vec![SDF_BAND; (res * res * res) as usize];
+
+    for triangle in triangles {
+        let normal = (triangle[1] - triangle[0]).cross(triangle[2] - triangle[0]).normalize_or_zero();
+        let min = triangle[0].min(triangle[1]).min(triangle[2]) - Vec3::splat(SDF_BAND);
+        let max = triangle[0].max(triangle[1]).max(triangle[2]) + Vec3::splat(SDF_BAND);
+        let low = to_voxel(min).max(IVec3::ZERO);
+        let high = to_voxel(max).min(IVec3::splat(res - 1));
+        for z in low.z..=high.z {
+            for y in low.y..=high.y {
+                for x in low.x..=high.x {
+                    let pos = (IVec3::new(x, y, z).as_vec3() + Vec3::splat(0.5)) * voxel_size - Vec3::ONE;
+                    let closest = closest_point_on_triangle(pos, triangle);
+                    let distance = pos.distance(closest);
+                    let index = (x + y * res + z * res * res) as usize;
+                    if distance < distances[index].abs() {
+                        distances[index] = if (pos - closest).dot(normal) < 0.0 { -distance } else { distance };
+                    }
+                }
+            }
+        }
+    }
+    distances
+}
+
+fn closest_point_on_triangle(p: Vec3, [a, b, c]: &[Vec3; 3]) -> Vec3 {
+    let (a, b, c) = (*a, *b, *c);
+    let ab = b - a;
+    let ac = c - a;
+    let ap = p - a;
+    let d1 = ab.dot(ap);
+    let d2 = ac.dot(ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return a;
+    }
+
+    let bp = p - b;
+    let d3 = ab.dot(bp);
+    let d4 = ac.dot(bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return b;
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return a + ab * (d1 / (d1 - d3));
+    }
+
+    let cp = p - c;
+    let d5 = ab.dot(cp);
+    let d6 = ac.dot(cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return c;
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return a + ac * (d2 / (d2 - d6));
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+
+    let denom = 1.0 / (va + vb + vc);
+    a + ab * (vb * denom) + ac * (vc * denom)
+}
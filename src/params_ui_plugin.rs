@@ -0,0 +1,350 @@
+//! This file is responsible for the panel that allows tuning the boid params while the application is running.
+//! Bevy ui does not come with sliders, so every slider is a button with a fill node inside of it.
+//! The position of the cursor relative to the button decides the new value of the param.
+//! The sliders change the params of one species at a time, pressing the species button selects the next species.
+//! https://github.com/bevyengine/bevy/blob/release-0.12.1/examples/ui/relative_cursor_position.rs
+//! Was used to understand how to get the cursor position relative to a node.
+
+use bevy::{prelude::*, ui::RelativeCursorPosition};
+use bevy_panorbit_camera::PanOrbitCamera;
+use crate::shared::*;
+
+pub struct ParamsUiPlugin;
+
+impl Plugin for ParamsUiPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Flocks>()
+           .init_resource::<SelectedSpecies>()
+           .add_systems(Startup, setup)
+           .add_systems(Update, (select_species, drag_sliders, update_sliders).chain());
+    }
+}
+
+// The params of BoidParams that have a slider in the panel.
+#[derive(Clone, Copy)]
+enum ParamField {
+    Speed,
+    SeperationDistance,
+    AlignmentDistance,
+    CohesionDistance,
+    SeperationScale,
+    AlignmentScale,
+    CohesionScale,
+    ViewAngle,
+    BlindSpot,
+    Mass,
+    MaxForce,
+    MinSpeed,
+    MaxTurnRate,
+    FlapRate,
+}
+
+impl ParamField {
+    const ALL: [ParamField; 14] = [
+        ParamField::Speed,
+        ParamField::SeperationDistance,
+        ParamField::AlignmentDistance,
+        ParamField::CohesionDistance,
+        ParamField::SeperationScale,
+        ParamField::AlignmentScale,
+        ParamField::CohesionScale,
+        ParamField::ViewAngle,
+        ParamField::BlindSpot,
+        ParamField::Mass,
+        ParamField::MaxForce,
+        ParamField::MinSpeed,
+        ParamField::MaxTurnRate,
+        ParamField::FlapRate,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            ParamField::Speed => "Speed",
+            ParamField::SeperationDistance => "Seperation distance",
+            ParamField::AlignmentDistance => "Alignment distance",
+            ParamField::CohesionDistance => "Cohesion distance",
+            ParamField::SeperationScale => "Seperation scale",
+            ParamField::AlignmentScale => "Alignment scale",
+            ParamField::CohesionScale => "Cohesion scale",
+            ParamField::ViewAngle => "View angle",
+            ParamField::BlindSpot => "Blind spot",
+            ParamField::Mass => "Mass",
+            ParamField::MaxForce => "Max force",
+            ParamField::MinSpeed => "Min speed",
+            ParamField::MaxTurnRate => "Max turn rate",
+            ParamField::FlapRate => "Flap rate",
+        }
+    }
+
+    //Min and max value of the slider.
+    //The neighbours are only searched in the cells around a boid, so the distances can't go past the size of a cell.
+    fn range(&self, config: &SimulationConfig) -> (f32, f32) {
+        match self {
+            ParamField::Speed
+            | ParamField::MinSpeed => (0.0, 2.0),
+            ParamField::SeperationDistance
+            | ParamField::AlignmentDistance
+            | ParamField::CohesionDistance => (0.0, config.cell_size),
+            ParamField::SeperationScale
+            | ParamField::AlignmentScale
+            | ParamField::CohesionScale => (0.0, 5.0),
+            ParamField::ViewAngle
+            | ParamField::BlindSpot => (0.0, 360.0),
+            ParamField::Mass => (0.1, 5.0),
+            ParamField::MaxForce => (0.0, 10.0),
+            ParamField::MaxTurnRate => (0.0, 1080.0),
+            ParamField::FlapRate => (0.0, 15.0),
+        }
+    }
+
+    fn get(&self, params: &BoidParams) -> f32 {
+        match self {
+            ParamField::Speed => params.speed,
+            ParamField::SeperationDistance => params.seperation_distance,
+            ParamField::AlignmentDistance => params.alignment_distance,
+            ParamField::CohesionDistance => params.cohesion_distance,
+            ParamField::SeperationScale => params.seperation_scale,
+            ParamField::AlignmentScale => params.alignment_scale,
+            ParamField::CohesionScale => params.cohesion_scale,
+            ParamField::ViewAngle => params.view_angle,
+            ParamField::BlindSpot => params.blind_spot,
+            ParamField::Mass => params.mass,
+            ParamField::MaxForce => params.max_force,
+            ParamField::MinSpeed => params.min_speed,
+            ParamField::MaxTurnRate => params.max_turn_rate,
+            ParamField::FlapRate => params.flap_rate,
+        }
+    }
+
+    fn get_mut<'a>(&self, params: &'a mut BoidParams) -> &'a mut f32 {
+        match self {
+            ParamField::Speed => &mut params.speed,
+            ParamField::SeperationDistance => &mut params.seperation_distance,
+            ParamField::AlignmentDistance => &mut params.alignment_distance,
+            ParamField::CohesionDistance => &mut params.cohesion_distance,
+            ParamField::SeperationScale => &mut params.seperation_scale,
+            ParamField::AlignmentScale => &mut params.alignment_scale,
+            ParamField::CohesionScale => &mut params.cohesion_scale,
+            ParamField::ViewAngle => &mut params.view_angle,
+            ParamField::BlindSpot => &mut params.blind_spot,
+            ParamField::Mass => &mut params.mass,
+            ParamField::MaxForce => &mut params.max_force,
+            ParamField::MinSpeed => &mut params.min_speed,
+            ParamField::MaxTurnRate => &mut params.max_turn_rate,
+            ParamField::FlapRate => &mut params.flap_rate,
+        }
+    }
+
+    //Value of the param between 0 and 1, used for the width of the fill node.
+    fn normalized(&self, params: &BoidParams, config: &SimulationConfig) -> f32 {
+        let (min, max) = self.range(config);
+        ((self.get(params) - min) / (max - min)).clamp(0.0, 1.0)
+    }
+}
+
+//Index of the species in the Flocks whose params are shown by the sliders.
+#[derive(Resource, Default)]
+struct SelectedSpecies(usize);
+
+impl SelectedSpecies {
+    //The amount of species can change when a snapshot is loaded, without any species nothing is selected.
+    fn index(&self, flocks: &Flocks) -> Option<usize> {
+        flocks.species.len().checked_sub(1).map(|last| self.0.min(last))
+    }
+
+    fn params<'a>(&self, flocks: &'a Flocks) -> Option<&'a BoidParams> {
+        Some(&flocks.species[self.index(flocks)?].params)
+    }
+
+    fn params_mut<'a>(&self, flocks: &'a mut Flocks) -> Option<&'a mut BoidParams> {
+        let index = self.index(flocks)?;
+        Some(&mut flocks.species[index].params)
+    }
+}
+
+#[derive(Component)]
+struct SpeciesButton;
+
+#[derive(Component)]
+struct SpeciesLabel;
+
+#[derive(Component)]
+struct ParamSlider(ParamField);
+
+#[derive(Component)]
+struct ParamSliderFill(ParamField);
+
+#[derive(Component)]
+struct ParamLabel(ParamField);
+
+fn setup(mut commands: Commands, flocks: Res<Flocks>, selected: Res<SelectedSpecies>, config: Res<SimulationConfig>) {
+    //Without any species the sliders show the default params until there are species again.
+    let default_params = BoidParams::default();
+    let params = selected.params(&flocks).unwrap_or(&default_params);
+    commands.spawn(NodeBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            left: Val::Px(10.0),
+            top: Val::Px(10.0),
+            flex_direction: FlexDirection::Column,
+            row_gap: Val::Px(4.0),
+            padding: UiRect::all(Val::Px(8.0)),
+            ..default()
+        },
+        background_color: Color::rgba(0.0, 0.0, 0.0, 0.6).into(),
+        ..default()
+    }).with_children(|panel| {
+        panel.spawn((
+            ButtonBundle {
+                style: Style {
+                    width: Val::Px(200.0),
+                    padding: UiRect::all(Val::Px(2.0)),
+                    ..default()
+                },
+                background_color: Color::DARK_GRAY.into(),
+                ..default()
+            },
+            SpeciesButton,
+        )).with_children(|button| {
+            button.spawn((
+                TextBundle::from_section(
+                    species_text(&flocks, &selected),
+                    TextStyle {
+                        font_size: 14.0,
+                        color: Color::WHITE,
+                        ..default()
+                    },
+                ),
+                SpeciesLabel,
+            ));
+        });
+
+        for field in ParamField::ALL {
+            panel.spawn((
+                TextBundle::from_section(
+                    label_text(field, params),
+                    TextStyle {
+                        font_size: 14.0,
+                        color: Color::WHITE,
+                        ..default()
+                    },
+                ),
+                ParamLabel(field),
+            ));
+
+            panel.spawn((
+                ButtonBundle {
+                    style: Style {
+                        width: Val::Px(200.0),
+                        height: Val::Px(12.0),
+                        ..default()
+                    },
+                    background_color: Color::DARK_GRAY.into(),
+                    ..default()
+                },
+                RelativeCursorPosition::default(),
+                ParamSlider(field),
+            )).with_children(|slider| {
+                slider.spawn((
+                    NodeBundle {
+                        style: Style {
+                            width: Val::Percent(field.normalized(params, &config) * 100.0),
+                            height: Val::Percent(100.0),
+                            ..default()
+                        },
+                        background_color: Color::WHITE.into(),
+                        ..default()
+                    },
+                    ParamSliderFill(field),
+                ));
+            });
+        }
+    });
+}
+
+fn label_text(field: ParamField, params: &BoidParams) -> String {
+    format!("{}: {:.3}", field.label(), field.get(params))
+}
+
+fn species_text(flocks: &Flocks, selected: &SelectedSpecies) -> String {
+    let Some(index) = selected.index(flocks) else {
+        return String::from("Species: none");
+    };
+    format!("Species: {} ({}/{})", flocks.species[index].name, index + 1, flocks.species.len())
+}
+
+//Select the next species when the species button is pressed.
+fn select_species(
+    buttons: Query<&Interaction, (Changed<Interaction>, With<SpeciesButton>)>,
+    flocks: Res<Flocks>,
+    mut selected: ResMut<SelectedSpecies>,
+) {
+    for interaction in &buttons {
+        if *interaction == Interaction::Pressed && !flocks.species.is_empty() {
+            selected.0 = (selected.0 + 1) % flocks.species.len();
+        }
+    }
+}
+
+//Set the params based on where the slider is pressed, the camera is disabled while dragging so it doesn't orbit along.
+fn drag_sliders(
+    sliders: Query<(&Interaction, &RelativeCursorPosition, &ParamSlider)>,
+    mut flocks: ResMut<Flocks>,
+    selected: Res<SelectedSpecies>,
+    config: Res<SimulationConfig>,
+    mut cameras: Query<&mut PanOrbitCamera>,
+) {
+    let mut dragging = false;
+    for (interaction, cursor, slider) in &sliders {
+        if *interaction != Interaction::Pressed {
+            continue;
+        }
+        dragging = true;
+        let Some(normalized) = cursor.normalized else {
+            continue;
+        };
+        let Some(params) = selected.params(&flocks) else {
+            continue;
+        };
+        let (min, max) = slider.0.range(&config);
+        let value = min + normalized.x.clamp(0.0, 1.0) * (max - min);
+        //Only taken mutably when the value changes, so the params are not uploaded every frame.
+        if slider.0.get(params) != value {
+            if let Some(params) = selected.params_mut(&mut flocks) {
+                *slider.0.get_mut(params) = value;
+            }
+        }
+    }
+
+    for mut camera in &mut cameras {
+        if camera.enabled == dragging {
+            camera.enabled = !dragging;
+        }
+    }
+}
+
+//Update the fill and labels of the sliders whenever the params, the selected species or the size of the cells change.
+fn update_sliders(
+    flocks: Res<Flocks>,
+    selected: Res<SelectedSpecies>,
+    config: Res<SimulationConfig>,
+    mut fills: Query<(&mut Style, &ParamSliderFill)>,
+    mut labels: Query<(&mut Text, &ParamLabel), Without<SpeciesLabel>>,
+    mut species_labels: Query<&mut Text, With<SpeciesLabel>>,
+) {
+    if !flocks.is_changed() && !selected.is_changed() && !config.is_changed() {
+        return;
+    }
+    for mut text in &mut species_labels {
+        text.sections[0].value = species_text(&flocks, &selected);
+    }
+    let Some(params) = selected.params(&flocks) else {
+        return;
+    };
+    for (mut style, fill) in &mut fills {
+        style.width = Val::Percent(fill.0.normalized(params, &config) * 100.0);
+    }
+    for (mut text, label) in &mut labels {
+        text.sections[0].value = label_text(label.0, params);
+    }
+}
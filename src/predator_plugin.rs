@@ -0,0 +1,129 @@
+//! This file is responsible for the predators the boids flee from, ie. a hawk diving through the flock.
+//! Every frame the Predator components are collected into the Predators resource, which both simulation backends read.
+//! Predators can be moved by any system, or follow a scripted PredatorPath.
+//! Use `--predators 2` to let a couple of hawks fly through the box on random paths.
+
+use bevy::prelude::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use crate::shared::*;
+
+pub struct PredatorPlugin {
+    //Amount of hawks that are spawned on a random path at the start.
+    pub hawks: u32,
+}
+
+impl PredatorPlugin {
+    //Reads the amount of hawks from the command line, ie. `cargo run -- --predators 2`
+    pub fn from_args() -> Self {
+        let args: Vec<String> = std::env::args().collect();
+        let hawks = args.windows(2)
+            .find(|pair| pair[0] == "--predators")
+            .and_then(|pair| pair[1].parse().ok())
+            .unwrap_or(0);
+        Self { hawks }
+    }
+}
+
+impl Plugin for PredatorPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Predators>()
+            .insert_resource(Hawks(self.hawks))
+            .add_systems(Startup, spawn_hawks)
+            .add_systems(Update, follow_predator_paths)
+            .add_systems(PostUpdate, collect_predators);
+    }
+}
+
+#[derive(Resource)]
+struct Hawks(u32);
+
+//Scripted path of a predator, it flies from waypoint to waypoint and starts over after the last one.
+#[derive(Component, Clone, Debug)]
+pub struct PredatorPath {
+    //Positions in the world, the same space as the Transform of the predator.
+    pub waypoints: Vec<Vec3>,
+    //World units per second.
+    pub speed: f32,
+    pub next: usize,
+}
+
+//The hawks are only drawn when there is a renderer, in headless mode there are no meshes.
+fn spawn_hawks(
+    mut commands: Commands,
+    hawks: Res<Hawks>,
+    config: Res<SimulationConfig>,
+    meshes: Option<ResMut<Assets<Mesh>>>,
+    materials: Option<ResMut<Assets<StandardMaterial>>>,
+) {
+    if hawks.0 == 0 {
+        return
+    }
+    //Seeded, so a run with the same seed has the same hawks.
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let world_scale = config.world_scale();
+    let look = meshes.zip(materials).map(|(mut meshes, mut materials)| (
+        meshes.add(Mesh::from(shape::UVSphere { radius: 0.01 * world_scale, ..default() })),
+        materials.add(Color::rgb(0.8, 0.1, 0.1).into()),
+    ));
+
+    for _ in 0..hawks.0 {
+        let waypoints: Vec<Vec3> = (0..4)
+            .map(|_| config.simulation_to_world(Vec3::new(rng.gen_range(-0.9..0.9), rng.gen_range(-0.9..0.9), rng.gen_range(-0.9..0.9))))
+            .collect();
+        let mut hawk = commands.spawn((
+            SpatialBundle::from_transform(Transform::from_translation(waypoints[0])),
+            Predator::default(),
+            PredatorPath {
+                waypoints,
+                speed: 0.4 * world_scale,
+                next: 1,
+            },
+        ));
+        if let Some((mesh, material)) = &look {
+            hawk.insert((mesh.clone(), material.clone()));
+        }
+    }
+}
+
+fn follow_predator_paths(time: Res<Time>, mut predators: Query<(&mut Transform, &mut PredatorPath)>) {
+    for (mut transform, mut path) in &mut predators {
+        if path.waypoints.is_empty() {
+            continue;
+        }
+        let mut distance = path.speed * time.delta_seconds();
+        //A fast predator can pass multiple waypoints in a single frame.
+        while distance > 0.0 {
+            let target = path.waypoints[path.next % path.waypoints.len()];
+            let to_target = target - transform.translation;
+            if to_target.length() > distance {
+                transform.translation += to_target.normalize() * distance;
+                transform.look_to(to_target, Vec3::Y);
+                break;
+            }
+            transform.translation = target;
+            distance -= to_target.length();
+            path.next = (path.next + 1) % path.waypoints.len();
+            if path.waypoints.len() == 1 {
+                break;
+            }
+        }
+    }
+}
+
+//After the predators have been moved, so the compute shaders use their positions of this frame.
+//The cpu backend runs in Update, so it flees from the positions of the previous frame.
+fn collect_predators(
+    config: Res<SimulationConfig>,
+    query: Query<(&Transform, &Predator)>,
+    mut predators: ResMut<Predators>,
+) {
+    predators.0 = query.iter()
+        .map(|(transform, predator)| PredatorData {
+            pos: config.world_to_simulation(transform.translation).extend(predator.panic_radius),
+            flee_strength: predator.flee_strength,
+            speed_boost: predator.speed_boost,
+            _padding0: 0.0,
+            _padding1: 0.0,
+        })
+        .collect();
+}
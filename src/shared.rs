@@ -1,37 +1,310 @@
 //! This file holds some of the structs and values we have used in multiple files or to easily modify it.
-//! ie. the NUM_BOIDS value is responsible for the amount of crows that will be rendered.
+//! ie. the SimulationConfig resource is responsible for the amount of crows that will be rendered.
 //! It also holds the grid struct we use in order to speed up the computation of the boid algorithm.
 
 use bevy::{
     prelude::*,
     core::Pod,
-    render::render_resource::ShaderType,
+    render::render_resource::{Buffer, ShaderType},
     render::extract_component::{ExtractComponent, ExtractComponentPlugin},
+    render::extract_resource::ExtractResource,
     ecs::{
         query::QueryItem,
         system::{lifetimeless::*, SystemParamItem},
     }
 };
 use bytemuck::Zeroable;
+use crate::spawn::SpawnDistribution;
 
-pub const NUM_BOIDS: u32 = 128000;
-pub const BOX_SIZE: f32 = 40.;
-//Grid_size * cell_size should be 2.0
-pub const GRID_SIZE: f32 = 20.0;
-pub const CELL_SIZE: f32 = 0.1;
+// Settings of the simulation that used to be constants, read by the compute plugin, the instancing plugin and the scene setup.
+// Insert it before adding the plugins to change it, otherwise the default is used.
+#[derive(Resource, Clone, Debug, PartialEq, ExtractResource)]
+pub struct SimulationConfig {
+    pub num_boids: u32,
+    pub box_size: f32,
+    //Grid_size * cell_size should be 2.0
+    pub grid_size: u32,
+    pub cell_size: f32,
+    //When set, the simulation advances in steps of this size, running multiple steps in a frame if needed.
+    //Otherwise a single step with the frame delta time is run every frame.
+    pub fixed_timestep: Option<f32>,
+    //The most time simulated in a single frame, so a slow frame doesn't make the boids jump.
+    pub max_timestep: f32,
+    //What happens to the boids at the edges of the -1 to 1 simulation space.
+    pub boundary_mode: BoundaryMode,
+    pub backend: SimulationBackend,
+    //When set, the simulation stops after this many steps.
+    pub steps: Option<u64>,
+    //Seed of the random number generator the boids are spawned with, the same seed spawns the same boids.
+    pub seed: u64,
+    //Where the boids are placed at the start.
+    pub spawn: SpawnDistribution,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            num_boids: 128000,
+            box_size: 40.,
+            grid_size: 20,
+            cell_size: 0.1,
+            fixed_timestep: None,
+            max_timestep: 0.05,
+            boundary_mode: BoundaryMode::default(),
+            backend: SimulationBackend::default(),
+            steps: None,
+            seed: rand::random(),
+            spawn: SpawnDistribution::default(),
+        }
+    }
+}
+
+impl SimulationConfig {
+    //The neighbours are searched in the 3x3x3 cells around a boid, a smaller grid would visit the same cells twice.
+    pub const MIN_GRID_SIZE: u32 = 3;
+
+    pub fn with_num_boids(mut self, num_boids: u32) -> Self {
+        self.num_boids = num_boids;
+        self
+    }
+
+    pub fn with_box_size(mut self, box_size: f32) -> Self {
+        self.box_size = box_size;
+        self
+    }
+
+    //Also sets the cell size, so the grid keeps covering the -1 to 1 simulation space.
+    pub fn with_grid_size(mut self, grid_size: u32) -> Self {
+        self.grid_size = grid_size;
+        self.cell_size = 2.0 / grid_size as f32;
+        self
+    }
+
+    pub fn with_fixed_timestep(mut self, fixed_timestep: f32) -> Self {
+        self.fixed_timestep = Some(fixed_timestep);
+        self
+    }
+
+    pub fn with_max_timestep(mut self, max_timestep: f32) -> Self {
+        self.max_timestep = max_timestep;
+        self
+    }
+
+    pub fn with_boundary_mode(mut self, boundary_mode: BoundaryMode) -> Self {
+        self.boundary_mode = boundary_mode;
+        self
+    }
+
+    pub fn with_backend(mut self, backend: SimulationBackend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub fn with_steps(mut self, steps: u64) -> Self {
+        self.steps = Some(steps);
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_spawn(mut self, spawn: SpawnDistribution) -> Self {
+        self.spawn = spawn;
+        self
+    }
+
+    //Reads the config from the command line, ie. `cargo run -- --boids 1000 --box-size 20 --grid-size 10`
+    //Unknown or missing arguments fall back to the default values.
+    pub fn from_args() -> Self {
+        let mut config = Self::default();
+        let args: Vec<String> = std::env::args().collect();
+        for pair in args.windows(2) {
+            match pair[0].as_str() {
+                "--boids" => if let Ok(val) = pair[1].parse() {
+                    config = config.with_num_boids(val);
+                },
+                "--box-size" => if let Ok(val) = pair[1].parse() {
+                    config = config.with_box_size(val);
+                },
+                "--grid-size" => if let Ok(val) = pair[1].parse::<u32>() {
+                    if val < Self::MIN_GRID_SIZE {
+                        exit_with_error(&format!("--grid-size has to be at least {}, got {val}", Self::MIN_GRID_SIZE));
+                    }
+                    config = config.with_grid_size(val);
+                },
+                "--fixed-timestep" => if let Ok(val) = pair[1].parse::<f32>() {
+                    //A step of zero would never consume the accumulated time.
+                    if val.is_nan() || val <= 0.0 {
+                        exit_with_error(&format!("--fixed-timestep has to be larger than 0, got {val}"));
+                    }
+                    config = config.with_fixed_timestep(val);
+                },
+                "--max-timestep" => if let Ok(val) = pair[1].parse() {
+                    config = config.with_max_timestep(val);
+                },
+                "--boundary" => if let Some(val) = BoundaryMode::from_arg(&pair[1]) {
+                    config = config.with_boundary_mode(val);
+                },
+                "--steps" => if let Ok(val) = pair[1].parse() {
+                    config = config.with_steps(val);
+                },
+                "--seed" => if let Ok(val) = pair[1].parse() {
+                    config = config.with_seed(val);
+                },
+                "--spawn" => if let Some(val) = SpawnDistribution::from_arg(&pair[1]) {
+                    config = config.with_spawn(val);
+                },
+                "--spawn-file" => {
+                    config = config.with_spawn(SpawnDistribution::FromFile(pair[1].clone().into()));
+                },
+                "--backend" => match pair[1].as_str() {
+                    "gpu" => config = config.with_backend(SimulationBackend::Gpu),
+                    "cpu" => config = config.with_backend(SimulationBackend::Cpu),
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+        config
+    }
+
+    //Whether the buffers and shaders of a simulation with the other config can be reused, only the boids have to be replaced.
+    //The amount of boids doesn't matter, the buffers grow when more boids are uploaded.
+    pub fn same_simulation(&self, other: &SimulationConfig) -> bool {
+        self.grid_size == other.grid_size
+            && self.cell_size == other.cell_size
+            && self.boundary_mode == other.boundary_mode
+    }
+
+    pub fn total_cells(&self) -> usize {
+        (self.grid_size as usize).pow(3)
+    }
+
+    //Scale from the -1 to 1 simulation space to the world.
+    pub fn world_scale(&self) -> f32 {
+        self.box_size * 0.5
+    }
+
+    //The box is centered on the origin of the world, the instancing shader places the boids the same way.
+    pub fn simulation_to_world(&self, pos: Vec3) -> Vec3 {
+        pos * self.world_scale()
+    }
+
+    //Position in the -1 to 1 simulation space of a translation in the world, ie. of a predator.
+    pub fn world_to_simulation(&self, translation: Vec3) -> Vec3 {
+        translation / self.world_scale()
+    }
+}
+
+// How the boids are kept inside of the simulation space.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BoundaryMode {
+    //Boids that leave on one side come back on the other side, the world is toroidal.
+    #[default]
+    Wrap,
+    //Boids are reflected by the walls.
+    Bounce,
+    //Boids within margin of a wall are pushed back, stronger the closer they get.
+    SteerBack { margin: f32, strength: f32 },
+    //There are no walls, the grid follows the centroid of the flock instead.
+    Open,
+}
+
+impl BoundaryMode {
+    //Value of the mode in the Params uniform, has to match the constants in the shaders.
+    pub fn shader_value(&self) -> u32 {
+        match self {
+            BoundaryMode::Wrap => 0,
+            BoundaryMode::Bounce => 1,
+            BoundaryMode::SteerBack { .. } => 2,
+            BoundaryMode::Open => 3,
+        }
+    }
+
+    //Parses the `--boundary` argument, steer uses a default margin and strength.
+    pub fn from_arg(arg: &str) -> Option<Self> {
+        match arg {
+            "wrap" => Some(BoundaryMode::Wrap),
+            "bounce" => Some(BoundaryMode::Bounce),
+            "steer" => Some(BoundaryMode::SteerBack { margin: 0.1, strength: 2.0 }),
+            "open" => Some(BoundaryMode::Open),
+            _ => None,
+        }
+    }
+}
+
+// Where the boids are simulated, the cpu backend is a reference implementation of the compute shaders that doesn't need a GPU.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SimulationBackend {
+    #[default]
+    Gpu,
+    Cpu,
+}
+
+//Prints the error and exits, for arguments and files given on the command line that can't be used.
+pub fn exit_with_error(message: &str) -> ! {
+    eprintln!("error: {message}");
+    std::process::exit(1)
+}
+
+//Time that has passed but has not been simulated yet.
+#[derive(Resource, Default)]
+pub struct SimulationTime {
+    pub accumulated: f32,
+}
+
+impl SimulationTime {
+    //Takes the time that should be simulated this frame, returns the amount of steps and the delta time of every step.
+    pub fn consume(&mut self, config: &SimulationConfig, frame: &SimulationFrame) -> (u32, f32) {
+        let delta = self.accumulated.min(config.max_timestep);
+        let (steps, dt) = match config.fixed_timestep {
+            Some(step) => {
+                let steps = (delta / step) as u32;
+                self.accumulated = delta - steps as f32 * step;
+                (steps, step)
+            },
+            None => {
+                self.accumulated = 0.0;
+                if delta > 0.0 { (1, delta) } else { (0, 0.0) }
+            }
+        };
+        match config.steps {
+            Some(max_steps) => (max_steps.saturating_sub(frame.steps).min(steps as u64) as u32, dt),
+            None => (steps, dt),
+        }
+    }
+}
+
+//Amount of steps that have been simulated since the start, counted by the backend that runs them.
+#[derive(Resource, Default)]
+pub struct SimulationFrame {
+    pub steps: u64,
+}
 
 // Boid struct that gets transfered over to the compute shader which includes all the information needed for the computation.
+// The w of the position is the species of the boid, the w of the velocity stays 0.
+// The x of the state is what the boid is doing, 0 flying, 1 landing, 2 perched and 3 taking off.
+// The y of the state is the seconds it has been doing that, a spawned boid starts flying.
+// The z of the state is the phase of its wing beat between 0 and 1, which the instancing shader flaps the wings with.
+// The w of the state is a value between 0 and 1 that stays with the boid, see spawn::wait_seed.
 #[derive(ShaderType, Pod, Zeroable, Clone, Copy)]
 #[repr(C)]
 pub struct Boid {
     pub pos: Vec4,
     pub vel: Vec4,
+    pub state: Vec4,
 }
 
-// Params we can set in order to change the behaviour of the compute shader.
+// Most species that can be simulated together, the size of the arrays in the Params uniform.
+pub const MAX_SPECIES: usize = 4;
+
+// Params of a single species, the species of a boid picks which of these it uses.
+// Boid, SpeciesParams and Params have to match the structs in boid_types.wgsl, which every shader imports.
 #[derive(ShaderType, Pod, Zeroable, Clone, Copy)]
 #[repr(C)]
-pub struct Params {
+pub struct SpeciesParams {
     pub speed: f32,
     pub seperation_distance: f32,
     pub alignment_distance: f32,
@@ -39,10 +312,466 @@ pub struct Params {
     pub seperation_scale: f32,
     pub alignment_scale: f32,
     pub cohesion_scale: f32,
+    //Cosine of half the view angle, neighbours at a larger angle to the velocity are not seen.
+    pub view_cos: f32,
+    //Cosine of half the blind spot, neighbours within this angle right behind the boid are not seen.
+    pub blind_spot_cos: f32,
+    pub mass: f32,
+    pub max_force: f32,
+    pub min_speed: f32,
+    //In radians per second.
+    pub max_turn_rate: f32,
+    pub flap_rate: f32,
+    pub _padding1: f32,
+    pub _padding2: f32,
+}
+
+// Params we can set in order to change the behaviour of the compute shader.
+#[derive(ShaderType, Pod, Zeroable, Clone, Copy)]
+#[repr(C)]
+pub struct Params {
+    pub species: [SpeciesParams; MAX_SPECIES],
+    //interactions[a][b] is how a boid of species a reacts to a boid of species b, see SpeciesInteraction::shader_value
+    pub interactions: [UVec4; MAX_SPECIES],
     pub grid_size: f32,
     pub cell_size: f32,
+    pub boundary_mode: u32,
+    pub boundary_margin: f32,
+    pub boundary_strength: f32,
+    pub species_count: u32,
+    pub neighbour_mode: u32,
+    //The k of the topological rule, clamped to MAX_NEAREST.
+    pub nearest_count: u32,
+    pub steering_model: u32,
+    //0 when the boids don't perch.
+    pub flight_time: f32,
+    pub perch_time: f32,
+    pub landing_distance: f32,
 }
 
+// Boid behaviour of a species that can be tuned while the application is running.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoidParams {
+    pub speed: f32,
+    pub seperation_distance: f32,
+    pub alignment_distance: f32,
+    pub cohesion_distance: f32,
+    pub seperation_scale: f32,
+    pub alignment_scale: f32,
+    pub cohesion_scale: f32,
+    //Full angle of the cone in front of the boid in which it sees other boids, in degrees. 360 sees all around.
+    pub view_angle: f32,
+    //Full angle of the cone behind the boid in which it sees nothing, in degrees.
+    pub blind_spot: f32,
+    //The params below are only used by the Reynolds steering model, which uses speed as the max speed.
+    //A heavier boid accelerates slower.
+    pub mass: f32,
+    //Most force the rules combined can steer the boid with.
+    pub max_force: f32,
+    pub min_speed: f32,
+    //Degrees per second.
+    pub max_turn_rate: f32,
+    //Wing beats per second when flying at the speed of the species.
+    pub flap_rate: f32,
+}
+
+impl Default for BoidParams {
+    fn default() -> Self {
+        Self {
+            speed: 0.5,
+            seperation_distance: 0.02,
+            alignment_distance: 0.05,
+            cohesion_distance: 0.1,
+            seperation_scale: 1.0,
+            alignment_scale: 1.0,
+            cohesion_scale: 1.0,
+            view_angle: 360.0,
+            blind_spot: 0.0,
+            mass: 1.0,
+            max_force: 3.0,
+            min_speed: 0.2,
+            max_turn_rate: 270.0,
+            flap_rate: 3.0,
+        }
+    }
+}
+
+impl BoidParams {
+    pub fn to_species_params(&self) -> SpeciesParams {
+        SpeciesParams {
+            speed: self.speed,
+            seperation_distance: self.seperation_distance,
+            alignment_distance: self.alignment_distance,
+            cohesion_distance: self.cohesion_distance,
+            seperation_scale: self.seperation_scale,
+            alignment_scale: self.alignment_scale,
+            cohesion_scale: self.cohesion_scale,
+            view_cos: (self.view_angle.clamp(0.0, 360.0) * 0.5).to_radians().cos(),
+            blind_spot_cos: (self.blind_spot.clamp(0.0, 360.0) * 0.5).to_radians().cos(),
+            mass: self.mass,
+            max_force: self.max_force,
+            min_speed: self.min_speed,
+            max_turn_rate: self.max_turn_rate.to_radians(),
+            flap_rate: self.flap_rate,
+            _padding1: 0.0,
+            _padding2: 0.0,
+        }
+    }
+}
+
+// A kind of boid with its own behaviour and look, ie. crows and starlings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Species {
+    pub name: String,
+    pub params: BoidParams,
+    pub color: Color,
+    //Size of the mesh compared to the scale of the InstanceMaterialData.
+    pub size: f32,
+    //Opaque and masked species are drawn with the opaque meshes, blended species with the transparent ones.
+    //The other blend modes of bevy are drawn as Blend, the alpha comes from the color.
+    pub alpha_mode: AlphaMode,
+}
+
+impl Species {
+    pub fn crows() -> Self {
+        Self {
+            name: "crows".to_string(),
+            params: BoidParams::default(),
+            color: Color::hsla(0.0, 0.0, 0.0, 1.0),
+            size: 1.0,
+            alpha_mode: AlphaMode::Opaque,
+        }
+    }
+
+    //Smaller and faster than crows, flying closer together.
+    pub fn starlings() -> Self {
+        Self {
+            name: "starlings".to_string(),
+            params: BoidParams {
+                speed: 0.7,
+                seperation_distance: 0.015,
+                alignment_distance: 0.04,
+                cohesion_distance: 0.08,
+                seperation_scale: 1.0,
+                alignment_scale: 1.5,
+                cohesion_scale: 1.0,
+                //Their eyes are on the sides of their head, so they can't see right behind them.
+                view_angle: 360.0,
+                blind_spot: 60.0,
+                //Lighter and more agile.
+                mass: 0.6,
+                max_turn_rate: 360.0,
+                //Small wings beat a lot faster.
+                flap_rate: 8.0,
+                ..BoidParams::default()
+            },
+            color: Color::hsla(280.0, 0.3, 0.25, 1.0),
+            size: 0.6,
+            alpha_mode: AlphaMode::Opaque,
+        }
+    }
+
+    //Value of the alpha mode in the instancing uniform and the snapshots, with the cutoff of a mask.
+    //Has to match the constants in instancing.wgsl
+    pub fn alpha_shader_value(&self) -> (u32, f32) {
+        match self.alpha_mode {
+            AlphaMode::Opaque => (0, 0.0),
+            AlphaMode::Mask(cutoff) => (1, cutoff),
+            _ => (2, 0.0),
+        }
+    }
+
+    pub fn alpha_mode_from_shader_value(value: u32, cutoff: f32) -> Option<AlphaMode> {
+        match value {
+            0 => Some(AlphaMode::Opaque),
+            1 => Some(AlphaMode::Mask(cutoff)),
+            2 => Some(AlphaMode::Blend),
+            _ => None,
+        }
+    }
+
+    //Parses a species of the `--species` argument.
+    pub fn from_arg(arg: &str) -> Option<Self> {
+        match arg {
+            "crows" => Some(Species::crows()),
+            "starlings" => Some(Species::starlings()),
+            _ => None,
+        }
+    }
+}
+
+// How a boid reacts to the boids of another species.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SpeciesInteraction {
+    //The other boids are not seen at all.
+    Ignore,
+    //The other boids are only kept away from, within the cohesion distance.
+    Avoid,
+    //The other boids are flocked with as if they are the same species.
+    #[default]
+    Flock,
+}
+
+impl SpeciesInteraction {
+    //Value of the interaction in the Params uniform, has to match the constants in the shaders.
+    pub fn shader_value(&self) -> u32 {
+        match self {
+            SpeciesInteraction::Ignore => 0,
+            SpeciesInteraction::Avoid => 1,
+            SpeciesInteraction::Flock => 2,
+        }
+    }
+
+    pub fn from_shader_value(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(SpeciesInteraction::Ignore),
+            1 => Some(SpeciesInteraction::Avoid),
+            2 => Some(SpeciesInteraction::Flock),
+            _ => None,
+        }
+    }
+
+    //Parses the `--interaction` argument.
+    pub fn from_arg(arg: &str) -> Option<Self> {
+        match arg {
+            "ignore" => Some(SpeciesInteraction::Ignore),
+            "avoid" => Some(SpeciesInteraction::Avoid),
+            "flock" => Some(SpeciesInteraction::Flock),
+            _ => None,
+        }
+    }
+}
+
+//Most neighbours the topological rule can use, has to match MAX_NEAREST in boids_grid.wgsl.
+pub const MAX_NEAREST: u32 = 16;
+
+// Which neighbours a boid aligns with and flies towards, seperation always uses the seperation distance.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NeighbourMode {
+    //Every neighbour within the alignment and cohesion distance.
+    #[default]
+    Metric,
+    //The k nearest neighbours in the surrounding grid cells, no matter how far away they are.
+    //Real starlings interact with about 7 neighbours.
+    Topological { k: u32 },
+}
+
+impl NeighbourMode {
+    //Value of the mode in the Params uniform, has to match the constants in the shaders.
+    pub fn shader_value(&self) -> u32 {
+        match self {
+            NeighbourMode::Metric => 0,
+            NeighbourMode::Topological { .. } => 1,
+        }
+    }
+
+    pub fn nearest_count(&self) -> u32 {
+        match self {
+            NeighbourMode::Metric => 0,
+            NeighbourMode::Topological { k } => (*k).clamp(1, MAX_NEAREST),
+        }
+    }
+
+    pub fn from_shader_value(value: u32, k: u32) -> Option<Self> {
+        match value {
+            0 => Some(NeighbourMode::Metric),
+            1 => Some(NeighbourMode::Topological { k }),
+            _ => None,
+        }
+    }
+
+    //Switches between the rules, the topological rule uses 7 neighbours like starlings.
+    pub fn toggled(&self) -> Self {
+        match self {
+            NeighbourMode::Metric => NeighbourMode::Topological { k: 7 },
+            NeighbourMode::Topological { .. } => NeighbourMode::Metric,
+        }
+    }
+}
+
+// How the rules change the velocity of a boid.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SteeringModel {
+    //The rules are added to the velocity, which is then set to the speed of the species.
+    //Every boid flies at the same speed and can turn around instantly.
+    #[default]
+    Direct,
+    //The rules are a force limited to the max force that accelerates the mass of the boid.
+    //The speed stays between the min speed and the speed, and the boid turns at most the max turn rate.
+    //https://www.red3d.com/cwr/steer/gdc99/
+    Reynolds,
+}
+
+impl SteeringModel {
+    //Value of the model in the Params uniform, has to match the constants in the shaders.
+    pub fn shader_value(&self) -> u32 {
+        match self {
+            SteeringModel::Direct => 0,
+            SteeringModel::Reynolds => 1,
+        }
+    }
+
+    pub fn from_shader_value(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(SteeringModel::Direct),
+            1 => Some(SteeringModel::Reynolds),
+            _ => None,
+        }
+    }
+
+    //Parses the `--steering` argument.
+    pub fn from_arg(arg: &str) -> Option<Self> {
+        match arg {
+            "direct" => Some(SteeringModel::Direct),
+            "reynolds" => Some(SteeringModel::Reynolds),
+            _ => None,
+        }
+    }
+
+    pub fn toggled(&self) -> Self {
+        match self {
+            SteeringModel::Direct => SteeringModel::Reynolds,
+            SteeringModel::Reynolds => SteeringModel::Direct,
+        }
+    }
+}
+
+// When boids land on the obstacles with a Perch component and how long they stay there.
+// Every boid waits between half and one and a half times the time, so they don't all land or take off at once.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Perching {
+    //Seconds a boid flies before it lands on the next perch it flies over.
+    pub flight_time: f32,
+    //Seconds a boid stays perched, unless it is disturbed by a predator or a neighbour taking off.
+    pub perch_time: f32,
+    //How close above a perch a boid has to be to land on it, in the -1 to 1 simulation space.
+    pub landing_distance: f32,
+}
+
+impl Default for Perching {
+    fn default() -> Self {
+        Self {
+            flight_time: 20.0,
+            perch_time: 10.0,
+            landing_distance: 0.3,
+        }
+    }
+}
+
+// The species that are simulated, the species of a boid is its index in species.
+// The compute plugin uploads the params of all species to the Params uniform whenever they change.
+#[derive(Resource, Clone, Debug, PartialEq, ExtractResource)]
+pub struct Flocks {
+    pub species: Vec<Species>,
+    //interactions[a][b] is how a boid of species a reacts to a boid of species b.
+    pub interactions: [[SpeciesInteraction; MAX_SPECIES]; MAX_SPECIES],
+    pub neighbour_mode: NeighbourMode,
+    pub steering_model: SteeringModel,
+    //None when the boids keep flying.
+    pub perching: Option<Perching>,
+}
+
+impl Default for Flocks {
+    fn default() -> Self {
+        Self::new(vec![Species::crows()])
+    }
+}
+
+impl Flocks {
+    //Boids flock with their own species and avoid the other species.
+    pub fn new(species: Vec<Species>) -> Self {
+        let mut interactions = [[SpeciesInteraction::Avoid; MAX_SPECIES]; MAX_SPECIES];
+        for (i, row) in interactions.iter_mut().enumerate() {
+            row[i] = SpeciesInteraction::Flock;
+        }
+        Self {
+            species,
+            interactions,
+            neighbour_mode: NeighbourMode::default(),
+            steering_model: SteeringModel::default(),
+            perching: None,
+        }
+    }
+
+    //Sets how every species reacts to the other species, a species always flocks with itself.
+    pub fn with_interaction(mut self, interaction: SpeciesInteraction) -> Self {
+        for (i, row) in self.interactions.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                if i != j {
+                    *value = interaction;
+                }
+            }
+        }
+        self
+    }
+
+    //Reads the species from the command line, ie. `cargo run -- --species crows,starlings --interaction ignore`
+    pub fn from_args() -> Self {
+        let mut flocks = Self::default();
+        let args: Vec<String> = std::env::args().collect();
+        if args.iter().any(|arg| arg == "--perching") {
+            flocks.perching = Some(Perching::default());
+        }
+        for pair in args.windows(2) {
+            if pair[0] == "--species" {
+                let species: Vec<Species> = pair[1].split(',').filter_map(Species::from_arg).collect();
+                if species.len() > MAX_SPECIES {
+                    warn!("Only {MAX_SPECIES} species can be simulated together, ignoring the rest");
+                }
+                if !species.is_empty() {
+                    flocks = Self::new(species.into_iter().take(MAX_SPECIES).collect());
+                }
+            }
+        }
+        //After the species, as those reset the interactions.
+        for pair in args.windows(2) {
+            if pair[0] == "--interaction" {
+                if let Some(val) = SpeciesInteraction::from_arg(&pair[1]) {
+                    flocks = flocks.with_interaction(val);
+                }
+            }
+            if pair[0] == "--steering" {
+                if let Some(model) = SteeringModel::from_arg(&pair[1]) {
+                    flocks.steering_model = model;
+                }
+            }
+            if pair[0] == "--topological" {
+                if let Ok(k) = pair[1].parse() {
+                    flocks.neighbour_mode = NeighbourMode::Topological { k };
+                }
+            }
+        }
+        flocks
+    }
+
+    //Combine the params of the species with the grid layout and boundary of the config into the struct the compute shader expects.
+    pub fn to_params(&self, config: &SimulationConfig) -> Params {
+        let (boundary_margin, boundary_strength) = match config.boundary_mode {
+            BoundaryMode::SteerBack { margin, strength } => (margin, strength),
+            _ => (0.0, 0.0),
+        };
+        let mut species = [BoidParams::default().to_species_params(); MAX_SPECIES];
+        for (params, s) in species.iter_mut().zip(&self.species) {
+            *params = s.params.to_species_params();
+        }
+        Params {
+            species,
+            interactions: self.interactions.map(|row| UVec4::from_array(row.map(|interaction| interaction.shader_value()))),
+            grid_size: config.grid_size as f32,
+            cell_size: config.cell_size,
+            boundary_mode: config.boundary_mode.shader_value(),
+            boundary_margin,
+            boundary_strength,
+            species_count: self.species.len().clamp(1, MAX_SPECIES) as u32,
+            neighbour_mode: self.neighbour_mode.shader_value(),
+            nearest_count: self.neighbour_mode.nearest_count(),
+            steering_model: self.steering_model.shader_value(),
+            flight_time: self.perching.map_or(0.0, |perching| perching.flight_time),
+            perch_time: self.perching.map_or(0.0, |perching| perching.perch_time),
+            landing_distance: self.perching.map_or(0.0, |perching| perching.landing_distance),
+        }
+    }
+}
 
 
 //Identifier in order to link the boids data to a texture.
@@ -108,31 +837,361 @@ impl Grid {
 
     pub fn cooridnate_to_grid_coordinate (&self, coordinate: f32) -> usize {
         //negative value would be -1 + 2 if size is say 4, resulting in 1, positive values will be 1 + 2 = 3, so i
-        let val = ((coordinate / self.cell_size) + (self.size as f32 * 0.5)) as usize % self.size;
-        val
+        //Coordinates outside of the grid end up in the cells at the edge, the same as in the grid_build shader.
+        let val = ((coordinate / self.cell_size) + (self.size as f32 * 0.5)) as usize;
+        val.min(self.size - 1)
         
     }
+
+    //Remove all crows from the grid, so it can be filled again for the next step.
+    pub fn clear (&mut self) {
+        for cell in self.grid.iter_mut().flatten().flatten() {
+            cell.crows.clear();
+        }
+    }
 }
 
 
-#[derive(Clone, Copy, Pod, Zeroable)]
-#[repr(C)]
-pub struct InstanceData {
-    pub position: Vec3,
+//How the boids of an instanced mesh are drawn, the positions come straight from the BoidBuffer.
+//The colour and size of every boid come from its species in the Flocks resource.
+#[derive(Component, Clone)]
+pub struct InstanceMaterialData {
     pub scale: f32,
-    pub vel: Vec4,
-    pub color: [f32; 4],
 }
 
-#[derive(Component, Deref)]
-pub struct InstanceMaterialData(pub Vec<InstanceData>);
-
 impl ExtractComponent for InstanceMaterialData {
     type Query = &'static InstanceMaterialData;
     type Filter = ();
     type Out = Self;
 
     fn extract_component(item: QueryItem<'_, Self::Query>) -> Option<Self> {
-        Some(InstanceMaterialData(item.0.clone()))
+        Some(item.clone())
+    }
+}
+
+//The storage buffer the compute plugin has last written the boids to, lives in the render world so the instancing plugin can draw from it.
+//Only the boids before the alive count are alive, length is the most boids that could be alive and is used as the amount of instances.
+#[derive(Resource, Clone)]
+pub struct BoidBuffer {
+    pub buffer: Buffer,
+    //Uniform buffer with a single u32
+    pub alive_count: Buffer,
+    pub length: u32,
+}
+
+//The boids are only copied back to the cpu when requested.
+//Set requested to true and boids will hold the boids of that frame a few frames later.
+#[derive(Resource, Default)]
+pub struct BoidReadback {
+    pub requested: bool,
+    pub boids: Option<Vec<Boid>>,
+    //The amount of steps that had been simulated when the boids were read back.
+    pub frame: u64,
+}
+
+//Boids the simulation starts with instead of random boids, ie. loaded from a snapshot.
+//Insert it before adding the plugins.
+#[derive(Resource)]
+pub struct InitialBoids(pub Vec<Boid>);
+
+//Replaces the boids of a running simulation, the buffers grow when there are more boids than before.
+#[derive(Resource)]
+pub struct UploadBoids(pub Vec<Boid>);
+
+//Adds boids to a running simulation, the positions are in the -1 to 1 simulation space.
+#[derive(Event, Clone)]
+pub struct SpawnBoids(pub Vec<Boid>);
+
+//Removes all boids within the radius around the center from a running simulation, in the -1 to 1 simulation space.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct DespawnBoids {
+    pub center: Vec3,
+    pub radius: f32,
+}
+
+//The amount of boids in the simulation.
+//The gpu removes the boids itself, so after despawning it is the most there could be until the new amount has been read back.
+#[derive(Resource, Default)]
+pub struct BoidCount {
+    pub alive: u32,
+}
+
+//A threat the boids flee from, the translation of its Transform is its position in the world.
+//Move it with any system, or give it a PredatorPath to let it follow a scripted path.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Predator {
+    //Boids closer than this, in the -1 to 1 simulation space, flee from the predator.
+    pub panic_radius: f32,
+    //How hard the boids steer away, stronger the closer they are.
+    pub flee_strength: f32,
+    //How much faster a boid flies when the predator is right next to it, 1 doubles its speed.
+    pub speed_boost: f32,
+}
+
+impl Default for Predator {
+    fn default() -> Self {
+        Self {
+            panic_radius: 0.15,
+            flee_strength: 4.0,
+            speed_boost: 1.0,
+        }
+    }
+}
+
+// Predator struct that gets transfered over to the compute shader, the w of the position is the panic radius.
+#[derive(ShaderType, Pod, Zeroable, Clone, Copy, Debug)]
+#[repr(C)]
+pub struct PredatorData {
+    pub pos: Vec4,
+    pub flee_strength: f32,
+    pub speed_boost: f32,
+    pub _padding0: f32,
+    pub _padding1: f32,
+}
+
+//The predators of this frame in the -1 to 1 simulation space, collected from the Predator components by the predator plugin.
+#[derive(Resource, Clone, Default, ExtractResource)]
+pub struct Predators(pub Vec<PredatorData>);
+
+//Marks an obstacle with a simple shape as a place the boids can land on, when perching is enabled in the Flocks.
+//They land on the parts of its surface that face up.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct Perch;
+
+//Something in the world the boids fly around, placed by the Transform of its entity.
+//The size of the shape is in the world, the same as the meshes, and is scaled along with the Transform.
+#[derive(Component, Clone, Debug)]
+pub struct Obstacle {
+    pub shape: ObstacleShape,
+    //Boids closer than this, in the -1 to 1 simulation space, steer away from the obstacle.
+    pub margin: f32,
+    //How hard the boids steer away, stronger the closer they are.
+    pub strength: f32,
+}
+
+impl Obstacle {
+    pub fn new(shape: ObstacleShape) -> Self {
+        Self {
+            shape,
+            margin: 0.05,
+            strength: 4.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ObstacleShape {
+    Sphere { radius: f32 },
+    //Axis aligned box, the rotation of the Transform is ignored.
+    Cuboid { half_extents: Vec3 },
+    //A cylinder with round ends along the y axis of the Transform.
+    Capsule { half_height: f32, radius: f32 },
+    //The Handle<Mesh> of the entity, baked into the ObstacleSdf once the mesh has been loaded.
+    Mesh,
+}
+
+impl ObstacleShape {
+    //Value of the shape in the ObstacleData, has to match the constants in the shaders.
+    pub fn shader_value(&self) -> u32 {
+        match self {
+            ObstacleShape::Sphere { .. } => 0,
+            ObstacleShape::Cuboid { .. } => 1,
+            ObstacleShape::Capsule { .. } => 2,
+            ObstacleShape::Mesh => 3,
+        }
+    }
+}
+
+// Obstacle struct that gets transfered over to the compute shader, in the -1 to 1 simulation space.
+// The w of a is the shape, a is the center of a sphere or box and one end of a capsule.
+// The xyz of b are the half extents of a box or the other end of a capsule, the w is the radius of a sphere or capsule.
+#[derive(ShaderType, Pod, Zeroable, Clone, Copy, Debug)]
+#[repr(C)]
+pub struct ObstacleData {
+    pub a: Vec4,
+    pub b: Vec4,
+    pub margin: f32,
+    pub strength: f32,
+    //1 when the boids can land on the top of the obstacle.
+    pub perch: u32,
+    pub _padding0: f32,
+}
+
+//The obstacles of this frame with a simple shape, collected from the Obstacle components by the obstacle plugin.
+#[derive(Resource, Clone, Default, ExtractResource)]
+pub struct Obstacles(pub Vec<ObstacleData>);
+
+//Signed distance to the closest mesh obstacle for every voxel of a grid over the -1 to 1 simulation space, negative inside of a mesh.
+//Only the distances close to a mesh are exact, further away they are at least SDF_BAND.
+#[derive(Resource, Clone, ExtractResource)]
+pub struct ObstacleSdf {
+    //Voxels along every axis, 0 when there are no mesh obstacles.
+    pub resolution: u32,
+    //Indexed by x + y * resolution + z * resolution * resolution, the same as a 3d texture.
+    pub distances: Vec<f32>,
+    //Margin and strength of the mesh obstacles, all meshes share them.
+    pub margin: f32,
+    pub strength: f32,
+}
+
+//Distance of the voxels that are not close to a mesh.
+pub const SDF_BAND: f32 = 0.2;
+
+//Pulls the boids towards itself, placed by the Transform of its entity, ie. to let a flock roost on a tree or chase the camera.
+#[derive(Component, Clone, Debug)]
+pub struct Attractor {
+    pub shape: AttractorShape,
+    //How hard the boids are pulled, a negative weight pushes them away.
+    pub weight: f32,
+    //Distance in the -1 to 1 simulation space at which the pull has halved, 0 pulls just as hard everywhere.
+    pub falloff: f32,
+}
+
+impl Attractor {
+    pub fn new(shape: AttractorShape) -> Self {
+        Self {
+            shape,
+            weight: 1.0,
+            falloff: 0.2,
+        }
+    }
+
+    pub fn with_weight(mut self, weight: f32) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    pub fn with_falloff(mut self, falloff: f32) -> Self {
+        self.falloff = falloff;
+        self
+    }
+}
+
+//The sizes and waypoints are in the world and follow the Transform of the entity, the same as the meshes.
+#[derive(Clone, Debug)]
+pub enum AttractorShape {
+    Point,
+    //A line along the y axis of the Transform, the boids are pulled to the closest point on it.
+    Line { half_length: f32 },
+    //The boids are pulled to the closest point on the path and along it towards the next waypoint.
+    Path { waypoints: Vec<Vec3>, looped: bool },
+}
+
+impl AttractorShape {
+    //Value of the shape in the AttractorData, has to match the constants in the shaders.
+    pub fn shader_value(&self) -> u32 {
+        match self {
+            AttractorShape::Point => 0,
+            AttractorShape::Line { .. } => 1,
+            AttractorShape::Path { .. } => 2,
+        }
+    }
+}
+
+// Attractor struct that gets transfered over to the compute shader, in the -1 to 1 simulation space.
+// A path is split up into a segment for every pair of waypoints, which follow each other in the buffer.
+#[derive(ShaderType, Pod, Zeroable, Clone, Copy, Debug)]
+#[repr(C)]
+pub struct AttractorData {
+    //The w of a is the shape, a is the point or the start of a line or path segment.
+    pub a: Vec4,
+    //The end of a line or path segment, the w is the weight.
+    pub b: Vec4,
+    pub falloff: f32,
+    //The amount of segments of a path, only set on the first segment so the others are skipped.
+    pub path_segments: u32,
+    pub _padding0: f32,
+    pub _padding1: f32,
+}
+
+//The attractors of this frame, collected from the Attractor components by the attractor plugin.
+#[derive(Resource, Clone, Default, ExtractResource)]
+pub struct Attractors(pub Vec<AttractorData>);
+
+impl Default for ObstacleSdf {
+    fn default() -> Self {
+        Self {
+            resolution: 0,
+            distances: Vec::new(),
+            margin: 0.0,
+            strength: 0.0,
+        }
     }
-}
\ No newline at end of file
+}
+
+impl ObstacleSdf {
+    //Trilinear interpolation of the distances, the same as the sdf_distance function of boids_grid.wgsl.
+    pub fn distance(&self, pos: Vec3) -> f32 {
+        let res = self.resolution as i32;
+        if res == 0 || pos.abs().cmpgt(Vec3::ONE).any() {
+            return SDF_BAND;
+        }
+        let voxel = ((pos + Vec3::ONE) * 0.5 * self.resolution as f32 - Vec3::splat(0.5)).clamp(Vec3::ZERO, Vec3::splat((res - 1) as f32));
+        let base = voxel.floor().as_ivec3().min(IVec3::splat((res - 2).max(0)));
+        let t = voxel - base.as_vec3();
+        let load = |x: i32, y: i32, z: i32| {
+            let c = (base + IVec3::new(x, y, z)).min(IVec3::splat(res - 1));
+            self.distances[(c.x + c.y * res + c.z * res * res) as usize]
+        };
+        let x00 = load(0, 0, 0) + (load(1, 0, 0) - load(0, 0, 0)) * t.x;
+        let x10 = load(0, 1, 0) + (load(1, 1, 0) - load(0, 1, 0)) * t.x;
+        let x01 = load(0, 0, 1) + (load(1, 0, 1) - load(0, 0, 1)) * t.x;
+        let x11 = load(0, 1, 1) + (load(1, 1, 1) - load(0, 1, 1)) * t.x;
+        let y0 = x00 + (x10 - x00) * t.y;
+        let y1 = x01 + (x11 - x01) * t.y;
+        y0 + (y1 - y0) * t.z
+    }
+
+    //The direction away from the closest mesh, the same as the sdf_normal function of boids_grid.wgsl.
+    pub fn normal(&self, pos: Vec3) -> Vec3 {
+        let h = 1.0 / self.resolution as f32;
+        let gradient = Vec3::new(
+            self.distance(pos + Vec3::X * h) - self.distance(pos - Vec3::X * h),
+            self.distance(pos + Vec3::Y * h) - self.distance(pos - Vec3::Y * h),
+            self.distance(pos + Vec3::Z * h) - self.distance(pos - Vec3::Z * h));
+        gradient.normalize_or_zero()
+    }
+}
+
+//The air the boids fly in, it carries the boids along without changing the direction they fly in.
+//The velocities are in the -1 to 1 simulation space per second, the same as the speed of the boids.
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub struct Wind {
+    pub velocity: Vec3,
+    //Speed of the swirls of the curl noise, 0 for no turbulence.
+    pub turbulence: f32,
+    //Size of the swirls in the simulation space.
+    pub turbulence_scale: f32,
+    //Average seconds between the start of two gusts, 0 for no gusts.
+    pub gust_interval: f32,
+    //Seconds a gust lasts.
+    pub gust_duration: f32,
+    //Extra speed of the wind at the peak of a gust.
+    pub gust_strength: f32,
+}
+
+impl Default for Wind {
+    fn default() -> Self {
+        Self {
+            velocity: Vec3::ZERO,
+            turbulence: 0.0,
+            turbulence_scale: 0.5,
+            gust_interval: 0.0,
+            gust_duration: 2.0,
+            gust_strength: 0.5,
+        }
+    }
+}
+
+// Wind struct that gets transfered over to the compute shader, updated every frame by the wind plugin.
+#[derive(Resource, ExtractResource, ShaderType, Pod, Zeroable, Clone, Copy, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct WindData {
+    //The wind including the current gust, the w is the speed of the turbulence.
+    pub velocity: Vec4,
+    //Seconds since the start, so the turbulence changes over time.
+    pub time: f32,
+    pub turbulence_scale: f32,
+    pub _padding0: f32,
+    pub _padding1: f32,
+}
@@ -0,0 +1,584 @@
+//! This file is responsible for saving and loading the complete state of the simulation.
+//! A snapshot holds the boids, the Flocks, the SimulationConfig (including the seed) and the amount of simulated steps.
+//! It is written in a small binary format that starts with a magic number and a version, so older snapshots can still be recognized.
+//! Press F5 to save the simulation to the snapshot file and F9 to load it again, use `--load` to start from a snapshot.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+use bevy::prelude::*;
+use crate::shared::*;
+use crate::spawn::{wait_seed, SpawnDistribution};
+
+const MAGIC: &[u8; 4] = b"CROW";
+//Increase when the layout of the snapshot changes.
+//Version 1 only held the BoidParams of a single species.
+//Version 2 held the BoidParams without the view cone.
+//Version 3 held the Flocks without the neighbour mode.
+//Version 4 held the Flocks without the steering model.
+//Version 5 held the Flocks without the perching and the boids without their state.
+//Version 6 held the BoidParams without the flap rate.
+//Version 7 held the species without their alpha mode.
+//Version 8 held the boids without the wait seed in the w of their state.
+const VERSION: u32 = 9;
+//Longest species name that is read, so a corrupt length is reported instead of allocating the memory for it.
+const MAX_NAME_LENGTH: u32 = 256;
+
+pub struct SnapshotPlugin {
+    pub path: PathBuf,
+}
+
+impl SnapshotPlugin {
+    //Reads the file the snapshots are saved to from the command line, ie. `cargo run -- --snapshot flock.crows`
+    pub fn from_args() -> Self {
+        let args: Vec<String> = std::env::args().collect();
+        let path = args.windows(2)
+            .find(|pair| pair[0] == "--snapshot")
+            .map(|pair| PathBuf::from(&pair[1]))
+            .unwrap_or_else(|| PathBuf::from("snapshot.crows"));
+        Self { path }
+    }
+}
+
+impl Plugin for SnapshotPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(SnapshotFile {
+            path: self.path.clone(),
+            saving: false,
+        });
+        app.add_systems(Update, (save_snapshot, load_snapshot));
+    }
+}
+
+#[derive(Resource)]
+struct SnapshotFile {
+    path: PathBuf,
+    //The boids have been requested and will be saved once they have been read back.
+    saving: bool,
+}
+
+//The complete state of the simulation.
+pub struct Snapshot {
+    pub config: SimulationConfig,
+    pub flocks: Flocks,
+    pub frame: u64,
+    pub boids: Vec<Boid>,
+}
+
+impl Snapshot {
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::read(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        write_u32(writer, VERSION)?;
+
+        let config = &self.config;
+        write_u32(writer, config.num_boids)?;
+        write_f32(writer, config.box_size)?;
+        write_u32(writer, config.grid_size)?;
+        write_f32(writer, config.cell_size)?;
+        write_u32(writer, config.fixed_timestep.is_some() as u32)?;
+        write_f32(writer, config.fixed_timestep.unwrap_or(0.0))?;
+        write_f32(writer, config.max_timestep)?;
+        let (margin, strength) = match config.boundary_mode {
+            BoundaryMode::SteerBack { margin, strength } => (margin, strength),
+            _ => (0.0, 0.0),
+        };
+        write_u32(writer, config.boundary_mode.shader_value())?;
+        write_f32(writer, margin)?;
+        write_f32(writer, strength)?;
+        write_u32(writer, (config.backend == SimulationBackend::Cpu) as u32)?;
+        write_u32(writer, config.steps.is_some() as u32)?;
+        write_u64(writer, config.steps.unwrap_or(0))?;
+        write_u64(writer, config.seed)?;
+
+        write_u32(writer, self.flocks.species.len() as u32)?;
+        for species in &self.flocks.species {
+            write_u32(writer, species.name.len() as u32)?;
+            writer.write_all(species.name.as_bytes())?;
+            write_params(writer, &species.params)?;
+            for value in species.color.as_rgba_f32() {
+                write_f32(writer, value)?;
+            }
+            write_f32(writer, species.size)?;
+            let (alpha_mode, alpha_cutoff) = species.alpha_shader_value();
+            write_u32(writer, alpha_mode)?;
+            write_f32(writer, alpha_cutoff)?;
+        }
+        for interaction in self.flocks.interactions.iter().flatten() {
+            write_u32(writer, interaction.shader_value())?;
+        }
+        write_u32(writer, self.flocks.neighbour_mode.shader_value())?;
+        write_u32(writer, self.flocks.neighbour_mode.nearest_count())?;
+        write_u32(writer, self.flocks.steering_model.shader_value())?;
+        write_u32(writer, self.flocks.perching.is_some() as u32)?;
+        let perching = self.flocks.perching.unwrap_or_default();
+        write_f32(writer, perching.flight_time)?;
+        write_f32(writer, perching.perch_time)?;
+        write_f32(writer, perching.landing_distance)?;
+
+        write_u64(writer, self.frame)?;
+        write_u32(writer, self.boids.len() as u32)?;
+        writer.write_all(bytemuck::cast_slice(&self.boids))
+    }
+
+    pub fn read(reader: &mut impl Read) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a snapshot file".to_string()));
+        }
+        let version = read_u32(reader)?;
+        if !(1..=VERSION).contains(&version) {
+            return Err(invalid_data(format!("unsupported snapshot version {version}, expected 1 to {VERSION}")));
+        }
+
+        let num_boids = read_u32(reader)?;
+        let box_size = read_f32(reader)?;
+        let grid_size = read_u32(reader)?;
+        if grid_size < SimulationConfig::MIN_GRID_SIZE {
+            return Err(invalid_data(format!("grid size {grid_size} is smaller than {}", SimulationConfig::MIN_GRID_SIZE)));
+        }
+        let cell_size = read_f32(reader)?;
+        let has_fixed_timestep = read_u32(reader)? != 0;
+        let fixed_timestep = read_f32(reader)?;
+        if has_fixed_timestep && (fixed_timestep.is_nan() || fixed_timestep <= 0.0) {
+            return Err(invalid_data(format!("fixed timestep {fixed_timestep} is not larger than 0")));
+        }
+        let max_timestep = read_f32(reader)?;
+        let boundary_mode = read_u32(reader)?;
+        let margin = read_f32(reader)?;
+        let strength = read_f32(reader)?;
+        let boundary_mode = match boundary_mode {
+            0 => BoundaryMode::Wrap,
+            1 => BoundaryMode::Bounce,
+            2 => BoundaryMode::SteerBack { margin, strength },
+            3 => BoundaryMode::Open,
+            mode => return Err(invalid_data(format!("unknown boundary mode {mode}"))),
+        };
+        let backend = if read_u32(reader)? != 0 { SimulationBackend::Cpu } else { SimulationBackend::Gpu };
+        let has_steps = read_u32(reader)? != 0;
+        let steps = read_u64(reader)?;
+        let seed = read_u64(reader)?;
+        let config = SimulationConfig {
+            num_boids,
+            box_size,
+            grid_size,
+            cell_size,
+            fixed_timestep: has_fixed_timestep.then_some(fixed_timestep),
+            max_timestep,
+            boundary_mode,
+            backend,
+            steps: has_steps.then_some(steps),
+            seed,
+            //The boids are in the snapshot, so it doesn't matter how they were spawned.
+            spawn: SpawnDistribution::default(),
+        };
+
+        let flocks = if version == 1 {
+            Flocks::new(vec![Species {
+                params: read_params(reader, version)?,
+                ..Species::crows()
+            }])
+        } else {
+            let species_count = read_u32(reader)?;
+            if species_count == 0 || species_count as usize > MAX_SPECIES {
+                return Err(invalid_data(format!("snapshot holds {species_count} species, expected 1 to {MAX_SPECIES}")));
+            }
+            let mut species = Vec::with_capacity(species_count as usize);
+            for _ in 0..species_count {
+                let name_length = read_u32(reader)?;
+                if name_length > MAX_NAME_LENGTH {
+                    return Err(invalid_data(format!("species name of {name_length} bytes is longer than {MAX_NAME_LENGTH}")));
+                }
+                let name = read_bytes(reader, name_length as u64)?;
+                let name = String::from_utf8(name).map_err(|e| invalid_data(format!("invalid species name: {e}")))?;
+                let params = read_params(reader, version)?;
+                let color = Color::rgba(read_f32(reader)?, read_f32(reader)?, read_f32(reader)?, read_f32(reader)?);
+                let size = read_f32(reader)?;
+                let mut alpha_mode = AlphaMode::Opaque;
+                if version >= 8 {
+                    let value = read_u32(reader)?;
+                    let cutoff = read_f32(reader)?;
+                    alpha_mode = Species::alpha_mode_from_shader_value(value, cutoff)
+                        .ok_or_else(|| invalid_data(format!("unknown alpha mode {value}")))?;
+                }
+                species.push(Species { name, params, color, size, alpha_mode });
+            }
+            let mut flocks = Flocks::new(species);
+            for interaction in flocks.interactions.iter_mut().flatten() {
+                let value = read_u32(reader)?;
+                *interaction = SpeciesInteraction::from_shader_value(value)
+                    .ok_or_else(|| invalid_data(format!("unknown species interaction {value}")))?;
+            }
+            if version >= 4 {
+                let value = read_u32(reader)?;
+                let k = read_u32(reader)?;
+                flocks.neighbour_mode = NeighbourMode::from_shader_value(value, k)
+                    .ok_or_else(|| invalid_data(format!("unknown neighbour mode {value}")))?;
+            }
+            if version >= 5 {
+                let value = read_u32(reader)?;
+                flocks.steering_model = SteeringModel::from_shader_value(value)
+                    .ok_or_else(|| invalid_data(format!("unknown steering model {value}")))?;
+            }
+            if version >= 6 {
+                let has_perching = read_u32(reader)? != 0;
+                let perching = Perching {
+                    flight_time: read_f32(reader)?,
+                    perch_time: read_f32(reader)?,
+                    landing_distance: read_f32(reader)?,
+                };
+                flocks.perching = has_perching.then_some(perching);
+            }
+            flocks
+        };
+
+        let frame = read_u64(reader)?;
+        let boid_count = read_u32(reader)?;
+        if boid_count != num_boids {
+            return Err(invalid_data(format!("snapshot holds {boid_count} boids, but its config has {num_boids}")));
+        }
+        let mut boids: Vec<Boid> = if version >= 6 {
+            let bytes = read_bytes(reader, boid_count as u64 * std::mem::size_of::<Boid>() as u64)?;
+            bytes.chunks_exact(std::mem::size_of::<Boid>()).map(bytemuck::pod_read_unaligned).collect()
+        } else {
+            //Only the position and velocity, the boids were all flying.
+            let bytes = read_bytes(reader, boid_count as u64 * std::mem::size_of::<[Vec4; 2]>() as u64)?;
+            bytes.chunks_exact(std::mem::size_of::<[Vec4; 2]>())
+                .map(|chunk| {
+                    let [pos, vel]: [Vec4; 2] = bytemuck::pod_read_unaligned(chunk);
+                    Boid { pos, vel, state: Vec4::ZERO }
+                })
+                .collect()
+        };
+        if version < 9 {
+            for (i, boid) in boids.iter_mut().enumerate() {
+                boid.state.w = wait_seed(i);
+            }
+        }
+
+        Ok(Self {
+            config,
+            flocks,
+            frame,
+            boids,
+        })
+    }
+}
+
+fn write_params(writer: &mut impl Write, params: &BoidParams) -> io::Result<()> {
+    for value in [
+        params.speed,
+        params.seperation_distance,
+        params.alignment_distance,
+        params.cohesion_distance,
+        params.seperation_scale,
+        params.alignment_scale,
+        params.cohesion_scale,
+        params.view_angle,
+        params.blind_spot,
+        params.mass,
+        params.max_force,
+        params.min_speed,
+        params.max_turn_rate,
+        params.flap_rate,
+    ] {
+        write_f32(writer, value)?;
+    }
+    Ok(())
+}
+
+//Snapshots before version 3 have no view cone, so their boids see all around.
+//Snapshots before version 5 have no steering params and before version 7 no flap rate, so their boids use the defaults.
+fn read_params(reader: &mut impl Read, version: u32) -> io::Result<BoidParams> {
+    let mut params = BoidParams {
+        speed: read_f32(reader)?,
+        seperation_distance: read_f32(reader)?,
+        alignment_distance: read_f32(reader)?,
+        cohesion_distance: read_f32(reader)?,
+        seperation_scale: read_f32(reader)?,
+        alignment_scale: read_f32(reader)?,
+        cohesion_scale: read_f32(reader)?,
+        ..BoidParams::default()
+    };
+    if version >= 3 {
+        params.view_angle = read_f32(reader)?;
+        params.blind_spot = read_f32(reader)?;
+    }
+    if version >= 5 {
+        params.mass = read_f32(reader)?;
+        params.max_force = read_f32(reader)?;
+        params.min_speed = read_f32(reader)?;
+        params.max_turn_rate = read_f32(reader)?;
+    }
+    if version >= 7 {
+        params.flap_rate = read_f32(reader)?;
+    }
+    Ok(params)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+//All values are stored little endian.
+fn write_u32(writer: &mut impl Write, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_u64(writer: &mut impl Write, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_f32(writer: &mut impl Write, value: f32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f32(reader: &mut impl Read) -> io::Result<f32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+
+//Reads the bytes in the chunks the reader has, so a corrupt length ends at the end of the file instead of allocating all of it up front.
+fn read_bytes(reader: &mut impl Read, length: u64) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader.take(length).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < length {
+        return Err(invalid_data(format!("expected {length} bytes, but the snapshot ends after {}", bytes.len())));
+    }
+    Ok(bytes)
+}
+
+//Requests the boids when F5 is pressed and saves them together with the rest of the state once they have been read back.
+fn save_snapshot(
+    keys: Res<Input<KeyCode>>,
+    config: Res<SimulationConfig>,
+    flocks: Res<Flocks>,
+    mut file: ResMut<SnapshotFile>,
+    mut readback: ResMut<BoidReadback>,
+) {
+    if keys.just_pressed(KeyCode::F5) && !file.saving {
+        file.saving = true;
+        readback.requested = true;
+    }
+    if !file.saving {
+        return
+    }
+    let Some(boids) = readback.boids.take() else {
+        return
+    };
+    file.saving = false;
+
+    //Boids might have been spawned or despawned since the start.
+    let snapshot = Snapshot {
+        config: SimulationConfig {
+            num_boids: boids.len() as u32,
+            ..config.clone()
+        },
+        flocks: flocks.clone(),
+        frame: readback.frame,
+        boids,
+    };
+    match snapshot.save(&file.path) {
+        Ok(()) => info!("Saved snapshot of step {} to {}", snapshot.frame, file.path.display()),
+        Err(e) => error!("Failed to save snapshot to {}: {e}", file.path.display()),
+    }
+}
+
+//Replaces the boids, species and step counter with the snapshot when F9 is pressed.
+//The simulation can only be replaced while running when it has the same grid and boundary, otherwise start with `--load`.
+fn load_snapshot(
+    mut commands: Commands,
+    keys: Res<Input<KeyCode>>,
+    config: Res<SimulationConfig>,
+    file: Res<SnapshotFile>,
+    mut flocks: ResMut<Flocks>,
+    mut frame: ResMut<SimulationFrame>,
+) {
+    if !keys.just_pressed(KeyCode::F9) {
+        return
+    }
+    let snapshot = match Snapshot::load(&file.path) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            error!("Failed to load snapshot from {}: {e}", file.path.display());
+            return
+        }
+    };
+    if !config.same_simulation(&snapshot.config) {
+        warn!("The snapshot in {} was made with a different config, start with `--load` to load it", file.path.display());
+        return
+    }
+
+    *flocks = snapshot.flocks;
+    frame.steps = snapshot.frame;
+    commands.insert_resource(UploadBoids(snapshot.boids));
+    info!("Loaded snapshot of step {} from {}", snapshot.frame, file.path.display());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //The config the snapshots in tests/fixtures were saved with, by the versions of the snapshot plugin that wrote those layouts.
+    fn fixture_config() -> SimulationConfig {
+        SimulationConfig::default()
+            .with_num_boids(3)
+            .with_grid_size(10)
+            .with_fixed_timestep(0.01)
+            .with_boundary_mode(BoundaryMode::SteerBack { margin: 0.1, strength: 2.0 })
+            .with_backend(SimulationBackend::Cpu)
+            .with_steps(100)
+            .with_seed(42)
+    }
+
+    //The boids of the fixtures, the older layouts drop the species or the state.
+    fn fixture_boid(i: usize, species: bool, state: bool) -> Boid {
+        Boid {
+            pos: Vec4::new(0.1 * i as f32, -0.2, 0.3, if species { (i % 2) as f32 } else { 0.0 }),
+            vel: Vec4::new(0.0, 0.5, -0.1 * i as f32, 0.0),
+            state: if state { Vec4::new((i % 3) as f32, 1.5, 0.25 * i as f32, 0.0) } else { Vec4::ZERO },
+        }
+    }
+
+    fn read(bytes: &[u8]) -> io::Result<Snapshot> {
+        Snapshot::read(&mut &bytes[..])
+    }
+
+    fn assert_boids_eq(boids: &[Boid], expected: &[Boid]) {
+        assert_eq!(bytemuck::cast_slice::<Boid, f32>(boids), bytemuck::cast_slice::<Boid, f32>(expected));
+    }
+
+    //The boids of older snapshots get the wait seed they would have been spawned with.
+    fn with_wait_seeds(mut boids: Vec<Boid>) -> Vec<Boid> {
+        for (i, boid) in boids.iter_mut().enumerate() {
+            boid.state.w = wait_seed(i);
+        }
+        boids
+    }
+
+    //The colors are stored as rgba, so they are compared as rgba.
+    fn rgba(species: Species) -> Species {
+        Species {
+            color: species.color.as_rgba(),
+            ..species
+        }
+    }
+
+    fn two_species() -> Flocks {
+        let mut flocks = Flocks::new(vec![rgba(Species::crows()), rgba(Species::starlings())]).with_interaction(SpeciesInteraction::Ignore);
+        flocks.neighbour_mode = NeighbourMode::Topological { k: 5 };
+        flocks.steering_model = SteeringModel::Reynolds;
+        flocks
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut flocks = two_species();
+        flocks.species[0].alpha_mode = AlphaMode::Mask(0.4);
+        flocks.perching = Some(Perching { flight_time: 5.0, perch_time: 3.0, landing_distance: 0.2 });
+        let snapshot = Snapshot {
+            config: fixture_config(),
+            flocks,
+            frame: 12,
+            boids: (0..3).map(|i| Boid { state: Vec4::new(1.0, 0.5, 0.25, wait_seed(i)), ..fixture_boid(i, true, true) }).collect(),
+        };
+        let mut bytes = Vec::new();
+        snapshot.write(&mut bytes).unwrap();
+
+        let loaded = read(&bytes).unwrap();
+        assert_eq!(loaded.config, snapshot.config);
+        assert_eq!(loaded.flocks, snapshot.flocks);
+        assert_eq!(loaded.frame, snapshot.frame);
+        assert_boids_eq(&loaded.boids, &snapshot.boids);
+    }
+
+    #[test]
+    fn reads_version_1() {
+        let snapshot = read(include_bytes!("../tests/fixtures/snapshot_v1.crows")).unwrap();
+        assert_eq!(snapshot.config, fixture_config());
+        let species = Species {
+            params: BoidParams { speed: 0.6, ..default() },
+            ..Species::crows()
+        };
+        assert_eq!(snapshot.flocks, Flocks::new(vec![species]));
+        assert_eq!(snapshot.frame, 12);
+        assert_boids_eq(&snapshot.boids, &with_wait_seeds((0..3).map(|i| fixture_boid(i, false, false)).collect()));
+    }
+
+    #[test]
+    fn reads_version_5() {
+        let snapshot = read(include_bytes!("../tests/fixtures/snapshot_v5.crows")).unwrap();
+        assert_eq!(snapshot.config, fixture_config());
+        //Version 5 has no flap rate and no alpha mode.
+        let mut flocks = two_species();
+        for species in &mut flocks.species {
+            species.params.flap_rate = BoidParams::default().flap_rate;
+            species.alpha_mode = AlphaMode::Opaque;
+        }
+        assert_eq!(snapshot.flocks, flocks);
+        assert_eq!(snapshot.frame, 12);
+        assert_boids_eq(&snapshot.boids, &with_wait_seeds((0..3).map(|i| fixture_boid(i, true, false)).collect()));
+    }
+
+    #[test]
+    fn reads_version_8() {
+        let snapshot = read(include_bytes!("../tests/fixtures/snapshot_v8.crows")).unwrap();
+        assert_eq!(snapshot.config, fixture_config());
+        let mut flocks = two_species();
+        flocks.species[0].alpha_mode = AlphaMode::Mask(0.4);
+        flocks.species[1].alpha_mode = AlphaMode::Blend;
+        flocks.perching = Some(Perching { flight_time: 5.0, perch_time: 3.0, landing_distance: 0.2 });
+        assert_eq!(snapshot.flocks, flocks);
+        assert_eq!(snapshot.frame, 12);
+        assert_boids_eq(&snapshot.boids, &with_wait_seeds((0..3).map(|i| fixture_boid(i, true, true)).collect()));
+    }
+
+    #[test]
+    fn rejects_corrupt_lengths() {
+        let snapshot = Snapshot {
+            config: fixture_config().with_num_boids(0),
+            flocks: Flocks::default(),
+            frame: 0,
+            boids: Vec::new(),
+        };
+        let mut bytes = Vec::new();
+        snapshot.write(&mut bytes).unwrap();
+
+        //The num_boids of the config comes right after the magic and the version, the count of the boids is the last value.
+        let mut huge_count = bytes.clone();
+        huge_count[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        let end = huge_count.len();
+        huge_count[end - 4..].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(read(&huge_count).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+
+        let name = bytes.windows(5).position(|window| window == b"crows").unwrap();
+        let mut huge_name = bytes.clone();
+        huge_name[name - 4..name].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(read(&huge_name).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+}
@@ -0,0 +1,225 @@
+//! This file is responsible for the boids the simulation starts with.
+//! Every SpawnDistribution places the boids using a random number generator seeded with the seed of the SimulationConfig,
+//! so the same seed always gives the same boids, which makes runs reproducible.
+//! The boids can also be read from a csv file in the format the headless mode writes.
+//! When there are multiple species, the boids take turns being of every species.
+
+use std::{f32::consts::PI, io, path::{Path, PathBuf}};
+use bevy::prelude::*;
+use rand::{distributions::{Distribution, Uniform}, rngs::StdRng, Rng, SeedableRng};
+use crate::shared::Boid;
+
+// Where the boids are placed at the start, all positions are in the -1 to 1 simulation space.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SpawnDistribution {
+    //Spread out over a cube around the origin, a half size of 1 fills the whole simulation space.
+    UniformCube { half_size: f32 },
+    //Spread out between two spheres around the origin.
+    SphereShell { inner_radius: f32, outer_radius: f32 },
+    //Normally distributed around a number of random centers.
+    GaussianClusters { clusters: u32, std_dev: f32 },
+    //Spread out over a flat disk in the xz plane.
+    Disk { radius: f32, thickness: f32 },
+    //Read from a csv file with a line of x,y,z,vx,vy,vz,species,state for every boid, the first line is skipped.
+    //The species and state can be left out, those boids are of the first species and flying.
+    FromFile(PathBuf),
+}
+
+impl Default for SpawnDistribution {
+    fn default() -> Self {
+        SpawnDistribution::UniformCube { half_size: 1.0 }
+    }
+}
+
+impl SpawnDistribution {
+    //Parses the `--spawn` argument, every distribution uses default values.
+    pub fn from_arg(arg: &str) -> Option<Self> {
+        match arg {
+            "cube" => Some(SpawnDistribution::default()),
+            "sphere" => Some(SpawnDistribution::SphereShell { inner_radius: 0.6, outer_radius: 0.8 }),
+            "clusters" => Some(SpawnDistribution::GaussianClusters { clusters: 8, std_dev: 0.1 }),
+            "disk" => Some(SpawnDistribution::Disk { radius: 0.9, thickness: 0.05 }),
+            _ => None,
+        }
+    }
+
+    //Creates count boids spread over the amount of species, the same seed always gives the same boids.
+    //Only fails when the boids are read from a file.
+    pub fn spawn(&self, count: u32, seed: u64, species: u32) -> io::Result<Vec<Boid>> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let unif = Uniform::new_inclusive(-1.0f32, 1.0);
+
+        //The positions are drawn first, so the velocities don't change the positions of a distribution.
+        let positions: Vec<Vec3> = match self {
+            SpawnDistribution::UniformCube { half_size } => (0..count)
+                .map(|_| Vec3::new(unif.sample(&mut rng), unif.sample(&mut rng), unif.sample(&mut rng)) * *half_size)
+                .collect(),
+            SpawnDistribution::SphereShell { inner_radius, outer_radius } => (0..count)
+                .map(|_| {
+                    //Uniform in volume, so the outside of the shell isn't less dense than the inside.
+                    let t: f32 = rng.gen();
+                    let radius = (inner_radius.powi(3) + t * (outer_radius.powi(3) - inner_radius.powi(3))).cbrt();
+                    random_direction(&mut rng) * radius
+                })
+                .collect(),
+            SpawnDistribution::GaussianClusters { clusters, std_dev } => {
+                let centers: Vec<Vec3> = (0..(*clusters).max(1))
+                    .map(|_| Vec3::new(unif.sample(&mut rng), unif.sample(&mut rng), unif.sample(&mut rng)) * 0.7)
+                    .collect();
+                (0..count)
+                    .map(|i| {
+                        let offset = Vec3::new(gaussian(&mut rng), gaussian(&mut rng), gaussian(&mut rng)) * *std_dev;
+                        (centers[i as usize % centers.len()] + offset).clamp(Vec3::NEG_ONE, Vec3::ONE)
+                    })
+                    .collect()
+            },
+            SpawnDistribution::Disk { radius, thickness } => (0..count)
+                .map(|_| {
+                    let angle = rng.gen::<f32>() * 2.0 * PI;
+                    let distance = rng.gen::<f32>().sqrt() * radius;
+                    Vec3::new(angle.cos() * distance, unif.sample(&mut rng) * thickness * 0.5, angle.sin() * distance)
+                })
+                .collect(),
+            SpawnDistribution::FromFile(path) => {
+                let boids = read_boids_csv(path)
+                    .map_err(|e| io::Error::new(e.kind(), format!("failed to read the boids from {}: {e}", path.display())))?;
+                if boids.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} doesn't hold any boids", path.display())));
+                }
+                if boids.len() != count as usize {
+                    warn!("{} holds {} boids, repeating them to get {}", path.display(), boids.len(), count);
+                }
+                //The species come from the file.
+                return Ok(boids.iter().cycle().take(count as usize).copied().collect());
+            },
+        };
+
+        let species = species.max(1);
+        Ok(positions.into_iter()
+            .enumerate()
+            .map(|(i, pos)| Boid {
+                pos: pos.extend((i as u32 % species) as f32),
+                vel: Vec4::new(
+                    unif.sample(&mut rng),
+                    unif.sample(&mut rng),
+                    unif.sample(&mut rng),
+                    0.0),
+                state: Vec4::new(0.0, 0.0, wing_phase(i), wait_seed(i)),
+            })
+            .collect())
+    }
+}
+
+//Spreads the wing beats evenly with the golden ratio, so neighbouring boids don't flap in step.
+fn wing_phase(i: usize) -> f32 {
+    (i as f32 * 0.618_034).fract()
+}
+
+//Decides how much longer or shorter than the flight and perch time a boid waits, so the flock doesn't land all at once.
+//Spread with the plastic number instead of the golden ratio, so it is unrelated to the wing phase.
+pub fn wait_seed(i: usize) -> f32 {
+    (i as f32 * 0.754_877_7).fract()
+}
+
+fn random_direction(rng: &mut StdRng) -> Vec3 {
+    let z: f32 = rng.gen_range(-1.0..=1.0);
+    let angle = rng.gen::<f32>() * 2.0 * PI;
+    let r = (1.0 - z * z).sqrt();
+    Vec3::new(r * angle.cos(), r * angle.sin(), z)
+}
+
+//Standard normal distribution using the Box-Muller transform.
+fn gaussian(rng: &mut StdRng) -> f32 {
+    let u1: f32 = 1.0 - rng.gen::<f32>();
+    let u2: f32 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+}
+
+fn read_boids_csv(path: &Path) -> io::Result<Vec<Boid>> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut boids = Vec::new();
+    for (line_number, line) in contents.lines().enumerate().skip(1) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let values: Vec<f32> = line.split(',')
+            .map(|value| value.trim().parse::<f32>())
+            .collect::<Result<_, _>>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {e}", line_number + 1)))?;
+        if values.len() < 6 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("line {}: expected x,y,z,vx,vy,vz", line_number + 1)));
+        }
+        let species = values.get(6).copied().unwrap_or(0.0);
+        let state = values.get(7).copied().unwrap_or(0.0);
+        boids.push(Boid {
+            pos: Vec4::new(values[0], values[1], values[2], species),
+            vel: Vec4::new(values[3], values[4], values[5], 0.0),
+            state: Vec4::new(state, 0.0, wing_phase(boids.len()), wait_seed(boids.len())),
+        });
+    }
+    Ok(boids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_distributions() -> [SpawnDistribution; 4] {
+        [
+            SpawnDistribution::UniformCube { half_size: 1.0 },
+            SpawnDistribution::SphereShell { inner_radius: 0.6, outer_radius: 0.8 },
+            SpawnDistribution::GaussianClusters { clusters: 8, std_dev: 0.1 },
+            SpawnDistribution::Disk { radius: 0.9, thickness: 0.05 },
+        ]
+    }
+
+    fn values(boids: &[Boid]) -> &[f32] {
+        bytemuck::cast_slice(boids)
+    }
+
+    //A csv file of its own for every test, as the tests run at the same time.
+    fn csv_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("crows_{name}_{}.csv", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn same_seed_spawns_the_same_boids() {
+        for distribution in random_distributions() {
+            let boids = distribution.spawn(100, 7, 2).unwrap();
+            assert_eq!(boids.len(), 100);
+            assert_eq!(values(&boids), values(&distribution.spawn(100, 7, 2).unwrap()), "{distribution:?}");
+        }
+    }
+
+    #[test]
+    fn other_seed_spawns_other_boids() {
+        for distribution in random_distributions() {
+            assert_ne!(values(&distribution.spawn(100, 7, 2).unwrap()), values(&distribution.spawn(100, 8, 2).unwrap()), "{distribution:?}");
+        }
+    }
+
+    #[test]
+    fn from_file_repeats_the_boids_of_the_file() {
+        let path = csv_file("spawn", "x,y,z,vx,vy,vz,species,state\n0.1,0.2,0.3,1,0,0,1,0\n-0.1,-0.2,-0.3,0,1,0\n");
+        let boids = SpawnDistribution::FromFile(path.clone()).spawn(3, 7, 2).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(boids.len(), 3);
+        assert_eq!(boids[0].pos, Vec4::new(0.1, 0.2, 0.3, 1.0));
+        assert_eq!(boids[1].pos, Vec4::new(-0.1, -0.2, -0.3, 0.0));
+        assert_eq!(values(&boids[2..]), values(&boids[..1]));
+    }
+
+    #[test]
+    fn from_file_fails_without_boids() {
+        let missing = std::env::temp_dir().join("crows_missing.csv");
+        assert!(SpawnDistribution::FromFile(missing).spawn(3, 7, 1).is_err());
+
+        let path = csv_file("empty", "x,y,z,vx,vy,vz\n");
+        let empty = SpawnDistribution::FromFile(path.clone()).spawn(3, 7, 1);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(empty.err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+}
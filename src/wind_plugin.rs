@@ -0,0 +1,100 @@
+//! This file is responsible for the wind the boids fly in, which carries them along without changing the direction they fly in.
+//! The Wind resource holds the steady wind, the turbulence and the gusts, every frame they are combined into the WindData resource.
+//! The turbulence is curl noise, so the swirls don't push the boids together or apart.
+//! https://www.cs.ubc.ca/~rbridson/docs/bridson-siggraph2007-curlnoise.pdf
+//! Was used to understand curl noise.
+//! Use `--wind 0.2,0,0` for a steady wind, `--turbulence 0.3` for swirls and `--gusts 5` for a gust about every 5 seconds.
+
+use bevy::prelude::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use crate::shared::*;
+
+pub struct WindPlugin {
+    pub wind: Wind,
+}
+
+impl WindPlugin {
+    //Reads the wind from the command line, ie. `cargo run -- --wind 0.2,0,0 --turbulence 0.3 --gusts 5`
+    pub fn from_args() -> Self {
+        let mut wind = Wind::default();
+        let args: Vec<String> = std::env::args().collect();
+        for pair in args.windows(2) {
+            if pair[0] == "--wind" {
+                let values: Vec<f32> = pair[1].split(',').filter_map(|value| value.trim().parse().ok()).collect();
+                if let [x, y, z] = values[..] {
+                    wind.velocity = Vec3::new(x, y, z);
+                }
+            }
+            if pair[0] == "--turbulence" {
+                if let Ok(val) = pair[1].parse() {
+                    wind.turbulence = val;
+                }
+            }
+            if pair[0] == "--gusts" {
+                if let Ok(val) = pair[1].parse() {
+                    wind.gust_interval = val;
+                }
+            }
+        }
+        Self { wind }
+    }
+}
+
+impl Plugin for WindPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(self.wind.clone())
+            .init_resource::<WindData>()
+            .add_systems(PostUpdate, update_wind);
+    }
+}
+
+//The gust that is blowing or will blow next.
+struct Gust {
+    rng: StdRng,
+    start: f32,
+    //Along the wind, or horizontal in a random direction when there is no wind.
+    direction: Vec3,
+}
+
+impl Gust {
+    fn next(&mut self, after: f32, wind: &Wind) {
+        self.start = after + wind.gust_interval * self.rng.gen_range(0.5..1.5);
+        let angle = self.rng.gen_range(0.0..std::f32::consts::TAU);
+        self.direction = wind.velocity.try_normalize().unwrap_or(Vec3::new(angle.cos(), 0.0, angle.sin()));
+    }
+}
+
+//Uses the elapsed time, which is the simulated time in headless mode, so a run with the same seed has the same gusts.
+fn update_wind(
+    time: Res<Time>,
+    wind: Res<Wind>,
+    config: Res<SimulationConfig>,
+    mut data: ResMut<WindData>,
+    mut gust: Local<Option<Gust>>,
+) {
+    let now = time.elapsed_seconds();
+    let gust = gust.get_or_insert_with(|| {
+        let mut gust = Gust { rng: StdRng::seed_from_u64(config.seed), start: 0.0, direction: Vec3::ZERO };
+        gust.next(now, &wind);
+        gust
+    });
+
+    let mut velocity = wind.velocity;
+    if wind.gust_interval > 0.0 && now >= gust.start {
+        let t = (now - gust.start) / wind.gust_duration.max(0.001);
+        if t < 1.0 {
+            //Builds up and dies down again.
+            velocity += gust.direction * wind.gust_strength * (t * std::f32::consts::PI).sin();
+        } else {
+            gust.next(now, &wind);
+        }
+    }
+
+    *data = WindData {
+        velocity: velocity.extend(wind.turbulence),
+        time: now,
+        turbulence_scale: wind.turbulence_scale.max(0.001),
+        _padding0: 0.0,
+        _padding1: 0.0,
+    };
+}